@@ -0,0 +1,609 @@
+/*
+Copyright 2024 Eric Stokes.
+
+This file is part of bfdb.
+
+bfdb is free software: you can redistribute it and/or modify it under
+the terms of the GNU Affero Public License as published by the Free
+Software Foundation, either version 3 of the License, or (at your
+option) any later version.
+
+bfdb is distributed in the hope that it will be useful, but WITHOUT
+ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero Public License
+for more details.
+*/
+
+use anyhow::{anyhow, Result};
+use bflib::stats::{Dead, ObjectiveId, ObjectiveKind, Stat, StatKind, Vehicle};
+use chrono::prelude::*;
+use dcso3::{coalition::Side, coord::LLPos, net::Ucid, String};
+use log::warn;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    io::{self, BufRead, BufReader},
+    path::{Path, PathBuf},
+};
+
+pub type Map<K, V> = immutable_chunkmap::map::Map<K, V, 256>;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PlayerStats {
+    pub name: String,
+    pub side: Side,
+    pub kills: u32,
+    /// kills where someone else landed the last hit
+    #[serde(default)]
+    pub assists: u32,
+    pub team_kills: u32,
+    pub deaths: u32,
+    pub sorties: u32,
+    pub captures: u32,
+    pub repairs: u32,
+    pub deploys: u32,
+    pub troops: u32,
+    pub actions: u32,
+    /// net points earned from kills, captures, and repairs, team kill
+    /// penalties are subtracted
+    pub points: i64,
+    /// the airframe the player most recently took off in
+    pub airframe: Option<Vehicle>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OwnerChange {
+    pub time: DateTime<Utc>,
+    pub owner: Side,
+    /// the players who captured the objective, empty if the change
+    /// came from the start of a new round
+    pub by: Vec<Ucid>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ObjectiveHistory {
+    pub pos: LLPos,
+    pub kind: ObjectiveKind,
+    pub owners: Vec<OwnerChange>,
+}
+
+impl ObjectiveHistory {
+    pub fn owner_at(&self, time: DateTime<Utc>) -> Option<Side> {
+        self.owners
+            .iter()
+            .take_while(|c| c.time <= time)
+            .last()
+            .map(|c| c.owner)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoundSummary {
+    pub start: DateTime<Utc>,
+    pub end: Option<DateTime<Utc>>,
+    pub winner: Option<Side>,
+    pub sessions: u32,
+    pub kills: u32,
+    pub team_kills: u32,
+    pub sorties: u32,
+    pub captures: u32,
+    pub losses: Map<Vehicle, u32>,
+}
+
+impl RoundSummary {
+    fn new(start: DateTime<Utc>) -> Self {
+        Self {
+            start,
+            end: None,
+            winner: None,
+            sessions: 0,
+            kills: 0,
+            team_kills: 0,
+            sorties: 0,
+            captures: 0,
+            losses: Map::new(),
+        }
+    }
+}
+
+/// The campaign statistics database. It is built by ingesting the stats
+/// logs written by bflib, and it is stored on disk as a single zstd
+/// compressed json file so it can live next to the server without any
+/// external database.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StatsDb {
+    players: Map<Ucid, PlayerStats>,
+    objectives: Map<ObjectiveId, ObjectiveHistory>,
+    rounds: Vec<RoundSummary>,
+    losses: Map<Vehicle, u32>,
+    /// the number of records already ingested from each stats log,
+    /// logs are append only so re-ingesting a log only processes the
+    /// new records
+    ingested: Map<String, u64>,
+}
+
+impl StatsDb {
+    /// open the database at path, or create an empty one if the file
+    /// doesn't exist yet
+    pub fn open(path: &Path) -> Result<Self> {
+        match File::open(path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(anyhow!("failed to open stats db {:?}, {:?}", path, e)),
+            Ok(file) => {
+                let file = zstd::stream::Decoder::new(file)?;
+                serde_json::from_reader(file)
+                    .map_err(|e| anyhow!("failed to decode stats db {:?}, {:?}", path, e))
+            }
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut tmp = PathBuf::from(path);
+        tmp.set_extension("tmp");
+        let file = File::options()
+            .write(true)
+            .truncate(true)
+            .create(true)
+            .open(&tmp)?;
+        let mut file = zstd::stream::Encoder::new(file, 9)?.auto_finish();
+        serde_json::to_writer(&mut file, self)?;
+        drop(file);
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    /// ingest all the stats logs in dir in the order they were
+    /// written. Returns the number of new records ingested.
+    pub fn ingest_dir(&mut self, dir: &Path) -> Result<usize> {
+        let mut logs = vec![];
+        for ent in fs::read_dir(dir)? {
+            let path = ent?.path();
+            let is_log = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .map(|n| n.contains("_STATS") && n.ends_with(".jsonl.zst"))
+                .unwrap_or(false);
+            if is_log {
+                logs.push(path)
+            }
+        }
+        logs.sort_by_key(|p| log_timestamp(p));
+        let mut n = 0;
+        for path in logs {
+            n += self.ingest_file(&path)?;
+        }
+        Ok(n)
+    }
+
+    /// ingest the stats log at path, skipping any records that have
+    /// already been ingested. Returns the number of new records
+    /// ingested. A log that is still being written may end in a
+    /// partial frame, ingestion stops there and picks up the rest next
+    /// time.
+    pub fn ingest_file(&mut self, path: &Path) -> Result<usize> {
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .map(String::from)
+            .ok_or_else(|| anyhow!("stats log with no name {:?}", path))?;
+        let skip = self.ingested.get(&name).copied().unwrap_or(0);
+        let file = BufReader::new(zstd::stream::Decoder::new(File::open(path)?)?);
+        let mut read = 0;
+        let mut new = 0;
+        for line in file.lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    warn!(
+                        "stopped reading stats log {:?} at record {read}, {:?}",
+                        path, e
+                    );
+                    break;
+                }
+            };
+            read += 1;
+            if read <= skip {
+                continue;
+            }
+            match serde_json::from_str::<Stat>(&line) {
+                Ok(stat) => {
+                    self.ingest(&stat);
+                    new += 1
+                }
+                Err(e) => warn!("invalid record {read} in stats log {:?}, {:?}", path, e),
+            }
+        }
+        self.ingested.insert_cow(name, read);
+        Ok(new)
+    }
+
+    /// ingest a single stat record
+    pub fn ingest(&mut self, stat: &Stat) {
+        match &stat.kind {
+            StatKind::NewRound => {
+                if let Some(round) = self.rounds.last_mut() {
+                    round.end.get_or_insert(stat.time);
+                }
+                self.rounds.push(RoundSummary::new(stat.time));
+            }
+            StatKind::RoundEnd { winner } => {
+                let round = self.current_round(stat.time);
+                round.end = Some(stat.time);
+                round.winner = *winner;
+            }
+            StatKind::SessionStart { .. } => self.current_round(stat.time).sessions += 1,
+            StatKind::SessionEnd => (),
+            StatKind::Objective {
+                id,
+                pos,
+                owner,
+                kind,
+            } => {
+                let change = OwnerChange {
+                    time: stat.time,
+                    owner: *owner,
+                    by: vec![],
+                };
+                match self.objectives.get_mut_cow(id) {
+                    Some(obj) => {
+                        obj.pos = *pos;
+                        obj.kind = kind.clone();
+                        obj.owners.push(change);
+                    }
+                    None => {
+                        self.objectives.insert_cow(
+                            *id,
+                            ObjectiveHistory {
+                                pos: *pos,
+                                kind: kind.clone(),
+                                owners: vec![change],
+                            },
+                        );
+                    }
+                }
+            }
+            StatKind::Capture {
+                id,
+                ucid,
+                side,
+                points,
+            } => {
+                let player = self.player_mut(ucid);
+                player.captures += 1;
+                player.points += *points as i64;
+                if let Some(obj) = self.objectives.get_mut_cow(id) {
+                    // multiple players share a capture, they each get a
+                    // record with the same timestamp
+                    match obj.owners.last_mut() {
+                        Some(c) if c.time == stat.time && c.owner == *side => c.by.push(*ucid),
+                        Some(_) | None => obj.owners.push(OwnerChange {
+                            time: stat.time,
+                            owner: *side,
+                            by: vec![*ucid],
+                        }),
+                    }
+                }
+                self.current_round(stat.time).captures += 1;
+            }
+            StatKind::Repair {
+                id: _,
+                ucid,
+                points,
+            } => {
+                let player = self.player_mut(ucid);
+                player.repairs += 1;
+                player.points += *points as i64;
+            }
+            StatKind::Action { by, action: _ } => self.player_mut(by).actions += 1,
+            StatKind::Deploy {
+                ucid,
+                deployable: _,
+            } => self.player_mut(ucid).deploys += 1,
+            StatKind::Troop { ucid, troop: _ } => self.player_mut(ucid).troops += 1,
+            StatKind::ObjectiveStatus { .. } => (),
+            StatKind::PlayerRegister {
+                name,
+                ucid,
+                side,
+                points: _,
+            } => {
+                let player = self.player_mut(ucid);
+                player.name = name.clone();
+                player.side = *side;
+            }
+            StatKind::PlayerSideswitch { ucid, side } => self.player_mut(ucid).side = *side,
+            StatKind::Slot { .. } => (),
+            StatKind::Takeoff { ucid, aircraft } => {
+                let player = self.player_mut(ucid);
+                player.sorties += 1;
+                player.airframe = Some(aircraft.clone());
+                self.current_round(stat.time).sorties += 1;
+            }
+            StatKind::Land { .. } => (),
+            StatKind::Kill {
+                shots,
+                team_kill,
+                points,
+                assists,
+            } => self.ingest_kill(stat.time, shots, *team_kill, points, assists),
+        }
+    }
+
+    fn ingest_kill(
+        &mut self,
+        time: DateTime<Utc>,
+        dead: &Dead,
+        team_kill: bool,
+        points: &[(Ucid, usize)],
+        assists: &[Ucid],
+    ) {
+        // when assists are configured only the last hitter gets the
+        // kill, older logs credit everyone in points with it
+        for (ucid, pts) in points {
+            let player = self.player_mut(ucid);
+            if team_kill {
+                player.team_kills += 1;
+                player.points -= *pts as i64;
+            } else {
+                if assists.contains(ucid) {
+                    player.assists += 1;
+                } else {
+                    player.kills += 1;
+                }
+                player.points += *pts as i64;
+            }
+        }
+        let round = self.current_round(time);
+        if team_kill {
+            round.team_kills += 1
+        } else {
+            round.kills += 1
+        }
+        if let Some(victim) = dead.victim_ucid.as_ref() {
+            let player = self.player_mut(victim);
+            player.deaths += 1;
+            let airframe = dead
+                .shots
+                .iter()
+                .find(|s| s.target_typ.trim() != "")
+                .map(|s| Vehicle(s.target_typ.clone()))
+                .or_else(|| player.airframe.clone());
+            if let Some(airframe) = airframe {
+                *self.losses.get_or_default_cow(airframe.clone()) += 1;
+                *self.current_round(time).losses.get_or_default_cow(airframe) += 1;
+            }
+        }
+    }
+
+    fn player_mut(&mut self, ucid: &Ucid) -> &mut PlayerStats {
+        self.players.get_or_default_cow(*ucid)
+    }
+
+    fn current_round(&mut self, time: DateTime<Utc>) -> &mut RoundSummary {
+        // logs that predate the first NewRound record still count
+        // toward a round
+        if self.rounds.is_empty() {
+            self.rounds.push(RoundSummary::new(time))
+        }
+        self.rounds.last_mut().unwrap()
+    }
+
+    pub fn player(&self, ucid: &Ucid) -> Option<&PlayerStats> {
+        self.players.get(ucid)
+    }
+
+    pub fn players(&self) -> impl Iterator<Item = (&Ucid, &PlayerStats)> {
+        self.players.into_iter()
+    }
+
+    pub fn player_by_name(&self, name: &str) -> Option<(&Ucid, &PlayerStats)> {
+        self.players().find(|(_, p)| p.name.as_str() == name)
+    }
+
+    pub fn objective_history(&self, id: &ObjectiveId) -> Option<&ObjectiveHistory> {
+        self.objectives.get(id)
+    }
+
+    pub fn objectives(&self) -> impl Iterator<Item = (&ObjectiveId, &ObjectiveHistory)> {
+        self.objectives.into_iter()
+    }
+
+    pub fn rounds(&self) -> &[RoundSummary] {
+        &self.rounds
+    }
+
+    /// the number of player aircraft of type lost over the whole
+    /// campaign
+    pub fn airframe_losses(&self, typ: &Vehicle) -> u32 {
+        self.losses.get(typ).copied().unwrap_or(0)
+    }
+
+    pub fn losses(&self) -> impl Iterator<Item = (&Vehicle, u32)> {
+        self.losses.into_iter().map(|(v, n)| (v, *n))
+    }
+}
+
+/// the timestamp suffix bflib appends to each stats log
+fn log_timestamp(path: &Path) -> i64 {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .and_then(|n| n.strip_suffix(".jsonl.zst"))
+        .and_then(|n| n.rsplit_once("_STATS"))
+        .and_then(|(_, ts)| ts.parse::<i64>().ok())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bflib::stats::Shot;
+    use std::{env, io::Write};
+
+    fn ucid(i: u8) -> Ucid {
+        format!("{i:032x}").parse().unwrap()
+    }
+
+    fn ts(s: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(1_700_000_000 + s, 0).unwrap()
+    }
+
+    fn kill(time: i64, victim: Option<Ucid>, hits: &[Ucid], team_kill: bool) -> Dead {
+        let oid = |id: u64| serde_json::from_value(serde_json::json!({"id": id, "class": "Unit"}));
+        Dead {
+            victim: oid(1).unwrap(),
+            victim_ucid: victim,
+            victim_side: Side::Red,
+            victim_gid: None,
+            time: ts(time),
+            shots: hits
+                .iter()
+                .enumerate()
+                .map(|(i, shooter)| Shot {
+                    weapon_name: None,
+                    weapon: None,
+                    shooter: oid(i as u64 + 2).unwrap(),
+                    shooter_ucid: *shooter,
+                    shooter_gid: None,
+                    target: oid(1).unwrap(),
+                    target_side: Side::Red,
+                    target_ucid: victim,
+                    target_gid: None,
+                    target_typ: String::from("F-16C_50"),
+                    time: ts(time - 10 + i as i64),
+                    hit: !team_kill,
+                })
+                .collect(),
+        }
+    }
+
+    fn stat(time: i64, kind: StatKind) -> Stat {
+        Stat {
+            time: ts(time),
+            kind,
+        }
+    }
+
+    fn log_path(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("bfdb-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("Caucasus_STATS1700000000.jsonl.zst")
+    }
+
+    /// encode stats as one frame, the way bflib flushes its log
+    fn frame(stats: &[Stat]) -> Vec<u8> {
+        let mut buf = vec![];
+        for stat in stats {
+            serde_json::to_writer(&mut buf, stat).unwrap();
+            buf.push(b'\n');
+        }
+        zstd::stream::encode_all(&buf[..], 9).unwrap()
+    }
+
+    fn append(path: &Path, bytes: &[u8]) {
+        File::options()
+            .append(true)
+            .create(true)
+            .open(path)
+            .unwrap()
+            .write_all(bytes)
+            .unwrap()
+    }
+
+    #[test]
+    fn ingest_kill_with_assist() {
+        let (a, b, c) = (ucid(1), ucid(2), ucid(3));
+        let mut db = StatsDb::default();
+        db.ingest(&stat(
+            0,
+            StatKind::Kill {
+                shots: kill(0, Some(c), &[b, a], false),
+                team_kill: false,
+                points: vec![(a, 7), (b, 3)].into(),
+                assists: vec![b].into(),
+            },
+        ));
+        let pa = db.player(&a).unwrap();
+        assert_eq!((pa.kills, pa.assists, pa.points), (1, 0, 7));
+        let pb = db.player(&b).unwrap();
+        assert_eq!((pb.kills, pb.assists, pb.points), (0, 1, 3));
+        assert_eq!(db.player(&c).unwrap().deaths, 1);
+        assert_eq!(db.rounds()[0].kills, 1);
+        assert_eq!(db.airframe_losses(&Vehicle(String::from("F-16C_50"))), 1);
+    }
+
+    #[test]
+    fn ingest_kill_without_assists() {
+        let (a, b) = (ucid(1), ucid(2));
+        let mut st = serde_json::to_value(stat(
+            0,
+            StatKind::Kill {
+                shots: kill(0, None, &[a, b], false),
+                team_kill: false,
+                points: vec![(a, 5), (b, 5)].into(),
+                assists: vec![].into(),
+            },
+        ))
+        .unwrap();
+        // logs written before assists existed don't have the field
+        st.as_object_mut().unwrap().remove("assists");
+        let mut db = StatsDb::default();
+        db.ingest(&serde_json::from_value(st).unwrap());
+        for u in [a, b] {
+            let p = db.player(&u).unwrap();
+            assert_eq!((p.kills, p.assists, p.points), (1, 0, 5));
+        }
+        assert_eq!(db.rounds()[0].kills, 1);
+    }
+
+    #[test]
+    fn ingest_team_kill() {
+        let a = ucid(1);
+        let mut db = StatsDb::default();
+        db.ingest(&stat(
+            0,
+            StatKind::Kill {
+                shots: kill(0, None, &[a], true),
+                team_kill: true,
+                points: vec![(a, 5)].into(),
+                assists: vec![].into(),
+            },
+        ));
+        let p = db.player(&a).unwrap();
+        assert_eq!((p.kills, p.team_kills, p.points), (0, 1, -5));
+        assert_eq!(db.rounds()[0].team_kills, 1);
+    }
+
+    #[test]
+    fn resume_partial_log() {
+        let (a, b) = (ucid(1), ucid(2));
+        let path = log_path("resume_partial_log");
+        let db_path = path.with_file_name("stats.db");
+        let takeoff = |t, ucid| {
+            stat(
+                t,
+                StatKind::Takeoff {
+                    ucid,
+                    aircraft: Vehicle(String::from("F-16C_50")),
+                },
+            )
+        };
+        append(&path, &frame(&[stat(0, StatKind::NewRound), takeoff(1, a)]));
+        let mut db = StatsDb::open(&db_path).unwrap();
+        assert_eq!(db.ingest_file(&path).unwrap(), 2);
+        db.save(&db_path).unwrap();
+        // the server is in the middle of flushing the next frame
+        let next = frame(&[takeoff(2, a), takeoff(3, b)]);
+        let (head, tail) = next.split_at(next.len() / 2);
+        append(&path, head);
+        let mut db = StatsDb::open(&db_path).unwrap();
+        assert_eq!(db.ingest_file(&path).unwrap(), 0);
+        append(&path, tail);
+        assert_eq!(db.ingest_file(&path).unwrap(), 2);
+        assert_eq!(db.ingest_file(&path).unwrap(), 0);
+        assert_eq!(db.player(&a).unwrap().sorties, 2);
+        assert_eq!(db.player(&b).unwrap().sorties, 1);
+        assert_eq!(db.rounds().len(), 1);
+        assert_eq!(db.rounds()[0].sorties, 3);
+    }
+}
@@ -15,21 +15,26 @@ for more details.
 */
 
 use crate::{
+    adminsock::Remote,
+    audit, backup,
     bg::Task,
-    cfg::Cfg,
+    cfg::{AdminCommandKind, Cfg},
+    chatcmd::format_duration,
     db::{
         group::{DeployKind, GroupId},
         objective::ObjectiveId,
         Db, Set,
     },
+    ledger,
     msgq::MsgTyp,
     return_lives,
     spawnctx::{SpawnCtx, SpawnLoc},
+    stats::StatKind,
     Context,
 };
 use anyhow::{anyhow, bail, Context as AnyhowContext, Result};
 use chrono::{prelude::*, Duration};
-use compact_str::format_compact;
+use compact_str::{format_compact, CompactString};
 use dcso3::{
     coalition::Side,
     degrees_to_radians,
@@ -44,12 +49,12 @@ use dcso3::{
 };
 use enumflags2::BitFlags;
 use fxhash::FxHashMap;
-use log::warn;
+use log::{info, warn};
 use mlua::Value;
 use parking_lot::{Condvar, Mutex};
 use regex::{Regex, RegexBuilder};
 use smallvec::{smallvec, SmallVec};
-use std::{mem, str::FromStr, sync::Arc};
+use std::{cmp::max, mem, str::FromStr, sync::Arc};
 
 #[derive(Debug, Clone, Copy)]
 pub enum WarehouseKind {
@@ -69,6 +74,41 @@ impl FromStr for WarehouseKind {
     }
 }
 
+/// Who asked for an admin command to be run
+#[derive(Debug)]
+pub(super) enum Requester {
+    /// an admin in the game
+    Player(PlayerId),
+    /// a client connected to the admin socket
+    Remote(Remote),
+    /// a scheduled command, by schedule name
+    Schedule(String),
+}
+
+impl Requester {
+    fn player(&self) -> Option<PlayerId> {
+        match self {
+            Self::Player(id) => Some(*id),
+            Self::Remote(_) | Self::Schedule(_) => None,
+        }
+    }
+
+    fn reply(&mut self, ctx: &mut Context, msg: CompactString) {
+        match self {
+            Self::Player(id) => ctx.db.ephemeral.msgs().send(MsgTyp::Chat(Some(*id)), msg),
+            Self::Remote(r) => r.push(msg),
+            Self::Schedule(name) => info!("schedule {name}: {msg}"),
+        }
+    }
+
+    fn finish(self, outcome: &audit::Outcome) {
+        match self {
+            Self::Player(_) | Self::Schedule(_) => (),
+            Self::Remote(r) => r.finish(matches!(outcome, audit::Outcome::Ok(_))),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum AdminCommand {
     Help,
@@ -106,6 +146,17 @@ pub enum AdminCommand {
     Kick {
         player: String,
     },
+    Mute {
+        player: String,
+        until: Option<DateTime<Utc>>,
+    },
+    Unmute {
+        player: String,
+    },
+    SlowMode {
+        side: Option<Side>,
+        interval: Option<Duration>,
+    },
     Connected,
     Banned,
     Search {
@@ -132,6 +183,9 @@ pub enum AdminCommand {
         amount: i32,
         player: String,
     },
+    Ledger {
+        player: String,
+    },
     Delete {
         group: GroupId,
     },
@@ -141,43 +195,184 @@ pub enum AdminCommand {
     Remark {
         objective: String,
     },
+    Backups,
+    Restore {
+        ts: Option<i64>,
+    },
+    Audit {
+        player: Option<String>,
+        since: Option<DateTime<Utc>>,
+    },
+    Schedule,
     Reset,
     Shutdown,
 }
 
 impl AdminCommand {
-    pub fn help() -> &'static [&'static str] {
+    pub fn help() -> &'static [(AdminCommandKind, &'static str)] {
+        use AdminCommandKind::*;
         &[
-            "reduce <objective> <percent>: reduce supplies at objective by <percent>",
-            "transfer <from-objective> <to-objective>: transfer supplies between two objectives",
-            "tick: execute a logistics tick now",
-            "deliver: execute a logistics delivery now",
-            "repair <airbase>: repair one step at the specified airbase",
-            "tim <key> [size]: create explosions of [size] default 3000 at every f10 mark with text <key>",
-            "spawn <key>: spawn at f10 mark. <key> <troop|deployable> <side> <heading> <name>",
-            "switch <side> <alias|playerid|ucid>: force side switch a player",
-            "ban <duration|forever> <alias|playerid|ucid>: kick a player and ban them. e.g. ban 10days D4n",
-            "unban <alias|ucid>: unban a player",
-            "kick <alias|playerid|ucid>: kick a player",
-            "reset-lives <alias|playerid|ucid>",
-            "connected: list connected players",
-            "banned: list banned players",
-            "search <regex>: search the player list by regular expression",
-            "log-warehouse <objective|dcs> <airbase>: write the contents of the selected warehouse to the log file",
-            "log-desc: write the getDesc of the plane you are currently in to the log file",
-            "add-admin <player>: make the specified player a server admin",
-            "remove-admin <player>: remove the specified player from the admin list",
-            "balance <player>: show <player>'s point balance",
-            "set-points <n> <player>: set <player>'s point balance to <n>",
-            "delete <groupid>: delete deployed group, now with 100% less mess",
-            "deslot <player>: force <player> to spectators",
-            "remark <obj>: force refresh the markup on objective",
-            "reset: shutdown the server and reset the campaign state",
-            "shutdown: shutdown the server"
+            (ReduceInventory, "reduce <objective> <percent>: reduce supplies at objective by <percent>"),
+            (TransferSupply, "transfer <from-objective> <to-objective>: transfer supplies between two objectives"),
+            (LogisticsTickNow, "tick: execute a logistics tick now"),
+            (LogisticsDeliverNow, "deliver: execute a logistics delivery now"),
+            (Repair, "repair <airbase>: repair one step at the specified airbase"),
+            (Tim, "tim <key> [size]: create explosions of [size] default 3000 at every f10 mark with text <key>"),
+            (Spawn, "spawn <key>: spawn at f10 mark. <key> <troop|deployable> <side> <heading> <name>"),
+            (SideSwitch, "switch <side> <alias|playerid|ucid>: force side switch a player"),
+            (Ban, "ban <duration|forever> <alias|playerid|ucid>: kick a player and ban them. e.g. ban 10days D4n"),
+            (Unban, "unban <alias|ucid>: unban a player"),
+            (Kick, "kick <alias|playerid|ucid>: kick a player"),
+            (Mute, "mute <duration|forever> <alias|playerid|ucid>: drop a player's chat messages. e.g. mute 1h D4n"),
+            (Unmute, "unmute <alias|playerid|ucid>: unmute a player"),
+            (SlowMode, "slow-mode <duration|off> [side]: limit everyone, or one side, to one chat message per <duration>"),
+            (ResetLives, "reset-lives <alias|playerid|ucid>"),
+            (Connected, "connected: list connected players"),
+            (Banned, "banned: list banned players"),
+            (Search, "search <regex>: search the player list by regular expression"),
+            (LogWarehouse, "log-warehouse <objective|dcs> <airbase>: write the contents of the selected warehouse to the log file"),
+            (Logdesc, "log-desc: write the getDesc of the plane you are currently in to the log file"),
+            (AddAdmin, "add-admin <player>: make the specified player a server admin"),
+            (RemoveAdmin, "remove-admin <player>: remove the specified player from the admin list"),
+            (Balance, "balance <player>: show <player>'s point balance"),
+            (SetPoints, "set-points <n> <player>: set <player>'s point balance to <n>"),
+            (Ledger, "ledger <player>: show the last changes to <player>'s points"),
+            (Delete, "delete <groupid>: delete deployed group, now with 100% less mess"),
+            (Deslot, "deslot <player>: force <player> to spectators"),
+            (Remark, "remark <obj>: force refresh the markup on objective"),
+            (Backups, "backups: list the available save backups"),
+            (Restore, "restore <timestamp|cancel>: restore the backup <timestamp> at the next restart"),
+            (Audit, "audit [player|last <duration>]: show recent admin commands, those run by or against <player>, or those in the last <duration>"),
+            (Schedule, "schedule: list the upcoming scheduled commands"),
+            (Reset, "reset: shutdown the server and reset the campaign state"),
+            (Shutdown, "shutdown: shutdown the server"),
         ]
     }
 }
 
+impl AdminCommand {
+    /// The kind of command, used to check permissions. Help is
+    /// available to everyone who can run any admin command.
+    pub fn kind(&self) -> Option<AdminCommandKind> {
+        use AdminCommandKind as K;
+        Some(match self {
+            Self::Help => return None,
+            Self::ReduceInventory { .. } => K::ReduceInventory,
+            Self::TransferSupply { .. } => K::TransferSupply,
+            Self::LogisticsTickNow => K::LogisticsTickNow,
+            Self::LogisticsDeliverNow => K::LogisticsDeliverNow,
+            Self::Repair { .. } => K::Repair,
+            Self::Tim { .. } => K::Tim,
+            Self::Spawn { .. } => K::Spawn,
+            Self::SideSwitch { .. } => K::SideSwitch,
+            Self::Ban { .. } => K::Ban,
+            Self::Unban { .. } => K::Unban,
+            Self::Kick { .. } => K::Kick,
+            Self::Mute { .. } => K::Mute,
+            Self::Unmute { .. } => K::Unmute,
+            Self::SlowMode { .. } => K::SlowMode,
+            Self::Connected => K::Connected,
+            Self::Banned => K::Banned,
+            Self::Search { .. } => K::Search,
+            Self::LogWarehouse { .. } => K::LogWarehouse,
+            Self::Logdesc => K::Logdesc,
+            Self::ResetLives { .. } => K::ResetLives,
+            Self::AddAdmin { .. } => K::AddAdmin,
+            Self::RemoveAdmin { .. } => K::RemoveAdmin,
+            Self::Balance { .. } => K::Balance,
+            Self::SetPoints { .. } => K::SetPoints,
+            Self::Ledger { .. } => K::Ledger,
+            Self::Delete { .. } => K::Delete,
+            Self::Deslot { .. } => K::Deslot,
+            Self::Remark { .. } => K::Remark,
+            Self::Backups => K::Backups,
+            Self::Restore { .. } => K::Restore,
+            Self::Audit { .. } => K::Audit,
+            Self::Schedule => K::Schedule,
+            Self::Reset => K::Reset,
+            Self::Shutdown => K::Shutdown,
+        })
+    }
+
+    /// should running this command be recorded in the audit log
+    fn audited(&self) -> bool {
+        match self {
+            Self::Help | Self::Audit { .. } => false,
+            Self::ReduceInventory { .. }
+            | Self::TransferSupply { .. }
+            | Self::LogisticsTickNow
+            | Self::LogisticsDeliverNow
+            | Self::Repair { .. }
+            | Self::Tim { .. }
+            | Self::Spawn { .. }
+            | Self::SideSwitch { .. }
+            | Self::Ban { .. }
+            | Self::Unban { .. }
+            | Self::Kick { .. }
+            | Self::Mute { .. }
+            | Self::Unmute { .. }
+            | Self::SlowMode { .. }
+            | Self::Connected
+            | Self::Banned
+            | Self::Search { .. }
+            | Self::LogWarehouse { .. }
+            | Self::Logdesc
+            | Self::ResetLives { .. }
+            | Self::AddAdmin { .. }
+            | Self::RemoveAdmin { .. }
+            | Self::Balance { .. }
+            | Self::SetPoints { .. }
+            | Self::Ledger { .. }
+            | Self::Delete { .. }
+            | Self::Deslot { .. }
+            | Self::Remark { .. }
+            | Self::Backups
+            | Self::Restore { .. }
+            | Self::Schedule
+            | Self::Reset
+            | Self::Shutdown => true,
+        }
+    }
+
+    /// the player the command acts on, if any
+    fn target_player(&self) -> Option<&String> {
+        match self {
+            Self::SideSwitch { player, .. }
+            | Self::Ban { player, .. }
+            | Self::Unban { player }
+            | Self::Kick { player }
+            | Self::Mute { player, .. }
+            | Self::Unmute { player }
+            | Self::ResetLives { player }
+            | Self::AddAdmin { player }
+            | Self::RemoveAdmin { player }
+            | Self::Balance { player }
+            | Self::SetPoints { player, .. }
+            | Self::Ledger { player }
+            | Self::Deslot { player } => Some(player),
+            _ => None,
+        }
+    }
+
+    /// the player, objective, group, etc that the command acts on
+    fn target(&self) -> Option<String> {
+        match self {
+            Self::ReduceInventory { airbase, .. }
+            | Self::Repair { airbase }
+            | Self::LogWarehouse { airbase, .. } => Some(airbase.clone()),
+            Self::TransferSupply { from, to } => Some(format_compact!("{from} -> {to}").into()),
+            Self::Tim { key, .. } | Self::Spawn { key } => Some(key.clone()),
+            Self::Delete { group } => Some(format_compact!("{group}").into()),
+            Self::Remark { objective } => Some(objective.clone()),
+            Self::Restore { ts: Some(ts) } => Some(format_compact!("{ts}").into()),
+            Self::SlowMode {
+                side: Some(side), ..
+            } => Some(format_compact!("{side}").into()),
+            cmd => cmd.target_player().cloned(),
+        }
+    }
+}
+
 impl FromStr for AdminCommand {
     type Err = anyhow::Error;
 
@@ -256,6 +451,36 @@ impl FromStr for AdminCommand {
             Ok(Self::Unban { player: s.into() })
         } else if let Some(s) = s.strip_prefix("kick ") {
             Ok(Self::Kick { player: s.into() })
+        } else if let Some(s) = s.strip_prefix("mute ") {
+            match s.split_once(" ") {
+                None => bail!("mute <duration|forever> <alias|id|ucid>"),
+                Some((dur, player)) => {
+                    let until = if dur == "forever" {
+                        None
+                    } else {
+                        let dur = humantime::Duration::from_str(dur)?;
+                        Some(Utc::now() + Duration::seconds(dur.as_secs() as i64))
+                    };
+                    Ok(Self::Mute {
+                        player: player.into(),
+                        until,
+                    })
+                }
+            }
+        } else if let Some(s) = s.strip_prefix("unmute ") {
+            Ok(Self::Unmute { player: s.into() })
+        } else if let Some(s) = s.strip_prefix("slow-mode ") {
+            let (interval, side) = match s.trim().split_once(" ") {
+                None => (s.trim(), None),
+                Some((interval, side)) => (interval, Some(side.trim().parse::<Side>()?)),
+            };
+            let interval = if interval == "off" {
+                None
+            } else {
+                let dur = humantime::Duration::from_str(interval)?;
+                Some(Duration::seconds(dur.as_secs() as i64))
+            };
+            Ok(Self::SlowMode { side, interval })
         } else if let Some(_) = s.strip_prefix("connected") {
             Ok(Self::Connected)
         } else if let Some(_) = s.strip_prefix("banned") {
@@ -292,6 +517,8 @@ impl FromStr for AdminCommand {
                     player: player.into(),
                 }),
             }
+        } else if let Some(s) = s.strip_prefix("ledger ") {
+            Ok(Self::Ledger { player: s.into() })
         } else if let Some(s) = s.strip_prefix("delete ") {
             Ok(Self::Delete { group: s.parse()? })
         } else if let Some(s) = s.strip_prefix("deslot ") {
@@ -300,6 +527,33 @@ impl FromStr for AdminCommand {
             Ok(Self::Remark {
                 objective: s.into(),
             })
+        } else if s == "backups" {
+            Ok(Self::Backups)
+        } else if let Some(s) = s.strip_prefix("restore ") {
+            match s.trim() {
+                "cancel" => Ok(Self::Restore { ts: None }),
+                ts => Ok(Self::Restore {
+                    ts: Some(ts.parse::<i64>()?),
+                }),
+            }
+        } else if s == "audit" {
+            Ok(Self::Audit {
+                player: None,
+                since: None,
+            })
+        } else if let Some(s) = s.strip_prefix("audit last ") {
+            let dur = humantime::Duration::from_str(s.trim())?;
+            Ok(Self::Audit {
+                player: None,
+                since: Some(Utc::now() - Duration::seconds(dur.as_secs() as i64)),
+            })
+        } else if let Some(s) = s.strip_prefix("audit ") {
+            Ok(Self::Audit {
+                player: Some(s.trim().into()),
+                since: None,
+            })
+        } else if s == "schedule" {
+            Ok(Self::Schedule)
         } else if s == "reset" {
             Ok(Self::Reset)
         } else {
@@ -308,6 +562,29 @@ impl FromStr for AdminCommand {
     }
 }
 
+/// blow up every mark panel with the text key, returning how many there
+/// were
+fn admin_tim(ctx: &mut Context, lua: MizLua, key: &str, size: usize) -> Result<usize> {
+    let mut to_remove: SmallVec<[MarkId; 8]> = smallvec![];
+    let act = Trigger::singleton(lua)?.action()?;
+    for mk in World::singleton(lua)?
+        .get_mark_panels()
+        .context("getting marks")?
+    {
+        let mk = mk?;
+        if mk.text.as_str() == key {
+            to_remove.push(mk.id);
+            act.explosion(mk.pos, size as f32)
+                .context("making boom beserker!")?;
+        }
+    }
+    let n = to_remove.len();
+    for id in to_remove {
+        ctx.db.ephemeral.msgs().delete_mark(id);
+    }
+    Ok(n)
+}
+
 fn admin_spawn(ctx: &mut Context, lua: MizLua, id: PlayerId, key: String) -> Result<()> {
     let mut to_remove: SmallVec<[MarkId; 8]> = smallvec![];
     let act = Trigger::singleton(lua)?.action()?;
@@ -553,6 +830,17 @@ fn admin_ban(
         .player(&ucid)
         .map(|p| p.name.clone())
         .unwrap_or_else(|| name.clone());
+    ban(ctx, lua, ucid, until, name)
+}
+
+/// ban ucid, and kick them if they are connected
+pub(super) fn ban(
+    ctx: &mut Context,
+    lua: MizLua,
+    ucid: Ucid,
+    until: Option<DateTime<Utc>>,
+    name: String,
+) -> Result<()> {
     with_mut_cfg(ctx, |cfg| {
         cfg.banned.insert(ucid.clone(), (until, name));
         Ok(())
@@ -585,6 +873,46 @@ fn admin_unban(ctx: &mut Context, name: &String) -> Result<()> {
     })
 }
 
+/// lift the ban of ucid, if it is still the ban that ends at until
+pub(super) fn lift_ban(ctx: &mut Context, ucid: &Ucid, until: DateTime<Utc>) -> Result<()> {
+    match ctx.db.ephemeral.cfg.banned.get(ucid) {
+        Some((Some(ts), _)) if *ts == until => with_mut_cfg(ctx, |cfg| {
+            cfg.banned.remove(ucid);
+            Ok(())
+        }),
+        Some(_) | None => Ok(()),
+    }
+}
+
+fn admin_mute(ctx: &mut Context, until: Option<DateTime<Utc>>, name: &String) -> Result<()> {
+    let ucid = get_player_ucid(ctx, name.as_str())?;
+    let name = ctx
+        .db
+        .player(&ucid)
+        .map(|p| p.name.clone())
+        .unwrap_or_else(|| name.clone());
+    with_mut_cfg(ctx, |cfg| {
+        cfg.muted.insert(ucid.clone(), (until, name));
+        Ok(())
+    })?;
+    if let Some(id) = ctx.connected.id_by_ucid.get(&ucid) {
+        let msg = match until {
+            None => format_compact!("you have been muted"),
+            Some(ts) => format_compact!("you have been muted until {}", ts),
+        };
+        ctx.db.ephemeral.msgs().send(MsgTyp::Chat(Some(*id)), msg)
+    }
+    Ok(())
+}
+
+fn admin_unmute(ctx: &mut Context, name: &String) -> Result<()> {
+    let ucid = get_player_ucid(ctx, name.as_str())?;
+    with_mut_cfg(ctx, |cfg| match cfg.muted.remove(&ucid) {
+        None => bail!("was not muted"),
+        Some(_) => Ok(()),
+    })
+}
+
 fn admin_list_banned(ctx: &Context) -> SmallVec<[(Ucid, String, Option<DateTime<Utc>>); 16]> {
     ctx.db
         .ephemeral
@@ -653,6 +981,32 @@ fn admin_log_desc(ctx: &Context, lua: MizLua, ucid: &Ucid) -> Result<()> {
     Ok(())
 }
 
+fn admin_list_backups(ctx: &Context) -> Result<SmallVec<[CompactString; 16]>> {
+    let path = &ctx.miz_state_path;
+    let now = Utc::now().timestamp();
+    let index = backup::Index::load(path);
+    let staged = backup::staged(path)?;
+    let mut res = smallvec![];
+    for ts in backup::list(path)? {
+        let age = std::time::Duration::from_secs(max(0, now - ts) as u64);
+        let age = humantime::format_duration(age);
+        let summary = match index.get(ts) {
+            None => format_compact!("no summary"),
+            Some(s) => format_compact!(
+                "red {} blue {} neutral {} objectives, {} players, {} deployed",
+                s.red,
+                s.blue,
+                s.neutral,
+                s.players,
+                s.deployed
+            ),
+        };
+        let staged = if staged == Some(ts) { " (staged)" } else { "" };
+        res.push(format_compact!("{ts}: {age} old, {summary}{staged}"));
+    }
+    Ok(res)
+}
+
 fn admin_reset_lives(ctx: &mut Context, player: &String) -> Result<()> {
     let ucid = get_player_ucid(ctx, player)?;
     ctx.db.player_reset_lives(&ucid)
@@ -661,14 +1015,18 @@ fn admin_reset_lives(ctx: &mut Context, player: &String) -> Result<()> {
 pub(super) fn admin_shutdown(ctx: &mut Context, lua: MizLua, reset: bool) -> Result<()> {
     let wait = Arc::new((Mutex::new(false), Condvar::new()));
     if reset {
+        ctx.db.ephemeral.stat(StatKind::RoundEnd { winner: None });
         ctx.do_bg_task(Task::ResetState(ctx.miz_state_path.clone()))
     } else {
         return_lives(lua, ctx, DateTime::<Utc>::MAX_UTC);
         ctx.do_bg_task(Task::SaveState(
             ctx.miz_state_path.clone(),
             ctx.db.persisted.clone(),
+            ctx.db.ephemeral.cfg.checkpoint_interval,
         ));
     }
+    ctx.db.ephemeral.stat(StatKind::SessionEnd);
+    ctx.flush_stats();
     ctx.do_bg_task(Task::Sync(Arc::clone(&wait)));
     let &(ref lock, ref cvar) = &*wait;
     let mut synced = lock.lock();
@@ -712,13 +1070,12 @@ fn balance(ctx: &Context, player: &String) -> Result<i32> {
 
 fn set_points(ctx: &mut Context, player: &String, amount: i32) -> Result<()> {
     let ucid = get_player_ucid(ctx, player)?;
-    let player = ctx
-        .db
-        .player_mut(&ucid)
-        .ok_or_else(|| anyhow!("no such player {player}"))?;
-    player.points = amount;
-    ctx.db.ephemeral.dirty();
-    Ok(())
+    ctx.db.set_points(&ucid, amount)
+}
+
+fn admin_ledger(ctx: &Context, player: &String) -> Result<Vec<ledger::Entry>> {
+    let ucid = get_player_ucid(ctx, player)?;
+    ledger::query(&ctx.miz_state_path, &ucid, 20)
 }
 
 fn delete(ctx: &mut Context, id: &GroupId) -> Result<()> {
@@ -751,20 +1108,148 @@ fn remark(ctx: &mut Context, objective: &String) -> Result<()> {
     Ok(())
 }
 
+fn admin_audit(
+    ctx: &Context,
+    player: Option<String>,
+    since: Option<DateTime<Utc>>,
+) -> Result<SmallVec<[CompactString; 16]>> {
+    let filter = match (player, since) {
+        (None, None) => audit::Filter::All,
+        (_, Some(ts)) => audit::Filter::Since(ts),
+        (Some(player), None) => match get_player_ucid(ctx, &player) {
+            Ok(ucid) => audit::Filter::Ucid(ucid),
+            Err(_) => audit::Filter::Name(player),
+        },
+    };
+    let entries = audit::query(&ctx.miz_state_path, &filter, 20)?;
+    Ok(entries
+        .into_iter()
+        .map(|e| {
+            let outcome = match &e.outcome {
+                audit::Outcome::Ok(m) => format_compact!("ok: {m}"),
+                audit::Outcome::Failed(m) => format_compact!("failed: {m}"),
+            };
+            let admin = match &e.admin {
+                Some(ucid) => format_compact!("{} ({ucid})", e.admin_name),
+                None => format_compact!("{}", e.admin_name),
+            };
+            format_compact!(
+                "{} {} {} -> {}",
+                e.time.format("%Y-%m-%d %H:%M:%S"),
+                admin,
+                e.command,
+                outcome
+            )
+        })
+        .collect())
+}
+
+fn admin_schedule(ctx: &Context) -> SmallVec<[CompactString; 16]> {
+    let now = Utc::now();
+    let cfg = &ctx.db.ephemeral.cfg;
+    ctx.scheduler
+        .upcoming(cfg)
+        .into_iter()
+        .map(|(next, name, command)| match next {
+            None => format_compact!("{name}: \"{command}\" will not run again"),
+            Some(ts) => format_compact!(
+                "{name}: \"{command}\" at {} UTC, in {}",
+                ts.format("%Y-%m-%d %H:%M:%S"),
+                format_duration(ts - now)
+            ),
+        })
+        .collect()
+}
+
+fn record_audit(
+    ctx: &mut Context,
+    req: &Requester,
+    command: CompactString,
+    target: Option<String>,
+    target_ucid: Option<Ucid>,
+    outcome: audit::Outcome,
+) {
+    let (admin, admin_name) = match req {
+        Requester::Remote(r) => (None, String::from(format_compact!("{} (remote)", r.client))),
+        Requester::Schedule(name) => (None, String::from(format_compact!("{name} (schedule)"))),
+        Requester::Player(id) => match ctx.connected.get(id) {
+            Some(ifo) => (Some(ifo.ucid), ifo.name.clone()),
+            None => {
+                warn!("not auditing {command}, unknown admin {id}");
+                return;
+            }
+        },
+    };
+    let entry = audit::Entry {
+        time: Utc::now(),
+        admin,
+        admin_name,
+        command,
+        target,
+        target_ucid,
+        outcome,
+    };
+    let path = ctx.miz_state_path.clone();
+    ctx.do_bg_task(Task::Audit(path, entry))
+}
+
 pub(super) fn run_admin_commands(ctx: &mut Context, lua: MizLua) -> Result<()> {
+    ctx.queue_remote_admin_commands();
     let mut cmds = mem::take(&mut ctx.admin_commands);
-    for (id, cmd) in cmds.drain(..) {
+    for (mut req, cmd) in cmds.drain(..) {
+        let mut pending = cmd.audited().then(|| {
+            let target_ucid = cmd
+                .target_player()
+                .and_then(|p| get_player_ucid(ctx, p).ok());
+            (format_compact!("{cmd:?}"), cmd.target(), target_ucid)
+        });
+        let mut outcome = audit::Outcome::Ok(CompactString::from("ok"));
         macro_rules! reply {
             ($($arg:expr),+) => {
-                ctx.db.ephemeral.msgs().send(MsgTyp::Chat(Some(id)), format_compact!($($arg),+))
+                req.reply(ctx, format_compact!($($arg),+))
             }
         }
+        macro_rules! ok {
+            ($($arg:expr),+) => {{
+                let msg = format_compact!($($arg),+);
+                outcome = audit::Outcome::Ok(msg.clone());
+                req.reply(ctx, msg)
+            }}
+        }
+        macro_rules! fail {
+            ($($arg:expr),+) => {{
+                let msg = format_compact!($($arg),+);
+                outcome = audit::Outcome::Failed(msg.clone());
+                req.reply(ctx, msg)
+            }}
+        }
+        macro_rules! finish {
+            () => {{
+                if let Some((command, target, target_ucid)) = pending.take() {
+                    record_audit(ctx, &req, command, target, target_ucid, outcome.clone())
+                }
+                req.finish(&outcome)
+            }};
+        }
         macro_rules! airbase {
             ($name:expr) => {
                 match get_airbase(&ctx.db, $name) {
                     Ok(oid) => oid,
                     Err(e) => {
-                        reply!("{e:?}");
+                        fail!("{e:?}");
+                        finish!();
+                        continue;
+                    }
+                }
+            };
+        }
+        macro_rules! in_game {
+            () => {
+                match req.player() {
+                    Some(id) => id,
+                    None => {
+                        fail!("this command can only be run in game");
+                        finish!();
                         continue;
                     }
                 }
@@ -777,146 +1262,219 @@ pub(super) fn run_admin_commands(ctx: &mut Context, lua: MizLua) -> Result<()> {
                     .db
                     .admin_reduce_inventory(lua, airbase!(&airbase), amount)
                 {
-                    Err(e) => reply!("reduce inventory failed: {:?}", e),
-                    Ok(()) => reply!("inventory reduced"),
+                    Err(e) => fail!("reduce inventory failed: {:?}", e),
+                    Ok(()) => ok!("inventory reduced"),
                 }
             }
             AdminCommand::TransferSupply { from, to } => {
                 let from = airbase!(&from);
                 let to = airbase!(&to);
                 match ctx.db.transfer_supplies(lua, from, to) {
-                    Err(e) => reply!("transfer inventory failed {:?}", e),
-                    Ok(()) => reply!("transfer complete. disconnect"),
+                    Err(e) => fail!("transfer inventory failed {:?}", e),
+                    Ok(()) => ok!("transfer complete. disconnect"),
                 }
             }
             AdminCommand::LogisticsTickNow => {
                 ctx.db.admin_tick_now();
-                reply!("tick scheduled")
+                ok!("tick scheduled")
             }
             AdminCommand::LogisticsDeliverNow => {
                 ctx.db.admin_deliver_now();
-                reply!("delivery scheduled")
+                ok!("delivery scheduled")
             }
             AdminCommand::Repair { airbase } => {
                 match ctx.db.repair_objective(airbase!(&airbase), Utc::now()) {
-                    Ok(()) => reply!("repaired {airbase}"),
-                    Err(e) => reply!("failed to repair {e:?}"),
-                }
-            }
-            AdminCommand::Tim { key, size } => {
-                let mut to_remove: SmallVec<[MarkId; 8]> = smallvec![];
-                let act = Trigger::singleton(lua)?.action()?;
-                for mk in World::singleton(lua)?
-                    .get_mark_panels()
-                    .context("getting marks")?
-                {
-                    let mk = mk?;
-                    if mk.text == key {
-                        to_remove.push(mk.id);
-                        act.explosion(mk.pos, size as f32)
-                            .context("making boom beserker!")?;
-                    }
-                }
-                for id in to_remove {
-                    ctx.db.ephemeral.msgs().delete_mark(id);
-                }
-            }
-            AdminCommand::Spawn { key } => {
-                if let Err(e) = admin_spawn(ctx, lua, id, key) {
-                    reply!("could not spawn {:?}", e)
+                    Ok(()) => ok!("repaired {airbase}"),
+                    Err(e) => fail!("failed to repair {e:?}"),
                 }
             }
+            AdminCommand::Tim { key, size } => match admin_tim(ctx, lua, &key, size) {
+                Ok(n) => ok!("{n} explosions at {key}"),
+                Err(e) => fail!("tim failed {:?}", e),
+            },
+            AdminCommand::Spawn { key } => match admin_spawn(ctx, lua, in_game!(), key.clone()) {
+                Ok(()) => ok!("spawned {key}"),
+                Err(e) => fail!("could not spawn {:?}", e),
+            },
             AdminCommand::SideSwitch { side, player } => {
                 if let Err(e) = admin_sideswitch(ctx, side, player.clone()) {
-                    reply!("could not sideswitch {:?}", e)
+                    fail!("could not sideswitch {:?}", e)
                 } else {
-                    reply!("{player} sideswitched to {side}")
+                    ok!("{player} sideswitched to {side}")
                 }
             }
             AdminCommand::Ban { player, until } => match admin_ban(ctx, lua, until, &player) {
-                Ok(()) => reply!("{player} banned until {:?}", until),
-                Err(e) => reply!("could not ban {player}, {:?}", e),
+                Ok(()) => ok!("{player} banned until {:?}", until),
+                Err(e) => fail!("could not ban {player}, {:?}", e),
             },
             AdminCommand::Unban { player } => match admin_unban(ctx, &player) {
-                Ok(()) => reply!("{player} unbanned"),
-                Err(e) => reply!("could not unban {}, {:?}", player, e),
+                Ok(()) => ok!("{player} unbanned"),
+                Err(e) => fail!("could not unban {}, {:?}", player, e),
             },
             AdminCommand::Kick { player } => match admin_kick(ctx, lua, &player) {
-                Ok(()) => reply!("{player} kicked"),
-                Err(e) => reply!("could not kick {player}, {:?}", e),
+                Ok(()) => ok!("{player} kicked"),
+                Err(e) => fail!("could not kick {player}, {:?}", e),
+            },
+            AdminCommand::Mute { player, until } => match admin_mute(ctx, until, &player) {
+                Ok(()) => ok!("{player} muted until {:?}", until),
+                Err(e) => fail!("could not mute {player}, {:?}", e),
+            },
+            AdminCommand::Unmute { player } => match admin_unmute(ctx, &player) {
+                Ok(()) => ok!("{player} unmuted"),
+                Err(e) => fail!("could not unmute {}, {:?}", player, e),
             },
+            AdminCommand::SlowMode { side, interval } => {
+                ctx.slow_mode.set(side, interval);
+                let who = match side {
+                    None => format_compact!("everyone"),
+                    Some(side) => format_compact!("{side}"),
+                };
+                match interval {
+                    None => ok!("slow mode is off for {who}"),
+                    Some(d) => ok!("{who} may chat once every {}s", d.num_seconds()),
+                }
+            }
             AdminCommand::Banned => {
-                for (ucid, name, until) in admin_list_banned(ctx) {
+                let banned = admin_list_banned(ctx);
+                let n = banned.len();
+                for (ucid, name, until) in banned {
                     reply!("{ucid} \"{name}\" {:?}", until)
                 }
+                ok!("{n} banned")
             }
             AdminCommand::Connected => {
-                for (pid, ucid, name) in admin_list_connected(ctx) {
+                let connected = admin_list_connected(ctx);
+                let n = connected.len();
+                for (pid, ucid, name) in connected {
                     reply!("{pid} {ucid} {name}")
                 }
+                ok!("{n} connected")
             }
             AdminCommand::Search { expr } => {
-                for (pid, ucid, names) in admin_search(ctx, expr) {
+                let found = admin_search(ctx, expr);
+                let n = found.len();
+                for (pid, ucid, names) in found {
                     match pid {
                         None => reply!("{ucid} {:?}", names),
                         Some(pid) => reply!("{pid} {ucid} {:?}", names),
                     }
                 }
+                ok!("{n} found")
             }
             AdminCommand::LogWarehouse { kind, airbase } => {
                 match ctx.db.admin_log_inventory(lua, kind, airbase!(&airbase)) {
-                    Ok(()) => reply!("{airbase} inventory logged"),
-                    Err(e) => reply!("could not log {airbase} inventory {:?}", e),
+                    Ok(()) => ok!("{airbase} inventory logged"),
+                    Err(e) => fail!("could not log {airbase} inventory {:?}", e),
+                }
+            }
+            AdminCommand::Logdesc => {
+                let id = in_game!();
+                match ctx.connected.get(&id).map(|ifo| ifo.ucid) {
+                    None => fail!("no player {id}"),
+                    Some(ucid) => match admin_log_desc(ctx, lua, &ucid) {
+                        Ok(()) => ok!("{ucid} desc logged"),
+                        Err(e) => fail!("could not log admin desc {:?}", e),
+                    },
                 }
             }
-            AdminCommand::Logdesc => match ctx.connected.get(&id) {
-                None => reply!("no player {id}"),
-                Some(ifo) => match admin_log_desc(ctx, lua, &ifo.ucid) {
-                    Ok(()) => reply!("{} desc logged", ifo.ucid),
-                    Err(e) => reply!("could not log admin desc {:?}", e),
-                },
-            },
             AdminCommand::ResetLives { player } => match admin_reset_lives(ctx, &player) {
-                Ok(()) => reply!("{player} lives reset"),
-                Err(e) => reply!("could not reset {player} lives {:?}", e),
+                Ok(()) => ok!("{player} lives reset"),
+                Err(e) => fail!("could not reset {player} lives {:?}", e),
             },
             AdminCommand::Shutdown => match admin_shutdown(ctx, lua, false) {
-                Ok(()) => reply!("shutting down"),
-                Err(e) => reply!("failed to shutdown {:?}", e),
+                Ok(()) => ok!("shutting down"),
+                Err(e) => fail!("failed to shutdown {:?}", e),
             },
             AdminCommand::AddAdmin { player } => match add_admin(ctx, &player) {
-                Ok(()) => reply!("{player} is now an admin"),
-                Err(e) => reply!("failed to make {player} an admin {e:?}"),
+                Ok(()) => ok!("{player} is now an admin"),
+                Err(e) => fail!("failed to make {player} an admin {e:?}"),
             },
             AdminCommand::RemoveAdmin { player } => match remove_admin(ctx, &player) {
-                Ok(()) => reply!("{player} is no longer an admin"),
-                Err(e) => reply!("failed to remove {player} from the admin list {e:?}"),
+                Ok(()) => ok!("{player} is no longer an admin"),
+                Err(e) => fail!("failed to remove {player} from the admin list {e:?}"),
             },
             AdminCommand::Balance { player } => match balance(ctx, &player) {
-                Ok(b) => reply!("{player}'s balance is {b}"),
-                Err(e) => reply!("could not get {player}'s balance {e:?}"),
+                Ok(b) => ok!("{player}'s balance is {b}"),
+                Err(e) => fail!("could not get {player}'s balance {e:?}"),
             },
             AdminCommand::SetPoints { amount, player } => match set_points(ctx, &player, amount) {
-                Ok(()) => reply!("{player}'s points set to {amount}"),
-                Err(e) => reply!("could not set {player}'s points {e:?}"),
+                Ok(()) => ok!("{player}'s points set to {amount}"),
+                Err(e) => fail!("could not set {player}'s points {e:?}"),
+            },
+            AdminCommand::Ledger { player } => match admin_ledger(ctx, &player) {
+                Err(e) => fail!("could not read {player}'s ledger {e:?}"),
+                Ok(entries) if entries.is_empty() => ok!("{player} has no ledger entries"),
+                Ok(entries) => {
+                    let n = entries.len();
+                    for e in entries {
+                        reply!("{e}")
+                    }
+                    ok!("{n} ledger entries")
+                }
             },
             AdminCommand::Delete { group } => match delete(ctx, &group) {
-                Ok(()) => reply!("{group} deleted"),
-                Err(e) => reply!("could not delete group {e:?}"),
+                Ok(()) => ok!("{group} deleted"),
+                Err(e) => fail!("could not delete group {e:?}"),
             },
             AdminCommand::Deslot { player } => match deslot(ctx, &player) {
-                Ok(()) => reply!("{player} deslotted"),
-                Err(e) => reply!("could not deslot {player} {e:?}"),
+                Ok(()) => ok!("{player} deslotted"),
+                Err(e) => fail!("could not deslot {player} {e:?}"),
             },
             AdminCommand::Remark { objective } => match remark(ctx, &objective) {
-                Ok(()) => reply!("{objective} remark queued"),
-                Err(e) => reply!("could not remark {objective} {e:?}"),
+                Ok(()) => ok!("{objective} remark queued"),
+                Err(e) => fail!("could not remark {objective} {e:?}"),
+            },
+            AdminCommand::Backups => match admin_list_backups(ctx) {
+                Err(e) => fail!("could not list backups {e:?}"),
+                Ok(backups) if backups.is_empty() => ok!("there are no backups"),
+                Ok(backups) => {
+                    let n = backups.len();
+                    for b in backups {
+                        reply!("{b}")
+                    }
+                    ok!("{n} backups")
+                }
             },
+            AdminCommand::Restore { ts: None } => match backup::unstage(&ctx.miz_state_path) {
+                Ok(None) => ok!("no restore was staged"),
+                Ok(Some(ts)) => ok!("restore of {ts} canceled"),
+                Err(e) => fail!("could not cancel the restore {e:?}"),
+            },
+            AdminCommand::Restore { ts: Some(ts) } => {
+                match backup::stage(&ctx.miz_state_path, ts) {
+                    Ok(()) => ok!("{ts} will be restored when the mission restarts"),
+                    Err(e) => fail!("could not stage {ts} {e:?}"),
+                }
+            }
+            AdminCommand::Schedule => {
+                let upcoming = admin_schedule(ctx);
+                if upcoming.is_empty() {
+                    ok!("there are no scheduled commands")
+                } else {
+                    let n = upcoming.len();
+                    for s in upcoming {
+                        reply!("{s}")
+                    }
+                    ok!("{n} scheduled commands")
+                }
+            }
             AdminCommand::Reset => match admin_shutdown(ctx, lua, true) {
-                Ok(()) => reply!("the state has been reset"),
-                Err(e) => reply!("the state could not be reset {e:?}"),
+                Ok(()) => ok!("the state has been reset"),
+                Err(e) => fail!("the state could not be reset {e:?}"),
+            },
+            AdminCommand::Audit { player, since } => match admin_audit(ctx, player, since) {
+                Err(e) => fail!("could not query the audit log {e:?}"),
+                Ok(entries) if entries.is_empty() => ok!("no matching audit entries"),
+                Ok(entries) => {
+                    let n = entries.len();
+                    for e in entries {
+                        reply!("{e}")
+                    }
+                    ok!("{n} audit entries")
+                }
             },
         }
+        finish!()
     }
     ctx.admin_commands = cmds;
     Ok(())
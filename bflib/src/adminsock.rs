@@ -0,0 +1,244 @@
+/*
+Copyright 2024 Eric Stokes.
+
+This file is part of bflib.
+
+bflib is free software: you can redistribute it and/or modify it under
+the terms of the GNU Affero Public License as published by the Free
+Software Foundation, either version 3 of the License, or (at your
+option) any later version.
+
+bflib is distributed in the hope that it will be useful, but WITHOUT
+ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero Public License
+for more details.
+*/
+
+//! Accept admin commands from tools running outside the game. The
+//! background thread listens on the address in `admin_socket`. Each
+//! request is one line of json,
+//!
+//! ```json
+//! {"client": "ops", "key": "secret", "command": "ban 1d Bob"}
+//! ```
+//!
+//! where command uses the same syntax as `-admin` in chat. The command
+//! is queued with the ones typed in game, and when it has run one line
+//! of json is written back,
+//!
+//! ```json
+//! {"ok": true, "lines": ["Bob banned until ..."]}
+//! ```
+//!
+//! A request with an unknown client or the wrong key, or a line longer
+//! than 64 KiB, is answered and then the connection is closed.
+
+use crate::{admin::AdminCommand, cfg::Cfg};
+use anyhow::Result;
+use compact_str::{format_compact, CompactString};
+use log::{error, info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::{net::SocketAddr, sync::Arc};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc::UnboundedSender, oneshot},
+    task::JoinSet,
+};
+
+#[derive(Debug, Clone, Serialize)]
+pub(super) struct Response {
+    ok: bool,
+    lines: Vec<CompactString>,
+}
+
+impl Response {
+    fn error(msg: CompactString) -> Self {
+        Self {
+            ok: false,
+            lines: vec![msg],
+        }
+    }
+}
+
+/// The client that sent a command, and where to send the result
+#[derive(Debug)]
+pub(super) struct Remote {
+    pub(super) client: CompactString,
+    lines: Vec<CompactString>,
+    reply: oneshot::Sender<Response>,
+}
+
+impl Remote {
+    pub(super) fn push(&mut self, line: CompactString) {
+        self.lines.push(line)
+    }
+
+    /// send the accumulated lines back to the client
+    pub(super) fn finish(self, ok: bool) {
+        let _ = self.reply.send(Response {
+            ok,
+            lines: self.lines,
+        });
+    }
+}
+
+#[derive(Debug)]
+pub(super) struct Request {
+    pub(super) remote: Remote,
+    pub(super) cmd: AdminCommand,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WireRequest {
+    client: CompactString,
+    key: CompactString,
+    command: CompactString,
+}
+
+async fn run(
+    cfg: &Cfg,
+    tx: &UnboundedSender<Request>,
+    client: CompactString,
+    role: Option<&str>,
+    command: &str,
+) -> Response {
+    let cmd = match command.parse::<AdminCommand>() {
+        Ok(cmd) => cmd,
+        Err(e) => return Response::error(format_compact!("parse error {e:?}")),
+    };
+    match cmd.kind() {
+        None => Response {
+            ok: true,
+            lines: AdminCommand::help()
+                .iter()
+                .filter(|(kind, _)| cfg.role_permits(role, *kind))
+                .map(|(_, line)| CompactString::from(*line))
+                .collect(),
+        },
+        Some(kind) if !cfg.role_permits(role, kind) => {
+            Response::error(format_compact!("you are not permitted to run {kind:?}"))
+        }
+        Some(_) => {
+            info!("queueing remote admin command {cmd:?} from {client}");
+            let (reply, result) = oneshot::channel();
+            let remote = Remote {
+                client,
+                lines: vec![],
+                reply,
+            };
+            if tx.send(Request { remote, cmd }).is_err() {
+                return Response::error("the mission is not running".into());
+            }
+            match result.await {
+                Ok(res) => res,
+                Err(_) => Response::error("the command failed, see the server log".into()),
+            }
+        }
+    }
+}
+
+/// Requests longer than this are refused and the connection is closed,
+/// so an unauthenticated peer can't make us buffer without bound
+const MAX_REQUEST: u64 = 64 * 1024;
+
+/// Compare two keys in a time that does not depend on where they
+/// differ
+fn keys_match(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+async fn serve(
+    cfg: Arc<Cfg>,
+    tx: UnboundedSender<Request>,
+    sock: TcpStream,
+    addr: SocketAddr,
+) -> Result<()> {
+    let clients = match &cfg.admin_socket {
+        Some(s) => &s.clients,
+        None => return Ok(()),
+    };
+    let (rd, mut wr) = sock.into_split();
+    let mut rd = BufReader::new(rd);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let n = (&mut rd)
+            .take(MAX_REQUEST)
+            .read_until(b'\n', &mut line)
+            .await?;
+        if n == 0 {
+            break;
+        }
+        if line.iter().all(|c| c.is_ascii_whitespace()) {
+            continue;
+        }
+        let mut close = false;
+        let res = if n as u64 == MAX_REQUEST && line.last() != Some(&b'\n') {
+            warn!("admin request from {addr} is too long");
+            close = true;
+            Response::error(format_compact!(
+                "requests are limited to {MAX_REQUEST} bytes"
+            ))
+        } else {
+            match serde_json::from_slice::<WireRequest>(&line) {
+                Err(e) => Response::error(format_compact!("invalid request {e}")),
+                Ok(req) => match clients.get(req.client.as_str()) {
+                    Some(c) if keys_match(c.key.as_str(), req.key.as_str()) => {
+                        let role = c.role.as_ref().map(|r| r.as_str());
+                        run(&cfg, &tx, req.client, role, &req.command).await
+                    }
+                    Some(_) | None => {
+                        warn!("admin socket auth failed for {} from {addr}", req.client);
+                        close = true;
+                        Response::error("unknown client or wrong key".into())
+                    }
+                },
+            }
+        };
+        let mut buf = serde_json::to_vec(&res)?;
+        buf.push(b'\n');
+        wr.write_all(&buf).await?;
+        if close {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Listen for admin clients until aborted. Aborting also closes every
+/// connection accepted by this listener, so clients removed from the
+/// config are cut off when it is restarted.
+pub(super) async fn listen(cfg: Arc<Cfg>, tx: UnboundedSender<Request>) {
+    let addr = match &cfg.admin_socket {
+        Some(s) => s.listen.clone(),
+        None => return,
+    };
+    let listener = match TcpListener::bind(addr.as_str()).await {
+        Ok(l) => l,
+        Err(e) => {
+            error!("could not listen for admin commands on {addr} {e:?}");
+            return;
+        }
+    };
+    info!("listening for admin commands on {addr}");
+    let mut connections = JoinSet::new();
+    loop {
+        tokio::select! {
+            r = listener.accept() => match r {
+                Err(e) => error!("failed to accept an admin connection {e:?}"),
+                Ok((sock, peer)) => {
+                    info!("admin connection from {peer}");
+                    connections.spawn(serve(Arc::clone(&cfg), tx.clone(), sock, peer));
+                }
+            },
+            Some(r) = connections.join_next() => match r {
+                Ok(Ok(())) => (),
+                Ok(Err(e)) => warn!("admin connection failed {e:?}"),
+                Err(e) => error!("admin connection panicked {e:?}"),
+            },
+        }
+    }
+}
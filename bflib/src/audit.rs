@@ -0,0 +1,146 @@
+/*
+Copyright 2024 Eric Stokes.
+
+This file is part of bflib.
+
+bflib is free software: you can redistribute it and/or modify it under
+the terms of the GNU Affero Public License as published by the Free
+Software Foundation, either version 3 of the License, or (at your
+option) any later version.
+
+bflib is distributed in the hope that it will be useful, but WITHOUT
+ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero Public License
+for more details.
+*/
+
+//! An append only record of the admin commands run on the server. One
+//! json object per line is written to `<save>_AUDIT`. The file is never
+//! rotated or reset, so it survives campaign resets too.
+
+use crate::backup::with_suffix;
+use anyhow::Result;
+use chrono::prelude::*;
+use compact_str::CompactString;
+use dcso3::{net::Ucid, String};
+use log::warn;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, BufRead, BufReader, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Outcome {
+    Ok(CompactString),
+    Failed(CompactString),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub time: DateTime<Utc>,
+    /// None if the command came from the admin socket
+    pub admin: Option<Ucid>,
+    pub admin_name: String,
+    /// the parsed command
+    pub command: CompactString,
+    /// the player, objective, or group the command was run against
+    pub target: Option<String>,
+    /// the target player's ucid, if the target is a player and could be
+    /// resolved when the command ran
+    pub target_ucid: Option<Ucid>,
+    pub outcome: Outcome,
+}
+
+#[derive(Debug, Clone)]
+pub enum Filter {
+    All,
+    /// entries run by or against the player with this ucid
+    Ucid(Ucid),
+    /// entries where the admin or target name contains this string
+    Name(String),
+    Since(DateTime<Utc>),
+}
+
+impl Filter {
+    fn matches(&self, e: &Entry) -> bool {
+        match self {
+            Self::All => true,
+            Self::Ucid(ucid) => {
+                e.admin.as_ref() == Some(ucid) || e.target_ucid.as_ref() == Some(ucid)
+            }
+            Self::Name(name) => {
+                e.admin_name.contains(name.as_str())
+                    || e.target
+                        .as_ref()
+                        .map(|t| t.contains(name.as_str()))
+                        .unwrap_or(false)
+            }
+            Self::Since(ts) => &e.time >= ts,
+        }
+    }
+}
+
+fn path(save: &Path) -> Result<PathBuf> {
+    with_suffix(save, "_AUDIT")
+}
+
+pub(super) fn append(save: &Path, entry: &Entry) -> Result<()> {
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+    let mut file = File::options()
+        .create(true)
+        .append(true)
+        .open(path(save)?)?;
+    file.write_all(&line)?;
+    Ok(())
+}
+
+/// Queries only search this many bytes at the end of the audit log,
+/// since they run on the game thread and the log grows without bound
+const QUERY_TAIL: u64 = 1024 * 1024;
+
+/// the last `limit` entries that match filter, oldest first. Only the
+/// most recent entries, the last `QUERY_TAIL` bytes of the log, are
+/// searched.
+pub(super) fn query(save: &Path, filter: &Filter, limit: usize) -> Result<Vec<Entry>> {
+    let mut file = match File::open(path(save)?) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let len = file.metadata()?.len();
+    let partial = len > QUERY_TAIL;
+    if partial {
+        file.seek(SeekFrom::Start(len - QUERY_TAIL))?;
+    }
+    let mut res = VecDeque::new();
+    let mut lines = BufReader::new(file).split(b'\n');
+    if partial {
+        // we probably started in the middle of a line
+        lines.next();
+    }
+    for line in lines {
+        let line = line?;
+        if line.iter().all(|c| c.is_ascii_whitespace()) {
+            continue;
+        }
+        // a crash in the middle of an append can leave a torn line
+        let entry: Entry = match serde_json::from_slice(&line) {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!("skipping bad audit log line {e:?}");
+                continue;
+            }
+        };
+        if filter.matches(&entry) {
+            if res.len() == limit {
+                res.pop_front();
+            }
+            res.push_back(entry);
+        }
+    }
+    Ok(res.into())
+}
@@ -0,0 +1,242 @@
+/*
+Copyright 2024 Eric Stokes.
+
+This file is part of bflib.
+
+bflib is free software: you can redistribute it and/or modify it under
+the terms of the GNU Affero Public License as published by the Free
+Software Foundation, either version 3 of the License, or (at your
+option) any later version.
+
+bflib is distributed in the hope that it will be useful, but WITHOUT
+ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero Public License
+for more details.
+*/
+
+use crate::db::persisted::Persisted;
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::prelude::*;
+use dcso3::coalition::Side;
+use log::{error, info};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+pub(super) fn with_suffix(path: &Path, suffix: &str) -> Result<PathBuf> {
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| anyhow!("save file with no name"))?;
+    let mut path = PathBuf::from(path);
+    path.set_file_name(format!("{name}{suffix}"));
+    Ok(path)
+}
+
+/// the path of the backup of the save file taken at ts
+pub(super) fn backup_path(path: &Path, ts: i64) -> Result<PathBuf> {
+    with_suffix(path, &ts.to_string())
+}
+
+/// A short description of the campaign state in a save file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Summary {
+    pub red: usize,
+    pub blue: usize,
+    pub neutral: usize,
+    pub players: usize,
+    pub deployed: usize,
+}
+
+impl Summary {
+    pub fn new(persisted: &Persisted) -> Self {
+        let mut t = Self {
+            red: 0,
+            blue: 0,
+            neutral: 0,
+            players: persisted.players.len(),
+            deployed: persisted.deployed.len(),
+        };
+        for (_, obj) in &persisted.objectives {
+            match obj.owner() {
+                Side::Red => t.red += 1,
+                Side::Blue => t.blue += 1,
+                Side::Neutral => t.neutral += 1,
+            }
+        }
+        t
+    }
+}
+
+/// Summaries of the current save and its backups, kept next to the save
+/// file so listing backups doesn't require decoding all of them.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Index {
+    current: Option<Summary>,
+    backups: BTreeMap<i64, Summary>,
+}
+
+impl Index {
+    fn path(save: &Path) -> Result<PathBuf> {
+        with_suffix(save, "_BACKUPS")
+    }
+
+    pub fn load(save: &Path) -> Self {
+        let res = Self::path(save).and_then(|path| match fs::read(&path) {
+            Ok(buf) => Ok(serde_json::from_slice(&buf)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        });
+        match res {
+            Ok(idx) => idx,
+            Err(e) => {
+                error!("could not load the backup index for {save:?}, {e:?}");
+                Self::default()
+            }
+        }
+    }
+
+    pub fn save(&self, save: &Path) -> Result<()> {
+        let path = Self::path(save)?;
+        let mut tmp = path.clone();
+        tmp.set_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(self)?)?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    pub fn set_current(&mut self, summary: Summary) {
+        self.current = Some(summary)
+    }
+
+    /// the current save was moved to the backup at ts
+    pub fn rotated(&mut self, save: &Path, ts: i64) {
+        let summary = self.current.take().or_else(|| {
+            let path = backup_path(save, ts).ok()?;
+            Persisted::load(&path).ok().map(|p| Summary::new(&p))
+        });
+        if let Some(summary) = summary {
+            self.backups.insert(ts, summary);
+        }
+    }
+
+    pub fn removed(&mut self, ts: i64) {
+        self.backups.remove(&ts);
+    }
+
+    pub fn get(&self, ts: i64) -> Option<&Summary> {
+        self.backups.get(&ts)
+    }
+}
+
+/// list the timestamps of the available backups of the save file, newest
+/// first
+pub(super) fn list(save: &Path) -> Result<Vec<i64>> {
+    let name = save
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| anyhow!("save file with no name"))?;
+    let dir = save
+        .parent()
+        .ok_or_else(|| anyhow!("path has no parent dir"))?;
+    let mut backups = vec![];
+    for file in fs::read_dir(dir)? {
+        let file = file?;
+        if file.file_type()?.is_file() {
+            if let Some(ts) = file
+                .file_name()
+                .to_str()
+                .and_then(|f| f.strip_prefix(name))
+                .and_then(|ts| ts.parse::<i64>().ok())
+            {
+                backups.push(ts)
+            }
+        }
+    }
+    backups.sort();
+    backups.reverse();
+    Ok(backups)
+}
+
+fn staged_path(save: &Path) -> Result<PathBuf> {
+    with_suffix(save, "_RESTORE")
+}
+
+/// the backup that will be restored when the mission next starts
+pub(super) fn staged(save: &Path) -> Result<Option<i64>> {
+    match fs::read_to_string(staged_path(save)?) {
+        Ok(s) => Ok(Some(s.trim().parse::<i64>()?)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// restore the backup taken at ts the next time the mission starts
+pub(super) fn stage(save: &Path, ts: i64) -> Result<()> {
+    if !backup_path(save, ts)?.exists() {
+        bail!("there is no backup {ts}")
+    }
+    fs::write(staged_path(save)?, ts.to_string())?;
+    Ok(())
+}
+
+/// cancel a staged restore, returning the backup that was staged if any
+pub(super) fn unstage(save: &Path) -> Result<Option<i64>> {
+    let staged = staged(save)?;
+    if staged.is_some() {
+        fs::remove_file(staged_path(save)?)?;
+    }
+    Ok(staged)
+}
+
+/// Called at mission start before the save is loaded. If a restore is
+/// staged then the current state (including its journal) is checkpointed
+/// as a backup, and the staged backup is moved into place. The restore
+/// is only unstaged once the backup is in place, so if anything fails
+/// the save is left as it was and the restore will be tried again. Return
+/// true if a backup was restored, in which case the background journal
+/// must be reset, since it still holds the state from before the restore.
+pub(super) fn restore_staged(save: &Path) -> Result<bool> {
+    let ts = match staged(save)? {
+        None => return Ok(false),
+        Some(ts) => ts,
+    };
+    let backup = backup_path(save, ts)?;
+    if !backup.exists() {
+        unstage(save)?;
+        bail!("the staged backup {ts} no longer exists")
+    }
+    let restore = with_suffix(save, "_RESTORE.tmp")?;
+    fs::copy(&backup, &restore).context("copying the backup")?;
+    let mut idx = Index::load(save);
+    if save.exists() {
+        let current = Persisted::load(save).context("loading the current state")?;
+        let now = Utc::now().timestamp();
+        let keep = backup_path(save, now)?;
+        let tmp = with_suffix(save, ".tmp")?;
+        let file = fs::File::create(&tmp)?;
+        let mut file = zstd::stream::Encoder::new(file, 9)?.auto_finish();
+        serde_json::to_writer(&mut file, &current.versioned())?;
+        drop(file);
+        fs::rename(&tmp, &keep)?;
+        idx.current = None;
+        idx.backups.insert(now, Summary::new(&current));
+        info!("the state before the restore was kept as backup {now}");
+    }
+    match fs::remove_file(Persisted::journal_path(save)) {
+        Ok(()) => (),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e.into()),
+    }
+    fs::rename(&restore, save).context("moving the backup into place")?;
+    unstage(save)?;
+    idx.current = idx.backups.get(&ts).cloned();
+    if let Err(e) = idx.save(save) {
+        error!("could not save the backup index {e:?}")
+    }
+    info!("restored backup {ts}");
+    Ok(true)
+}
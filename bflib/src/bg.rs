@@ -14,13 +14,20 @@ FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero Public License
 for more details.
 */
 
-use crate::{cfg::Cfg, db::persisted::Persisted, Perf};
+use crate::{
+    adminsock, audit, backup,
+    cfg::Cfg,
+    db::persisted::{Persisted, PersistedDelta},
+    ledger, round, schedule,
+    stats::Stat,
+    Perf,
+};
 use anyhow::{anyhow, Result};
 use bytes::{BufMut, Bytes, BytesMut};
 use chrono::prelude::*;
 use compact_str::{format_compact, CompactString};
 use fxhash::FxHashMap;
-use log::error;
+use log::{error, info};
 use once_cell::sync::OnceCell;
 use parking_lot::{Condvar, Mutex};
 use simplelog::{LevelFilter, WriteLogger};
@@ -36,6 +43,7 @@ use tokio::{
     io::AsyncWriteExt,
     runtime::Builder,
     sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    task::JoinHandle,
 };
 
 struct LogHandle(UnboundedSender<Task>);
@@ -50,7 +58,7 @@ fn encode(db: &Persisted) -> Result<Bytes> {
     }
     BUF.with(|buf| {
         let mut buf = buf.borrow_mut();
-        serde_json::to_writer((&mut *buf).writer(), db)?;
+        serde_json::to_writer((&mut *buf).writer(), &db.versioned())?;
         Ok(buf.split().freeze())
     })
 }
@@ -68,6 +76,10 @@ fn rotate(path: &Path) -> Result<()> {
         write!(backup, "{}", now.timestamp()).unwrap();
         with_ts.set_file_name(backup);
         fs::rename(path, with_ts)?;
+        let mut index = backup::Index::load(path);
+        index.rotated(path, now.timestamp());
+        // never thin out a backup that is waiting to be restored
+        let staged = backup::staged(path).unwrap_or(None);
         let dir = path
             .parent()
             .ok_or_else(|| anyhow!("path has no parent dir"))?;
@@ -89,6 +101,9 @@ fn rotate(path: &Path) -> Result<()> {
             if file.file_type()?.is_file() {
                 if let Some(ts) = fname.strip_prefix(name) {
                     if let Ok(ts) = ts.parse::<i64>() {
+                        if Some(ts) == staged {
+                            continue;
+                        }
                         let age = now - ts;
                         let file = PathBuf::from(file.path());
                         if age > month {
@@ -130,9 +145,12 @@ fn rotate(path: &Path) -> Result<()> {
             paths.sort_by_key(|(ts, _)| *ts);
             paths.reverse();
             while paths.len() > 1 {
-                fs::remove_file(paths.pop().unwrap().1)?;
+                let (ts, path) = paths.pop().unwrap();
+                fs::remove_file(path)?;
+                index.removed(ts);
             }
         }
+        index.save(path)?;
     }
     Ok(())
 }
@@ -153,7 +171,91 @@ fn save(path: &Path, encoded: Bytes) -> Result<()> {
         error!("failed to rotate backup files {e:?}")
     }
     fs::rename(tmp, path)?;
-    Ok(())
+    // the new checkpoint includes everything in the journal. If we die
+    // before it is removed load will see that it is older than the
+    // checkpoint and ignore it.
+    remove_journal(path)
+}
+
+fn remove_journal(path: &Path) -> Result<()> {
+    match fs::remove_file(Persisted::journal_path(path)) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+struct Journal {
+    path: PathBuf,
+    last: Persisted,
+    since_checkpoint: usize,
+}
+
+impl Journal {
+    fn append(&mut self, db: Persisted) -> Result<()> {
+        use std::{fs::File, io::Write};
+        let delta = PersistedDelta::new(&self.last, &db);
+        let mut file = zstd::stream::Encoder::new(Vec::new(), 9)?;
+        serde_json::to_writer(&mut file, &delta)?;
+        let frame = file.finish()?;
+        File::options()
+            .create(true)
+            .append(true)
+            .open(Persisted::journal_path(&self.path))?
+            .write_all(&frame)?;
+        self.last = db;
+        self.since_checkpoint += 1;
+        Ok(())
+    }
+}
+
+struct StatsLog {
+    path: PathBuf,
+    buf: BytesMut,
+}
+
+impl StatsLog {
+    /// stats are written next to the save file, one file per session, as a
+    /// series of independent zstd frames containing json lines. Each flush
+    /// writes a complete frame, so a crash loses at most the unflushed tail.
+    fn new(save_path: &Path) -> Result<Self> {
+        let name = save_path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .ok_or_else(|| anyhow!("save file with no name"))?;
+        let mut path = PathBuf::from(save_path);
+        path.set_file_name(format_compact!(
+            "{name}_STATS{}.jsonl.zst",
+            Utc::now().timestamp()
+        ));
+        Ok(Self {
+            path,
+            buf: BytesMut::new(),
+        })
+    }
+
+    fn push(&mut self, stat: &Stat) -> Result<()> {
+        serde_json::to_writer((&mut self.buf).writer(), stat)?;
+        self.buf.put_u8(b'\n');
+        if self.buf.len() >= 65536 {
+            self.flush()?
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        use std::{fs::File, io::Write};
+        if !self.buf.is_empty() {
+            let frame = zstd::stream::encode_all(&self.buf[..], 9)?;
+            self.buf.clear();
+            let mut file = File::options()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+            file.write_all(&frame)?;
+        }
+        Ok(())
+    }
 }
 
 impl io::Write for LogHandle {
@@ -175,11 +277,28 @@ impl io::Write for LogHandle {
 
 #[derive(Debug)]
 pub(super) enum Task {
-    SaveState(PathBuf, Persisted),
+    /// save the state, writing a full checkpoint (and rotating a
+    /// backup) if the given number of saves have been journaled since
+    /// the last one
+    SaveState(PathBuf, Persisted, u32),
     ResetState(PathBuf),
+    /// forget the last saved state so the next save writes a full
+    /// checkpoint. Needed whenever the save file is replaced behind our
+    /// back, otherwise the next delta would be against the wrong base.
+    ResetJournal,
     SaveConfig(PathBuf, Arc<Cfg>),
     WriteLog(Bytes),
     LogPerf(Perf),
+    OpenStats(PathBuf),
+    Stat(Stat),
+    Audit(PathBuf, audit::Entry),
+    Ledger(PathBuf, Vec<ledger::Entry>),
+    /// archive the final state of a round that was won
+    ArchiveRound(PathBuf, Persisted),
+    SaveSchedule(PathBuf, schedule::LastRun),
+    /// (re)start the admin socket with the given config, or stop it if
+    /// None
+    AdminSocket(Option<(Arc<Cfg>, UnboundedSender<adminsock::Request>)>),
     Sync(Arc<(Mutex<bool>, Condvar)>),
 }
 
@@ -204,33 +323,126 @@ async fn background_loop(write_dir: PathBuf, mut rx: UnboundedReceiver<Task>) {
         .open(log_path)
         .await
         .unwrap();
+    let mut journal: Option<Journal> = None;
+    let mut stats: Option<StatsLog> = None;
+    let mut admin_socket: Option<JoinHandle<()>> = None;
+    macro_rules! flush_stats {
+        () => {
+            if let Some(stats) = stats.as_mut() {
+                if let Err(e) = stats.flush() {
+                    error!("failed to write stats to {:?} {e:?}", stats.path)
+                }
+            }
+        };
+    }
     while let Some(msg) = rx.recv().await {
         match msg {
-            Task::SaveState(path, db) => {
-                let encoded = match encode(&db) {
-                    Ok(encoded) => encoded,
-                    Err(e) => {
-                        error!("failed to encode save state {e:?}");
-                        continue
-                    }
+            Task::SaveState(path, db, interval) => {
+                // we keep the last saved state so the next save only needs to
+                // write what changed. It shares structure with the live db, so
+                // holding it is cheap.
+                let checkpoint = match &journal {
+                    None => true,
+                    Some(j) => j.path != path || j.since_checkpoint >= interval as usize,
                 };
-                drop(db); // don't hold the db reference any longer than necessary
-                match save(&path, encoded) {
-                    Ok(()) => (),
-                    Err(e) => error!("failed to save state to {path:?}, {e:?}"),
+                if checkpoint {
+                    let encoded = match encode(&db) {
+                        Ok(encoded) => encoded,
+                        Err(e) => {
+                            error!("failed to encode save state {e:?}");
+                            continue
+                        }
+                    };
+                    match save(&path, encoded) {
+                        Ok(()) => {
+                            let mut index = backup::Index::load(&path);
+                            index.set_current(backup::Summary::new(&db));
+                            if let Err(e) = index.save(&path) {
+                                error!("failed to save the backup index for {path:?}, {e:?}")
+                            }
+                            journal = Some(Journal {
+                                path,
+                                last: db,
+                                since_checkpoint: 0,
+                            })
+                        }
+                        Err(e) => {
+                            error!("failed to save state to {path:?}, {e:?}");
+                            journal = None
+                        }
+                    }
+                } else if let Some(j) = journal.as_mut() {
+                    if let Err(e) = j.append(db) {
+                        error!("failed to append to the journal for {path:?}, {e:?}");
+                        // force a full checkpoint on the next save
+                        journal = None
+                    }
                 }
+                flush_stats!()
             },
-            Task::ResetState(path) => match fs::remove_file(&path) {
-                Ok(()) => (),
-                Err(e) => error!("failed to reset state {path:?}, {e:?}"),
+            Task::ResetState(path) => {
+                journal = None;
+                if let Err(e) = fs::remove_file(&path) {
+                    error!("failed to reset state {path:?}, {e:?}")
+                }
+                if let Err(e) = remove_journal(&path) {
+                    error!("failed to remove the journal for {path:?}, {e:?}")
+                }
             },
+            Task::ResetJournal => journal = None,
             Task::SaveConfig(path, cfg) => match cfg.save(&path) {
                 Ok(()) => (),
                 Err(e) => error!("failed to save config {e:?}"),
             },
             Task::WriteLog(mut buf) => log_file.write_all_buf(&mut buf).await.unwrap(),
             Task::LogPerf(perf) => perf.log(),
+            Task::OpenStats(path) => {
+                flush_stats!();
+                stats = match StatsLog::new(&path) {
+                    Ok(stats) => Some(stats),
+                    Err(e) => {
+                        error!("could not open stats log for {path:?} {e:?}");
+                        None
+                    }
+                }
+            }
+            Task::Stat(st) => {
+                if let Some(stats) = stats.as_mut() {
+                    if let Err(e) = stats.push(&st) {
+                        error!("failed to log stat {st:?} {e:?}")
+                    }
+                }
+            }
+            Task::Audit(path, entry) => {
+                if let Err(e) = audit::append(&path, &entry) {
+                    error!("failed to write audit entry {entry:?} {e:?}")
+                }
+            }
+            Task::Ledger(path, entries) => {
+                if let Err(e) = ledger::append(&path, &entries) {
+                    error!("failed to write {} ledger entries {e:?}", entries.len())
+                }
+            }
+            Task::ArchiveRound(path, db) => match round::archive(&path, &db) {
+                Ok(archive) => info!("archived the final state of the round to {archive:?}"),
+                Err(e) => error!("failed to archive the round {e:?}"),
+            },
+            Task::SaveSchedule(path, last_run) => {
+                if let Err(e) = schedule::save(&path, &last_run) {
+                    error!("failed to save the schedule state {e:?}")
+                }
+            }
+            Task::AdminSocket(listen) => {
+                if let Some(listener) = admin_socket.take() {
+                    listener.abort();
+                    // wait for the old listener to close so the address
+                    // can be bound again
+                    let _ = listener.await;
+                }
+                admin_socket = listen.map(|(cfg, tx)| tokio::spawn(adminsock::listen(cfg, tx)));
+            }
             Task::Sync(a) => {
+                flush_stats!();
                 let &(ref lock, ref cvar) = &*a;
                 let mut synced = lock.lock();
                 *synced = true;
@@ -271,3 +483,109 @@ pub(super) fn init(write_dir: PathBuf) -> UnboundedSender<Task> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::{group::GroupId, objective::ObjectiveId};
+
+    fn save_path(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("bflib-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("state")
+    }
+
+    fn same(a: &Persisted, b: &Persisted) -> bool {
+        serde_json::to_value(a.versioned()).unwrap() == serde_json::to_value(b.versioned()).unwrap()
+    }
+
+    /// write a checkpoint of an empty state followed by n journaled
+    /// saves, returning the live state after the last one
+    fn journaled(path: &Path, n: usize) -> Persisted {
+        let mut db = Persisted::default();
+        save(path, encode(&db).unwrap()).unwrap();
+        let mut journal = Journal {
+            path: PathBuf::from(path),
+            last: db.clone(),
+            since_checkpoint: 0,
+        };
+        let mut crates = vec![];
+        for i in 0..n {
+            let gid = GroupId::new();
+            db.groups_by_name
+                .insert_cow(format_compact!("group{i}").into(), gid);
+            db.crates.insert_cow(gid);
+            crates.push(gid);
+            if i % 3 == 0 {
+                let gid = crates.remove(0);
+                db.crates.remove_cow(&gid);
+            }
+            db.farps.insert_cow(ObjectiveId::new());
+            db.nukes_used += 1;
+            journal.append(db.clone()).unwrap();
+        }
+        db
+    }
+
+    #[test]
+    fn journal_round_trip() {
+        let path = save_path("journal_round_trip");
+        let db = journaled(&path, 20);
+        let loaded = Persisted::load(&path).unwrap();
+        assert!(same(&db, &loaded));
+        assert_eq!(loaded.nukes_used, 20);
+    }
+
+    #[test]
+    fn journal_truncated_tail() {
+        use std::io::Write;
+        let path = save_path("journal_truncated_tail");
+        let db = journaled(&path, 5);
+        // a crash in the middle of an append leaves part of a frame
+        let mut next = db.clone();
+        next.nukes_used += 1;
+        let mut enc = zstd::stream::Encoder::new(Vec::new(), 9).unwrap();
+        serde_json::to_writer(&mut enc, &PersistedDelta::new(&db, &next)).unwrap();
+        let frame = enc.finish().unwrap();
+        fs::File::options()
+            .append(true)
+            .open(Persisted::journal_path(&path))
+            .unwrap()
+            .write_all(&frame[..frame.len() / 2])
+            .unwrap();
+        let loaded = Persisted::load(&path).unwrap();
+        assert!(same(&db, &loaded));
+    }
+
+    #[test]
+    fn delta_holds_only_changes() {
+        let mut base = Persisted::default();
+        for i in 0..2000 {
+            base.groups_by_name
+                .insert_cow(format_compact!("group{i}").into(), GroupId::new());
+        }
+        let mut cur = base.clone();
+        let gid = GroupId::new();
+        cur.groups_by_name
+            .insert_cow(format_compact!("group1000").into(), gid);
+        let delta = serde_json::to_value(PersistedDelta::new(&base, &cur)).unwrap();
+        let upsert = delta["groups_by_name"]["upsert"].as_array().unwrap();
+        assert_eq!(upsert.len(), 1);
+        assert_eq!(upsert[0][0], "group1000");
+        assert_eq!(upsert[0][1], serde_json::to_value(gid).unwrap());
+        assert_eq!(delta["groups_by_name"]["remove"], serde_json::json!([]));
+        let unchanged = serde_json::to_value(PersistedDelta::new(&base, &base)).unwrap();
+        assert_eq!(unchanged["groups_by_name"]["upsert"], serde_json::json!([]));
+    }
+
+    #[test]
+    fn checkpoint_removes_journal() {
+        let path = save_path("checkpoint_removes_journal");
+        let db = journaled(&path, 5);
+        save(&path, encode(&db).unwrap()).unwrap();
+        assert!(!Persisted::journal_path(&path).exists());
+        let loaded = Persisted::load(&path).unwrap();
+        assert!(same(&db, &loaded));
+    }
+}
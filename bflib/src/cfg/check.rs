@@ -0,0 +1,496 @@
+/*
+Copyright 2024 Eric Stokes.
+
+This file is part of bflib.
+
+bflib is free software: you can redistribute it and/or modify it under
+the terms of the GNU Affero Public License as published by the Free
+Software Foundation, either version 3 of the License, or (at your
+option) any later version.
+
+bflib is distributed in the hope that it will be useful, but WITHOUT
+ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero Public License
+for more details.
+*/
+
+//! Cross reference a config with the mission it will run on, so that
+//! mistakes are found before the mission is started instead of when
+//! the state is loaded.
+
+use super::{
+    ActionKind, AiPlaneCfg, AwacsCfg, BomberCfg, Cfg, DeployableCfg, DeployableLogistics, DroneCfg,
+    ScheduleWhen, Vehicle, VictoryCondition,
+};
+use crate::admin::AdminCommand;
+use anyhow::Result;
+use chrono::prelude::*;
+use compact_str::{format_compact, CompactString};
+use dcso3::{
+    coalition::Side,
+    env::miz::{Miz, Skill},
+    String,
+};
+use fxhash::{FxHashMap, FxHashSet};
+
+/// The problems found in a config
+#[derive(Debug, Default)]
+pub struct Report {
+    /// problems that will stop the mission from loading, or break it
+    /// while it is running
+    pub errors: Vec<CompactString>,
+    /// things that are probably mistakes, but won't break anything
+    pub warnings: Vec<CompactString>,
+}
+
+impl Report {
+    fn error(&mut self, e: CompactString) {
+        self.errors.push(e)
+    }
+
+    fn warn(&mut self, w: CompactString) {
+        self.warnings.push(w)
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// The parts of the mission the config refers to
+#[derive(Debug, Default)]
+struct MizInfo {
+    groups: FxHashMap<Side, FxHashSet<String>>,
+    slots: FxHashMap<Side, FxHashSet<Vehicle>>,
+    unit_types: FxHashSet<Vehicle>,
+    objectives: FxHashSet<String>,
+}
+
+impl MizInfo {
+    fn new(miz: &Miz) -> Result<Self> {
+        let mut t = Self::default();
+        for side in Side::ALL {
+            let coa = miz.coalition(side)?;
+            for country in coa.countries()? {
+                let country = country?;
+                let air = country.planes()?.into_iter().chain(country.helicopters()?);
+                let ground = country
+                    .vehicles()?
+                    .into_iter()
+                    .chain(country.ships()?)
+                    .chain(country.statics()?);
+                for (group, air) in air.map(|g| (g, true)).chain(ground.map(|g| (g, false))) {
+                    let group = group?;
+                    t.groups.entry(side).or_default().insert(group.name()?);
+                    for unit in group.units()? {
+                        let unit = unit?;
+                        let typ = Vehicle::from(unit.typ()?);
+                        if air && unit.skill()? == Skill::Client {
+                            t.slots.entry(side).or_default().insert(typ.clone());
+                        }
+                        t.unit_types.insert(typ);
+                    }
+                }
+            }
+        }
+        for zone in miz.triggers()? {
+            let name = zone?.name()?;
+            if name.starts_with('O') && name.len() > 4 {
+                t.objectives.insert(String::from(&name[4..]));
+            }
+        }
+        Ok(t)
+    }
+
+    fn has_group(&self, side: Side, name: &str) -> bool {
+        self.groups
+            .get(&side)
+            .map(|g| g.contains(name))
+            .unwrap_or(false)
+    }
+
+    fn has_slot(&self, typ: &Vehicle) -> bool {
+        self.slots.values().any(|s| s.contains(typ))
+    }
+}
+
+fn plane(kind: &ActionKind) -> Option<&AiPlaneCfg> {
+    match kind {
+        ActionKind::Awacs(AwacsCfg { plane, .. })
+        | ActionKind::Bomber(BomberCfg { plane, .. })
+        | ActionKind::Drone(DroneCfg { plane, .. })
+        | ActionKind::Deployable(DeployableCfg { plane, .. })
+        | ActionKind::Paratrooper(DeployableCfg { plane, .. })
+        | ActionKind::Tanker(plane)
+        | ActionKind::Fighters(plane)
+        | ActionKind::Attackers(plane)
+        | ActionKind::LogisticsRepair(plane)
+        | ActionKind::LogisticsTransfer(plane) => Some(plane),
+        ActionKind::AwacsWaypoint
+        | ActionKind::TankerWaypoint
+        | ActionKind::DroneWaypoint
+        | ActionKind::FighersWaypoint
+        | ActionKind::AttackersWaypoint
+        | ActionKind::Move(_)
+        | ActionKind::Nuke(_) => None,
+    }
+}
+
+/// Check every template, vehicle, and side referenced by cfg against
+/// the mission. Unlike loading the config in the mission this doesn't
+/// stop at the first problem.
+pub fn check(cfg: &Cfg, miz: &Miz) -> Result<Report> {
+    let info = MizInfo::new(miz)?;
+    let mut r = Report::default();
+    let points = cfg.points.is_some();
+    if let Some(tk) = &cfg.team_kill {
+        if !points {
+            r.warn(format_compact!(
+                "team_kill: kills are only scored when the points system is enabled"
+            ))
+        }
+        for (i, step) in tk.steps.iter().enumerate() {
+            if step.team_kills == 0 {
+                r.error(format_compact!(
+                    "team_kill.steps[{i}]: team_kills must be at least 1"
+                ))
+            }
+        }
+    }
+    if let Some(victory) = &cfg.victory {
+        if victory.conditions.is_empty() {
+            r.warn(format_compact!(
+                "victory: there are no conditions, so nobody can win"
+            ))
+        }
+        for (i, cond) in victory.conditions.iter().enumerate() {
+            match cond {
+                VictoryCondition::Objectives(pct) if *pct == 0 || *pct > 100 => r.error(
+                    format_compact!("victory.conditions[{i}]: {pct} must be between 1 and 100"),
+                ),
+                VictoryCondition::Capitals { objectives, .. } if objectives.is_empty() => r.error(
+                    format_compact!("victory.conditions[{i}]: there are no capitals"),
+                ),
+                VictoryCondition::Capitals { objectives, .. } => {
+                    for obj in objectives {
+                        if !info.objectives.contains(obj) {
+                            r.error(format_compact!(
+                                "victory.conditions[{i}]: {obj} is not an objective in the mission"
+                            ))
+                        }
+                    }
+                }
+                VictoryCondition::AllAirbases
+                | VictoryCondition::Objectives(_)
+                | VictoryCondition::NoEnemyLogistics => (),
+            }
+        }
+        let scenarios = cfg
+            .overlays
+            .iter()
+            .filter(|o| victory.scenarios.contains(o))
+            .count();
+        if !victory.scenarios.is_empty() && scenarios != 1 {
+            r.warn(format_compact!(
+                "victory.scenarios: expected exactly one scenario in overlays, found {scenarios}"
+            ))
+        }
+    }
+    if let Some(capture) = &cfg.capture {
+        if capture.min_time > capture.time {
+            r.warn(format_compact!(
+                "capture: min_time {} is longer than time {}, captures will always take min_time",
+                capture.min_time,
+                capture.time
+            ))
+        }
+    }
+    if let Some(front_line) = &cfg.front_line {
+        if front_line.color.iter().any(|c| !(0. ..=1.).contains(c)) {
+            r.error(format_compact!(
+                "front_line.color: {:?} components must be between 0 and 1",
+                front_line.color
+            ))
+        }
+    }
+    for (name, adj) in &cfg.adjacency {
+        for obj in std::iter::once(name).chain(adj.iter()) {
+            if !info.objectives.contains(obj) {
+                r.error(format_compact!(
+                    "adjacency.{name}: {obj} is not an objective in the mission"
+                ))
+            }
+        }
+    }
+    if let Some(share) = cfg.points.and_then(|p| p.assist_share) {
+        if !(0. ..=1.).contains(&share) {
+            r.error(format_compact!(
+                "points.assist_share: {share} must be between 0 and 1"
+            ))
+        }
+    }
+    let mut not_classified = info
+        .unit_types
+        .iter()
+        .filter(|typ| !cfg.unit_classification.contains_key(*typ))
+        .collect::<Vec<_>>();
+    not_classified.sort();
+    for typ in not_classified {
+        r.error(format_compact!(
+            "unit_classification: {typ} is in the mission but not classified"
+        ))
+    }
+    for side in Side::ALL {
+        let configured = cfg.deployables.contains_key(&side)
+            || cfg.troops.contains_key(&side)
+            || cfg.actions.contains_key(&side)
+            || cfg.crate_template.contains_key(&side);
+        if configured && !info.groups.contains_key(&side) {
+            r.warn(format_compact!(
+                "{side} is configured but has no groups in the mission"
+            ))
+        }
+    }
+    for (side, template) in &cfg.crate_template {
+        if !info.has_group(*side, template) {
+            r.error(format_compact!(
+                "crate_template[{side}]: missing group {template}"
+            ))
+        }
+    }
+    let mut global_pad_templates = FxHashSet::default();
+    for (side, deployables) in &cfg.deployables {
+        let side = *side;
+        let mut crates: FxHashSet<String> = FxHashSet::default();
+        let mut crate_names = |r: &mut Report, what: &str, name: &String| {
+            if !crates.insert(name.clone()) {
+                r.error(format_compact!("{what} duplicate crate name {name}"))
+            }
+        };
+        match cfg.repair_crate.get(&side) {
+            None => r.error(format_compact!(
+                "repair_crate: missing repair crate for {side}"
+            )),
+            Some(cr) => crate_names(&mut r, &format_compact!("repair_crate[{side}]:"), &cr.name),
+        }
+        if !cfg.crate_template.contains_key(&side) {
+            r.error(format_compact!(
+                "crate_template: {side} has deployables but no crate template"
+            ))
+        }
+        if let Some(whcfg) = &cfg.warehouse {
+            match whcfg.supply_transfer_crate.get(&side) {
+                None => r.error(format_compact!(
+                    "warehouse.supply_transfer_crate: missing supply transfer crate for {side}"
+                )),
+                Some(cr) => crate_names(
+                    &mut r,
+                    &format_compact!("warehouse.supply_transfer_crate[{side}]:"),
+                    &cr.name,
+                ),
+            }
+        }
+        let mut names = FxHashSet::default();
+        let mut repairs = FxHashSet::default();
+        let mut pad_templates = FxHashSet::default();
+        for dep in deployables {
+            let name = match dep.path.last() {
+                Some(name) => name.clone(),
+                None => {
+                    r.error(format_compact!(
+                        "deployables[{side}]: {:?} has an empty path",
+                        dep.template
+                    ));
+                    continue;
+                }
+            };
+            let what = format_compact!("deployables[{side}] {name}:");
+            if !names.insert(name.clone()) {
+                r.error(format_compact!("{what} duplicate deployable name"))
+            }
+            if !info.has_group(side, &dep.template) {
+                r.error(format_compact!("{what} missing template {}", dep.template))
+            }
+            if !points && dep.cost > 0 {
+                r.error(format_compact!(
+                    "{what} costs points, but the points system is disabled"
+                ))
+            }
+            for cr in &dep.crates {
+                crate_names(&mut r, &what, &cr.name)
+            }
+            if let Some(rep) = &dep.repair_crate {
+                if !repairs.insert(rep.name.clone()) {
+                    r.error(format_compact!(
+                        "{what} repair crate {} is shared",
+                        rep.name
+                    ))
+                }
+                crate_names(&mut r, &what, &rep.name)
+            }
+            if let Some(DeployableLogistics {
+                pad_templates: pads,
+                ammo_template,
+                fuel_template,
+                barracks_template,
+            }) = &dep.logistics
+            {
+                let mut parts = FxHashSet::default();
+                for tmpl in [
+                    &dep.template,
+                    ammo_template,
+                    fuel_template,
+                    barracks_template,
+                ]
+                .into_iter()
+                .chain(pads.iter())
+                {
+                    if !info.has_group(side, tmpl) {
+                        r.error(format_compact!("{what} missing logistics template {tmpl}"))
+                    }
+                    if !parts.insert(tmpl) {
+                        r.error(format_compact!(
+                            "{what} logistics template {tmpl} is reused"
+                        ))
+                    }
+                }
+                for pad in pads {
+                    if !pad_templates.insert(pad.clone()) {
+                        r.error(format_compact!("{what} duplicate pad template {pad}"))
+                    }
+                    if !global_pad_templates.insert(pad.clone()) {
+                        r.error(format_compact!(
+                            "{what} pad template {pad} is used on more than one side"
+                        ))
+                    }
+                }
+            }
+        }
+    }
+    for (side, troops) in &cfg.troops {
+        let mut names = FxHashSet::default();
+        for troop in troops {
+            let what = format_compact!("troops[{side}] {}:", troop.name);
+            if !names.insert(&troop.name) {
+                r.error(format_compact!("{what} duplicate squad name"))
+            }
+            if !info.has_group(*side, &troop.template) {
+                r.error(format_compact!(
+                    "{what} missing template {}",
+                    troop.template
+                ))
+            }
+            if !points && troop.cost > 0 {
+                r.error(format_compact!(
+                    "{what} costs points, but the points system is disabled"
+                ))
+            }
+        }
+    }
+    for (side, actions) in &cfg.actions {
+        for (name, act) in actions {
+            let what = format_compact!("actions[{side}] {name}:");
+            if !points && (act.cost > 0 || act.penalty.unwrap_or(0) > 0) {
+                r.error(format_compact!(
+                    "{what} costs points, but the points system is disabled"
+                ))
+            }
+            if let Some(plane) = plane(&act.kind) {
+                if !info.has_group(*side, &plane.template) {
+                    r.error(format_compact!(
+                        "{what} missing template {}",
+                        plane.template
+                    ))
+                }
+            }
+            match &act.kind {
+                ActionKind::Deployable(DeployableCfg { name, .. }) => {
+                    let found = cfg
+                        .deployables
+                        .get(side)
+                        .map(|d| d.iter().any(|d| d.path.last() == Some(name)))
+                        .unwrap_or(false);
+                    if !found {
+                        r.error(format_compact!("{what} no {side} deployable named {name}"))
+                    }
+                }
+                ActionKind::Paratrooper(DeployableCfg { name, .. }) => {
+                    let found = cfg
+                        .troops
+                        .get(side)
+                        .map(|t| t.iter().any(|t| &t.name == name))
+                        .unwrap_or(false);
+                    if !found {
+                        r.error(format_compact!("{what} no {side} troop named {name}"))
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+    let mut slot_types = info.slots.values().flatten().collect::<Vec<_>>();
+    slot_types.sort();
+    slot_types.dedup();
+    for typ in slot_types {
+        if !cfg.life_types.contains_key(typ) {
+            r.error(format_compact!(
+                "life_types: there are {typ} slots but no life type"
+            ))
+        }
+    }
+    for (typ, lt) in &cfg.life_types {
+        if !info.has_slot(typ) {
+            r.warn(format_compact!(
+                "life_types: there are no {typ} slots in the mission"
+            ))
+        }
+        if !cfg.default_lives.contains_key(lt) {
+            r.error(format_compact!(
+                "default_lives: missing {lt:?}, used by {typ}"
+            ))
+        }
+    }
+    for typ in cfg.cargo.keys() {
+        if !info.has_slot(typ) {
+            r.warn(format_compact!(
+                "cargo: there are no {typ} slots in the mission"
+            ))
+        }
+    }
+    for typ in cfg.airborne_jtacs.keys() {
+        if !info.has_slot(typ) {
+            r.warn(format_compact!(
+                "airborne_jtacs: there are no {typ} slots in the mission"
+            ))
+        }
+    }
+    for (name, s) in &cfg.schedules {
+        let what = format_compact!("schedules {name}:");
+        match s.command.parse::<AdminCommand>() {
+            Err(e) => r.error(format_compact!("{what} invalid command, {e}")),
+            Ok(AdminCommand::Help) => r.error(format_compact!("{what} help can't be scheduled")),
+            Ok(AdminCommand::Spawn { .. } | AdminCommand::Logdesc) => r.error(format_compact!(
+                "{what} {} can only be run in game",
+                s.command
+            )),
+            Ok(_) => (),
+        }
+        if let ScheduleWhen::Once(ts) = s.when {
+            if ts <= Utc::now() {
+                r.warn(format_compact!("{what} {ts} is in the past"))
+            }
+        }
+    }
+    if let Some(sock) = &cfg.admin_socket {
+        for (name, client) in &sock.clients {
+            if let Some(role) = &client.role {
+                if !cfg.admin_roles.contains_key(role) {
+                    r.error(format_compact!(
+                        "admin_socket.clients {name}: unknown role {role}"
+                    ))
+                }
+            }
+        }
+    }
+    Ok(r)
+}
@@ -1121,6 +1121,29 @@ fn default_jtac_priority() -> Vec<UnitTags> {
     .collect()
 }
 
+fn default_admin_roles() -> FxHashMap<String, AdminRole> {
+    use AdminCommandKind::*;
+    FxHashMap::from_iter([
+        (
+            "moderator".into(),
+            AdminRole {
+                commands: FxHashSet::from_iter([
+                    Kick, Ban, Unban, Banned, Deslot, Connected, Search, Audit, Mute, Unmute,
+                    SlowMode, Ledger,
+                ]),
+                members: FxHashMap::default(),
+            },
+        ),
+        (
+            "logistics".into(),
+            AdminRole {
+                commands: FxHashSet::from_iter([LogisticsTickNow, TransferSupply]),
+                members: FxHashMap::default(),
+            },
+        ),
+    ])
+}
+
 fn default_repair_crate() -> FxHashMap<Side, Crate> {
     FxHashMap::from_iter([
         (
@@ -1727,7 +1750,11 @@ impl Default for Cfg {
                 "f279deb7a6b62c96a78eca3ddb2bd8d0".parse().unwrap(),
                 "REAPER 32 | EvilKipper".into(),
             )]),
+            admin_roles: default_admin_roles(),
+            admin_socket: None,
+            schedules: FxHashMap::default(),
             banned: FxHashMap::default(),
+            muted: FxHashMap::default(),
             max_msgs_per_second: 3,
             repair_time: 1800,
             repair_crate: default_repair_crate(),
@@ -1747,6 +1774,46 @@ impl Default for Cfg {
                 logistics_repair: 25,
                 logistics_transfer: 15,
                 capture: 15,
+                last_hit_bonus: 5,
+                assist_share: Some(0.4),
+                assist_window: 300,
+            }),
+            team_kill: Some(TeamKillCfg {
+                window: 3600,
+                steps: vec![
+                    TeamKillStep {
+                        team_kills: 2,
+                        penalty: TeamKillPenalty::Points(50),
+                    },
+                    TeamKillStep {
+                        team_kills: 3,
+                        penalty: TeamKillPenalty::Life,
+                    },
+                    TeamKillStep {
+                        team_kills: 4,
+                        penalty: TeamKillPenalty::SlotBlock(1800),
+                    },
+                    TeamKillStep {
+                        team_kills: 5,
+                        penalty: TeamKillPenalty::Ban(86400),
+                    },
+                ],
+            }),
+            victory: Some(VictoryCfg {
+                conditions: vec![
+                    VictoryCondition::AllAirbases,
+                    VictoryCondition::Objectives(90),
+                    VictoryCondition::NoEnemyLogistics,
+                ],
+                scenarios: vec![],
+            }),
+            capture: Some(CaptureCfg {
+                time: 600,
+                min_time: 120,
+                decay_time: 300,
+            }),
+            front_line: Some(FrontLineCfg {
+                color: [1., 0.5, 0., 0.9],
             }),
             warehouse: Some(WarehouseConfig {
                 hub_max: 25,
@@ -1816,6 +1883,10 @@ impl Default for Cfg {
             ]),
             jtac_priority: default_jtac_priority(),
             extra_fixed_wing_objectives: FxHashSet::default(),
+            adjacency: FxHashMap::default(),
+            repair_indexes_on_load: false,
+            checkpoint_interval: 60,
+            overlays: vec![],
         }
     }
 }
@@ -14,26 +14,33 @@ FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero Public License
 for more details.
 */
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use chrono::prelude::*;
 use compact_str::format_compact;
 use dcso3::{coalition::Side, controller::AltType, net::Ucid, String};
 use enumflags2::{bitflags, BitFlags};
 use fxhash::{FxBuildHasher, FxHashMap, FxHashSet};
 use indexmap::IndexMap;
+#[cfg(feature = "schema")]
+use schemars::JsonSchema;
 use serde_derive::{Deserialize, Serialize};
 use std::{
     borrow::Borrow,
+    cmp::max,
     fmt,
     fs::{self, File},
-    io,
+    iter,
     ops::{Deref, DerefMut},
     path::{Path, PathBuf},
+    time::SystemTime,
 };
 
+pub mod check;
 mod example;
+pub mod overlay;
 
 #[derive(Debug, Clone, Serialize, Deserialize, Hash, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
 pub struct Vehicle(pub String);
 
 impl fmt::Display for Vehicle {
@@ -67,9 +74,12 @@ impl Vehicle {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
 pub enum Rule {
     Whitelist { allowed: FxHashMap<Ucid, String> },
     Blacklist { denied: FxHashMap<Ucid, String> },
+    /// members of these squadrons, by tag, or of any squadron if empty
+    Squadrons { allowed: Vec<String> },
     AlwaysAllowed,
     NeverAllowed,
 }
@@ -81,10 +91,15 @@ impl Default for Rule {
 }
 
 impl Rule {
-    pub fn check(&self, ucid: &Ucid) -> bool {
+    /// squadron is the tag of the player's squadron, if they are in one
+    pub fn check(&self, ucid: &Ucid, squadron: Option<&String>) -> bool {
         match self {
             Self::Whitelist { allowed } => allowed.contains_key(ucid),
             Self::Blacklist { denied } => !denied.contains_key(&ucid),
+            Self::Squadrons { allowed } => match squadron {
+                None => false,
+                Some(tag) => allowed.is_empty() || allowed.contains(tag),
+            },
             Self::AlwaysAllowed => true,
             Self::NeverAllowed => false,
         }
@@ -99,6 +114,7 @@ impl Rule {
             Self::Whitelist { allowed } => {
                 allowed.remove(&ucid);
             }
+            Self::Squadrons { .. } => (),
             Self::AlwaysAllowed => {
                 let denied = FxHashMap::from_iter([(ucid, name)]);
                 *self = Self::Blacklist { denied };
@@ -116,6 +132,7 @@ impl Rule {
             Self::Whitelist { allowed } => {
                 allowed.insert(ucid, name);
             }
+            Self::Squadrons { .. } => (),
             Self::NeverAllowed => {
                 let allowed = FxHashMap::from_iter([(ucid, name)]);
                 *self = Self::Whitelist { allowed };
@@ -125,7 +142,110 @@ impl Rule {
     }
 }
 
+/// The kinds of admin command, used to say which commands a role may
+/// run
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub enum AdminCommandKind {
+    ReduceInventory,
+    TransferSupply,
+    LogisticsTickNow,
+    LogisticsDeliverNow,
+    Repair,
+    Tim,
+    Spawn,
+    SideSwitch,
+    Ban,
+    Unban,
+    Kick,
+    Connected,
+    Banned,
+    Search,
+    LogWarehouse,
+    Logdesc,
+    ResetLives,
+    AddAdmin,
+    RemoveAdmin,
+    Balance,
+    SetPoints,
+    Ledger,
+    Delete,
+    Deslot,
+    Remark,
+    Backups,
+    Restore,
+    Audit,
+    Schedule,
+    Mute,
+    Unmute,
+    SlowMode,
+    Reset,
+    Shutdown,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(deny_unknown_fields)]
+pub struct AdminRole {
+    /// The admin commands members of this role may run
+    pub commands: FxHashSet<AdminCommandKind>,
+    /// The players who have this role
+    #[serde(default)]
+    pub members: FxHashMap<Ucid, String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(deny_unknown_fields)]
+pub struct AdminClient {
+    /// The secret the client must send with every request
+    pub key: String,
+    /// If set the client may only run the commands this role allows,
+    /// otherwise it may run all admin commands
+    #[serde(default)]
+    pub role: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(deny_unknown_fields)]
+pub struct AdminSocketCfg {
+    /// The tcp address to listen on, e.g. 127.0.0.1:7891. Requests and
+    /// keys are not encrypted, so this should not be reachable from the
+    /// internet.
+    pub listen: String,
+    /// The clients that may connect, by name
+    pub clients: FxHashMap<String, AdminClient>,
+}
+
+/// When a scheduled command runs. All times are UTC.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub enum ScheduleWhen {
+    /// Every this many seconds
+    Every(u32),
+    /// Every day at this time, e.g. "18:00:00"
+    Daily(NaiveTime),
+    /// Every week on this day at this time, e.g. ["Sun", "20:00:00"]
+    Weekly(Weekday, NaiveTime),
+    /// Once, at this time
+    Once(DateTime<Utc>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(deny_unknown_fields)]
+pub struct ScheduledCommand {
+    pub when: ScheduleWhen,
+    /// The admin command to run, with the same syntax as -admin in chat
+    pub command: String,
+    /// Shown to all players when the command runs
+    #[serde(default)]
+    pub announce: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
 #[bitflags]
 #[repr(u64)]
 pub enum UnitTag {
@@ -205,6 +325,17 @@ impl From<Vec<UnitTag>> for UnitTags {
     }
 }
 
+#[cfg(feature = "schema")]
+impl JsonSchema for UnitTags {
+    fn schema_name() -> std::string::String {
+        "UnitTags".into()
+    }
+
+    fn json_schema(gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        gen.subschema_for::<Vec<UnitTag>>()
+    }
+}
+
 impl From<BitFlags<UnitTag>> for UnitTags {
     fn from(value: BitFlags<UnitTag>) -> Self {
         Self(value)
@@ -218,6 +349,7 @@ impl Into<Vec<UnitTag>> for UnitTags {
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Hash)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
 pub enum LifeType {
     Standard,
     Intercept,
@@ -240,6 +372,7 @@ impl fmt::Display for LifeType {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
 pub enum PersistTyp {
     /// The deployable persists until it is destroyed
     Forever,
@@ -254,6 +387,7 @@ pub enum PersistTyp {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
 pub enum LimitEnforceTyp {
     /// Handle the limit by removing the oldest instance of the deployable when
     /// a new one is unpacked. (lifo)
@@ -264,6 +398,7 @@ pub enum LimitEnforceTyp {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
 pub struct Crate {
     /// The name of the crate in the menu
     pub name: String,
@@ -283,6 +418,7 @@ pub struct Crate {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
 #[serde(deny_unknown_fields)]
 pub struct DeployableLogistics {
     pub pad_templates: Vec<String>,
@@ -292,6 +428,7 @@ pub struct DeployableLogistics {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
 #[serde(deny_unknown_fields)]
 pub struct DeployableEwr {
     /// range for likely detection (Meters)
@@ -300,6 +437,7 @@ pub struct DeployableEwr {
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
 #[serde(deny_unknown_fields)]
 pub struct DeployableJtac {
     /// jtac detection and lasing range (Meters)
@@ -311,6 +449,7 @@ pub struct DeployableJtac {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
 #[serde(deny_unknown_fields)]
 pub struct Deployable {
     /// The full menu path of the deployable in the menu
@@ -340,6 +479,7 @@ pub struct Deployable {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
 #[serde(deny_unknown_fields)]
 pub struct Troop {
     /// The name of the squad in the menu
@@ -365,6 +505,7 @@ pub struct Troop {
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
 #[serde(deny_unknown_fields)]
 pub struct CargoConfig {
     /// How many troop slots does this vehicle have
@@ -378,6 +519,7 @@ pub struct CargoConfig {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
 #[serde(deny_unknown_fields)]
 pub struct WarehouseConfig {
     /// Logistics hub max supply stock as a multiple of the delivery amount
@@ -409,6 +551,7 @@ impl WarehouseConfig {
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
 #[serde(deny_unknown_fields)]
 pub struct PointsCfg {
     pub new_player_join: u32,
@@ -418,15 +561,129 @@ pub struct PointsCfg {
     pub logistics_repair: u32,
     pub logistics_transfer: u32,
     pub capture: u32,
+    /// extra points for the player who landed the last hit before a
+    /// kill
+    #[serde(default)]
+    pub last_hit_bonus: u32,
+    /// The fraction (0 - 1) of a kill's points that is shared among the
+    /// players who hit the victim before the last hit, in proportion to
+    /// how many times they hit it. The rest goes to the last hitter. If
+    /// not set the points are split evenly among everyone who hit.
+    #[serde(default)]
+    pub assist_share: Option<f32>,
+    /// Hits landed more than this many seconds before the kill don't
+    /// earn an assist
+    #[serde(default = "default_assist_window")]
+    pub assist_window: u32,
+}
+
+fn default_assist_window() -> u32 {
+    300
+}
+
+/// A penalty on the team kill ladder
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub enum TeamKillPenalty {
+    /// deduct this many points, on top of the points lost for the kill
+    Points(u32),
+    /// take a life of the type the shooter is flying
+    Life,
+    /// the shooter is moved to spectators and can't take a slot for
+    /// this many seconds
+    SlotBlock(u32),
+    /// ban the shooter for this many seconds
+    Ban(u32),
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(deny_unknown_fields)]
+pub struct TeamKillStep {
+    /// the step applies once, to the team kill that brings the number
+    /// of unforgiven team kills within the window to this many
+    pub team_kills: u32,
+    pub penalty: TeamKillPenalty,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(deny_unknown_fields)]
+pub struct TeamKillCfg {
+    /// How long, in seconds, a team kill counts toward the steps. The
+    /// victim can forgive a team kill with -forgive until it expires.
+    pub window: u32,
+    /// The penalties for repeat team killers. Each step applies when
+    /// the count reaches it, so penalties accumulate as the count climbs.
+    /// Forgiving a team kill returns the points it cost and lifts the
+    /// slot block and ban it caused. Team kills are tracked even if
+    /// points are not enabled.
+    pub steps: Vec<TeamKillStep>,
+}
+
+/// A way for a side to win the round
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub enum VictoryCondition {
+    /// the side holds every airbase
+    AllAirbases,
+    /// the side holds at least this percentage of the objectives, not
+    /// counting farps
+    Objectives(u8),
+    /// the side has held all of the named objectives for this many hours
+    Capitals { objectives: Vec<String>, hours: u32 },
+    /// the enemy holds no logistics hubs
+    NoEnemyLogistics,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(deny_unknown_fields)]
+pub struct VictoryCfg {
+    /// The round is won by the first side to meet any of these
+    pub conditions: Vec<VictoryCondition>,
+    /// Overlays to rotate through, one round each. When a round is won
+    /// the scenario in `overlays` is replaced by the next one in this
+    /// list for the next round. If empty the next round uses the same
+    /// config.
+    #[serde(default)]
+    pub scenarios: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(deny_unknown_fields)]
+pub struct CaptureCfg {
+    /// How long, in seconds, it takes one capture capable troop to
+    /// capture an objective. The time is divided by the number of troops
+    /// present.
+    pub time: u32,
+    /// No matter how many troops are present a capture takes at least
+    /// this many seconds
+    pub min_time: u32,
+    /// How long, in seconds, it takes full capture progress to decay
+    /// away once the attackers have left
+    pub decay_time: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(deny_unknown_fields)]
+pub struct FrontLineCfg {
+    /// The color of the line as red, green, blue, and alpha, each from
+    /// 0 to 1
+    pub color: [f32; 4],
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
 pub enum AiPlaneKind {
     FixedWing,
     Helicopter,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
 pub struct AiPlaneCfg {
     pub kind: AiPlaneKind,
     pub duration: Option<u8>,
@@ -439,12 +696,14 @@ pub struct AiPlaneCfg {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
 pub struct AwacsCfg {
     pub ewr: DeployableEwr,
     pub plane: AiPlaneCfg,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
 pub struct BomberCfg {
     pub targets: u32,
     pub power: u32,
@@ -454,18 +713,21 @@ pub struct BomberCfg {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
 pub struct DeployableCfg {
     pub name: String,
     pub plane: AiPlaneCfg,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
 pub struct DroneCfg {
     pub jtac: DeployableJtac,
     pub plane: AiPlaneCfg,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
 pub struct NukeCfg {
     /// using a nuke reduces the cost of nukes for everyone by this factor. e.g. cost_scale: 4, with initial cost 1000.
     /// The first nuke would cost 1000 points. The next nuke would cost 250 points. The next nuke would cost 62 points.
@@ -476,6 +738,7 @@ pub struct NukeCfg {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
 pub struct MoveCfg {
     /// max distance for troop moves in meters
     pub troop: u32,
@@ -484,6 +747,7 @@ pub struct MoveCfg {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
 pub enum ActionKind {
     Tanker(AiPlaneCfg),
     Awacs(AwacsCfg),
@@ -505,6 +769,7 @@ pub enum ActionKind {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
 pub struct Action {
     pub kind: ActionKind,
     pub cost: u32,
@@ -513,6 +778,7 @@ pub struct Action {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
 #[serde(deny_unknown_fields)]
 pub struct Rules {
     /// who can use actions
@@ -531,19 +797,38 @@ fn default_msgs_per_second() -> usize {
     5
 }
 
+fn default_checkpoint_interval() -> u32 {
+    60
+}
+
 fn default_cull_after() -> u32 {
     1800
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
 #[serde(deny_unknown_fields)]
 pub struct Cfg {
-    /// ucids in this list are able to run admin commands
+    /// ucids in this list are able to run all admin commands
     #[serde(default)]
     pub admins: FxHashMap<Ucid, String>,
+    /// Named roles that may run only some admin commands, e.g. moderators
+    /// who can kick and ban.
+    #[serde(default)]
+    pub admin_roles: FxHashMap<String, AdminRole>,
+    /// Accept admin commands from tools running outside the game
+    #[serde(default)]
+    pub admin_socket: Option<AdminSocketCfg>,
+    /// Admin commands to run at set times, by name. e.g. a logistics
+    /// delivery every 6 hours, or a reset at the end of a campaign week.
+    #[serde(default)]
+    pub schedules: FxHashMap<String, ScheduledCommand>,
     /// ucids in this list are banned
     #[serde(default)]
     pub banned: FxHashMap<Ucid, (Option<DateTime<Utc>>, String)>,
+    /// ucids in this list can't chat
+    #[serde(default)]
+    pub muted: FxHashMap<Ucid, (Option<DateTime<Utc>>, String)>,
     /// who can do what
     #[serde(default)]
     pub rules: Rules,
@@ -558,6 +843,23 @@ pub struct Cfg {
     /// how many points are various actions worth (if any)
     #[serde(default)]
     pub points: Option<PointsCfg>,
+    /// escalating penalties for players who keep killing friendlies
+    #[serde(default)]
+    pub team_kill: Option<TeamKillCfg>,
+    /// when a side has won the round. When a side wins the state is
+    /// archived and the next round starts at the next restart.
+    #[serde(default)]
+    pub victory: Option<VictoryCfg>,
+    /// how long troops must hold an objective to capture it. If not
+    /// specified objectives are captured as soon as troops are in range
+    /// and uncontested.
+    #[serde(default)]
+    pub capture: Option<CaptureCfg>,
+    /// draw the boundary between red and blue territory on the F10
+    /// map. Territory is divided by which objective is closest, farps
+    /// are not counted.
+    #[serde(default)]
+    pub front_line: Option<FrontLineCfg>,
     /// how often a base will repair if it has full logistics (Seconds)
     pub repair_time: u32,
     /// The base repair crate
@@ -633,9 +935,79 @@ pub struct Cfg {
     /// a port.
     #[serde(default)]
     pub extra_fixed_wing_objectives: FxHashSet<String>,
+    /// Which objectives border each other, by name. If there are any
+    /// links an objective can only be captured by a side that holds one
+    /// of its neighbors. Links go both ways, and are combined with the
+    /// ones declared in the mission by giving an objective's trigger
+    /// zone an `adjacent` property holding a comma separated list of
+    /// objective names. Objectives with no links, such as farps, can be
+    /// captured from anywhere.
+    #[serde(default)]
+    pub adjacency: FxHashMap<String, Vec<String>>,
+    /// If the integrity check run when the state is loaded finds
+    /// problems, rebuild the derived indexes from the primary data.
+    /// Otherwise the problems are only logged.
+    #[serde(default)]
+    pub repair_indexes_on_load: bool,
+    /// Write a full checkpoint of the state every this many saves, in
+    /// between only the changes are appended to a journal. A backup is
+    /// rotated with each checkpoint, so lower values keep finer grained
+    /// backups at the cost of more disk writes. 1 checkpoints, and
+    /// backs up, every save.
+    #[serde(default = "default_checkpoint_interval")]
+    pub checkpoint_interval: u32,
+    /// Files merged on top of this one, in order, to produce the
+    /// effective config. Relative paths are relative to the directory
+    /// containing this file. See `overlay` for the merge rules.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub overlays: Vec<String>,
 }
 
 impl Cfg {
+    /// Are shots and kills tracked. They are needed to award points and
+    /// to count team kills.
+    pub fn tracks_shots(&self) -> bool {
+        self.points.is_some() || self.team_kill.is_some()
+    }
+
+    /// Can ucid run at least one admin command
+    pub fn is_admin(&self, ucid: &Ucid) -> bool {
+        self.admins.contains_key(ucid)
+            || self
+                .admin_roles
+                .values()
+                .any(|r| r.members.contains_key(ucid))
+    }
+
+    /// Can ucid run admin commands of kind
+    pub fn admin_permits(&self, ucid: &Ucid, kind: AdminCommandKind) -> bool {
+        self.admins.contains_key(ucid)
+            || self
+                .admin_roles
+                .values()
+                .any(|r| r.members.contains_key(ucid) && r.commands.contains(&kind))
+    }
+
+    /// Can a client with role run admin commands of kind. A client
+    /// without a role can run everything.
+    pub fn role_permits(&self, role: Option<&str>, kind: AdminCommandKind) -> bool {
+        match role {
+            None => true,
+            Some(role) => self
+                .admin_roles
+                .get(role)
+                .map(|r| r.commands.contains(&kind))
+                .unwrap_or(false),
+        }
+    }
+
+    /// The json schema of the config file. Overlays are partial configs,
+    /// so they won't validate against it.
+    #[cfg(feature = "schema")]
+    pub fn schema() -> schemars::schema::RootSchema {
+        schemars::schema_for!(Cfg)
+    }
+
     fn path(miz_state_path: &Path) -> PathBuf {
         let mut path = PathBuf::from(miz_state_path);
         let file_name = path
@@ -652,29 +1024,131 @@ impl Cfg {
 
     pub fn load(miz_state_path: &Path) -> Result<Self> {
         let path = Self::path(miz_state_path);
-        let file = loop {
-            match File::open(&path) {
-                Ok(f) => break f,
-                Err(e) => match e.kind() {
-                    io::ErrorKind::NotFound => {
-                        let file = File::create(&path)
-                            .map_err(|e| anyhow!("could not create default config {}", e))?;
-                        serde_json::to_writer_pretty(file, &Cfg::default())
-                            .map_err(|e| anyhow!("could not write default config {}", e))?;
-                    }
-                    e => {
-                        return Err(anyhow!("error opening config file {:?}", e));
-                    }
-                },
-            }
-        };
-        let cfg: Self = serde_json::from_reader(file)
+        if !path.exists() {
+            let file = File::create(&path)
+                .map_err(|e| anyhow!("could not create default config {}", e))?;
+            serde_json::to_writer_pretty(file, &Cfg::default())
+                .map_err(|e| anyhow!("could not write default config {}", e))?;
+        }
+        Self::load_file(&path)
+    }
+
+    /// Load the config file at path, merging in its overlays
+    pub fn load_file(path: &Path) -> Result<Self> {
+        let v = overlay::load_merged(path)?;
+        let cfg: Self = serde_json::from_value(v)
             .map_err(|e| anyhow!("failed to decode cfg file {:?}, {:?}", path, e))?;
         Ok(cfg)
     }
 
+    /// Replace the scenario overlay in the base config with next. Any
+    /// of scenarios in the overlay list are removed, and next is added
+    /// at the end.
+    pub fn set_scenario(miz_state_path: &Path, scenarios: &[String], next: &str) -> Result<()> {
+        use serde_json::Value;
+        let base = Self::path(miz_state_path);
+        let mut raw: Value =
+            serde_json::from_slice(&fs::read(&base).context("reading the base cfg")?)
+                .context("decoding the base cfg")?;
+        let raw_obj = raw
+            .as_object_mut()
+            .ok_or_else(|| anyhow!("cfg is not an object"))?;
+        let mut overlays = match raw_obj.remove("overlays") {
+            None | Some(Value::Null) => vec![],
+            Some(Value::Array(a)) => a,
+            Some(_) => bail!("overlays must be a list of file names"),
+        };
+        overlays.retain(|o| match o.as_str() {
+            None => true,
+            Some(o) => !scenarios.iter().any(|s| s.as_str() == o),
+        });
+        overlays.push(Value::from(next));
+        raw_obj.insert("overlays".into(), Value::Array(overlays));
+        let mut path = base.clone();
+        path.set_extension("bak");
+        let fd = File::options()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .with_context(|| format_compact!("opening {:?}", path))?;
+        serde_json::to_writer_pretty(fd, &raw).context("serializing cfg")?;
+        fs::rename(&path, &base).context("moving new file into place")?;
+        Ok(())
+    }
+
+    /// The last modification time of the config file or any of its
+    /// overlays
+    pub fn mtime(&self, miz_state_path: &Path) -> Result<SystemTime> {
+        let path = Self::path(miz_state_path);
+        let dir = path.parent().unwrap_or(Path::new("."));
+        let mut latest = SystemTime::UNIX_EPOCH;
+        let overlays = self.overlays.iter().map(|o| dir.join(o.as_str()));
+        for path in iter::once(path.clone()).chain(overlays) {
+            let md = fs::metadata(&path).with_context(|| format_compact!("stat {:?}", path))?;
+            latest = max(latest, md.modified()?);
+        }
+        Ok(latest)
+    }
+
+    /// The names of the top level sections that differ between self and
+    /// other
+    pub fn changed_sections(&self, other: &Cfg) -> Result<Vec<String>> {
+        use serde_json::Value;
+        let (old, new) = match (serde_json::to_value(self)?, serde_json::to_value(other)?) {
+            (Value::Object(old), Value::Object(new)) => (old, new),
+            _ => bail!("cfg did not serialize to an object"),
+        };
+        let mut changed = vec![];
+        for (k, v) in &new {
+            if old.get(k) != Some(v) {
+                changed.push(String::from(k.as_str()))
+            }
+        }
+        for k in old.keys() {
+            if !new.contains_key(k) {
+                changed.push(String::from(k.as_str()))
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Save the config. If it has overlays only the changes from the
+    /// merged config on disk are written to the base file, what the
+    /// overlays contribute is kept out of it, and the overlays are not
+    /// touched. Removing something an overlay added is an error.
     pub fn save(&self, miz_state_path: &Path) -> Result<()> {
-        let mut path = Self::path(miz_state_path);
+        let base = Self::path(miz_state_path);
+        let v = if self.overlays.is_empty() {
+            serde_json::to_value(self).context("serializing cfg")?
+        } else {
+            let mut raw: serde_json::Value =
+                serde_json::from_slice(&fs::read(&base).context("reading the base cfg")?)
+                    .context("decoding the base cfg")?;
+            let merged = overlay::load_merged(&base)?;
+            let new = serde_json::to_value(self).context("serializing cfg")?;
+            match (raw.as_object_mut(), new) {
+                (Some(raw), serde_json::Value::Object(new)) => {
+                    for (k, v) in new {
+                        match merged.get(&k) {
+                            Some(m) if m == &v => (),
+                            Some(m) => overlay::unmerge(
+                                &k.clone(),
+                                raw.entry(k).or_insert(serde_json::Value::Null),
+                                m,
+                                v,
+                            )?,
+                            None => {
+                                raw.insert(k, v);
+                            }
+                        }
+                    }
+                }
+                _ => bail!("cfg is not an object"),
+            }
+            raw
+        };
+        let mut path = base.clone();
         path.set_extension("bak");
         let fd = File::options()
             .write(true)
@@ -682,8 +1156,8 @@ impl Cfg {
             .truncate(true)
             .open(&path)
             .with_context(|| format_compact!("opening {:?}", path))?;
-        serde_json::to_writer_pretty(fd, self).context("serializing cfg")?;
-        fs::rename(&path, Self::path(miz_state_path)).context("moving new file into place")?;
+        serde_json::to_writer_pretty(fd, &v).context("serializing cfg")?;
+        fs::rename(&path, &base).context("moving new file into place")?;
         Ok(())
     }
 }
@@ -0,0 +1,229 @@
+/*
+Copyright 2024 Eric Stokes.
+
+This file is part of bflib.
+
+bflib is free software: you can redistribute it and/or modify it under
+the terms of the GNU Affero Public License as published by the Free
+Software Foundation, either version 3 of the License, or (at your
+option) any later version.
+
+bflib is distributed in the hope that it will be useful, but WITHOUT
+ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero Public License
+for more details.
+*/
+
+//! A config file may list overlay files in its `overlays` field. Each
+//! overlay is a partial config that is merged, in order, on top of the
+//! base. Merging is done on the json before it is decoded,
+//!
+//! - objects (maps and structs) merge key by key, recursively
+//! - arrays and scalars replace what was there
+//! - `{"$append": [...]}` appends to the array instead of replacing it
+//! - `{"$replace": ...}` replaces the value without merging, e.g. to
+//!   swap out a whole map
+//! - `"$delete"` removes the key from the enclosing object
+//!
+//! e.g. an overlay for a cold war scenario might be
+//!
+//! ```json
+//! {
+//!   "deployables": { "Blue": [ ... ] },
+//!   "troops": { "Red": { "$append": [ ... ] } },
+//!   "unit_classification": { "M1A2": "$delete" }
+//! }
+//! ```
+
+use anyhow::{anyhow, bail, Context, Result};
+use compact_str::{format_compact, CompactString};
+use serde_json::{Map, Value};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+const APPEND: &str = "$append";
+const REPLACE: &str = "$replace";
+const DELETE: &str = "$delete";
+
+fn directive(o: &mut Map<String, Value>, path: &str, name: &str) -> Result<Option<Value>> {
+    if !o.contains_key(name) {
+        return Ok(None);
+    }
+    if o.len() != 1 {
+        bail!("{path}: {name} must be the only key in its object")
+    }
+    Ok(o.remove(name))
+}
+
+/// merge overlay into base following the rules described above. path
+/// is used to report errors.
+pub fn merge(path: &str, base: &mut Value, overlay: Value) -> Result<()> {
+    match overlay {
+        Value::Object(mut o) => {
+            if let Some(v) = directive(&mut o, path, APPEND)? {
+                let items = match v {
+                    Value::Array(a) => a,
+                    _ => bail!("{path}: {APPEND} must be an array"),
+                };
+                match base {
+                    Value::Array(a) => a.extend(items),
+                    Value::Null => *base = Value::Array(items),
+                    _ => bail!("{path}: can't append to something that isn't an array"),
+                }
+            } else if let Some(v) = directive(&mut o, path, REPLACE)? {
+                *base = v
+            } else {
+                if !base.is_object() {
+                    *base = Value::Object(Map::new())
+                }
+                let b = base.as_object_mut().unwrap();
+                for (k, v) in o {
+                    if v.as_str() == Some(DELETE) {
+                        b.remove(&k);
+                    } else {
+                        let path = format_compact!("{path}.{k}");
+                        merge(&path, b.entry(k).or_insert(Value::Null), v)?
+                    }
+                }
+            }
+        }
+        v => *base = v,
+    }
+    Ok(())
+}
+
+fn read(path: &Path) -> Result<Value> {
+    let buf = fs::read(path).with_context(|| format_compact!("reading {:?}", path))?;
+    serde_json::from_slice(&buf).map_err(|e| anyhow!("failed to decode {:?}, {:?}", path, e))
+}
+
+/// The paths of the overlays listed in the config file base. Relative
+/// paths are relative to the directory containing base.
+pub fn overlay_paths(base: &Path, cfg: &Value) -> Result<Vec<PathBuf>> {
+    let dir = base.parent().unwrap_or(Path::new("."));
+    match cfg.get("overlays") {
+        None | Some(Value::Null) => Ok(vec![]),
+        Some(Value::Array(a)) => a
+            .iter()
+            .map(|p| match p.as_str() {
+                Some(p) => Ok(dir.join(p)),
+                None => bail!("overlays must be a list of file names"),
+            })
+            .collect(),
+        Some(_) => bail!("overlays must be a list of file names"),
+    }
+}
+
+/// Read the config file at path and merge its overlays on top of it in
+/// order.
+pub fn load_merged(path: &Path) -> Result<Value> {
+    let mut cfg = read(path)?;
+    for overlay in overlay_paths(path, &cfg)? {
+        let v = read(&overlay)?;
+        if v.get("overlays").is_some() {
+            bail!("{:?}: overlays can't have overlays", overlay)
+        }
+        let name = CompactString::from(overlay.to_string_lossy());
+        merge(&name, &mut cfg, v)?
+    }
+    Ok(cfg)
+}
+
+/// Apply the changes between merged, the result of merging the
+/// overlays on top of base, and new to base, without copying in what
+/// the overlays added. Merging the overlays on top of the updated base
+/// will then produce new, except where an overlay itself sets the
+/// changed value. Removing a key or an array item that an overlay added
+/// is an error, since merging the overlays would just add it back, it
+/// has to be removed from the overlay instead. path is used to report
+/// errors.
+pub fn unmerge(path: &str, base: &mut Value, merged: &Value, new: Value) -> Result<()> {
+    match (merged, new) {
+        (Value::Object(m), Value::Object(n)) => {
+            if !base.is_object() {
+                *base = Value::Object(Map::new())
+            }
+            let b = base.as_object_mut().unwrap();
+            for k in m.keys().filter(|k| !n.contains_key(*k)) {
+                // if the merged value differs from the base then an
+                // overlay added or changed it
+                if b.get(k) != m.get(k) {
+                    bail!("{path}.{k} is set by an overlay, remove it from the overlay")
+                }
+                b.remove(k);
+            }
+            for (k, v) in n {
+                match m.get(&k) {
+                    Some(mv) if mv == &v => (),
+                    Some(mv) => {
+                        let path = format_compact!("{path}.{k}");
+                        unmerge(&path, b.entry(k).or_insert(Value::Null), mv, v)?
+                    }
+                    None => {
+                        b.insert(k, v);
+                    }
+                }
+            }
+        }
+        (Value::Array(m), Value::Array(mut n)) => {
+            // the overlays may have appended to the base array, keep
+            // their items out of it
+            let added = match base {
+                Value::Array(b) if m.starts_with(b) => &m[b.len()..],
+                Value::Array(_) => &[][..],
+                _ => &m[..],
+            };
+            if !n.ends_with(added) {
+                bail!("{path} has items added by an overlay, change them in the overlay")
+            }
+            n.truncate(n.len() - added.len());
+            *base = Value::Array(n)
+        }
+        (_, v) => *base = v,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{merge, unmerge};
+    use anyhow::Result;
+    use serde_json::{json, Value};
+
+    /// unmerge new into base, and check that merging overlay on top of
+    /// the result gives new
+    fn roundtrip(mut base: Value, overlay: Value, new: Value) -> Result<Value> {
+        let mut merged = base.clone();
+        merge("test", &mut merged, overlay.clone())?;
+        unmerge("test", &mut base, &merged, new.clone())?;
+        let mut check = base.clone();
+        merge("test", &mut check, overlay)?;
+        assert_eq!(check, new);
+        Ok(base)
+    }
+
+    #[test]
+    fn removed_key() {
+        let base = json!({"a": 1, "b": 2});
+        let overlay = json!({"c": 3, "b": 4});
+        let res = roundtrip(base.clone(), overlay.clone(), json!({"b": 4, "c": 3})).unwrap();
+        assert_eq!(res, json!({"b": 2}));
+        assert!(roundtrip(base.clone(), overlay.clone(), json!({"a": 1, "b": 4})).is_err());
+        assert!(roundtrip(base, overlay, json!({"a": 1, "c": 3})).is_err());
+    }
+
+    #[test]
+    fn removed_array_item() {
+        let base = json!({"l": [1, 2]});
+        let overlay = json!({"l": {"$append": [3, 4]}});
+        let res = roundtrip(base.clone(), overlay.clone(), json!({"l": [2, 3, 4]})).unwrap();
+        assert_eq!(res, json!({"l": [2]}));
+        assert!(roundtrip(base.clone(), overlay.clone(), json!({"l": [1, 2, 4]})).is_err());
+        assert!(roundtrip(base, overlay.clone(), json!({"l": [1, 2, 3]})).is_err());
+        let res = roundtrip(json!({}), overlay.clone(), json!({"l": [0, 3, 4]})).unwrap();
+        assert_eq!(res, json!({"l": [0]}));
+        assert!(roundtrip(json!({}), overlay, json!({"l": [3]})).is_err());
+    }
+}
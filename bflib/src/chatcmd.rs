@@ -1,11 +1,13 @@
 use crate::{
-    admin::{self, AdminCommand},
+    admin::{self, AdminCommand, Requester},
+    bg::Task,
     cfg::{Action, ActionKind},
     db::{
         actions::ActionCmd,
         group::{DeployKind, GroupId},
-        player::RegErr,
+        player::{Ranking, RegErr},
     },
+    ledger::{self, Reason},
     lives,
     msgq::MsgTyp,
     perf::PerfInner,
@@ -17,13 +19,91 @@ use chrono::{prelude::*, Duration};
 use compact_str::{format_compact, CompactString};
 use dcso3::{
     coalition::Side,
-    net::{Net, PlayerId},
+    net::{Net, PlayerId, Ucid},
     HooksLua, MizLua, String,
 };
-use fxhash::FxBuildHasher;
+use fxhash::{FxBuildHasher, FxHashMap};
 use indexmap::IndexMap;
 use log::{error, info};
-use std::sync::Arc;
+use smallvec::{smallvec, SmallVec};
+use std::{cmp::max, sync::Arc};
+
+/// players shown on each page of -top
+const TOP_PAGE: usize = 10;
+
+/// Limits on how often players may chat, set by the slow-mode admin
+/// command. Not persisted, a restart turns slow mode off.
+#[derive(Debug, Default)]
+pub(super) struct SlowMode {
+    global: Option<Duration>,
+    by_side: FxHashMap<Side, Duration>,
+    last_msg: FxHashMap<Ucid, DateTime<Utc>>,
+}
+
+impl SlowMode {
+    pub(super) fn set(&mut self, side: Option<Side>, interval: Option<Duration>) {
+        match (side, interval) {
+            (None, interval) => self.global = interval,
+            (Some(side), None) => {
+                self.by_side.remove(&side);
+            }
+            (Some(side), Some(interval)) => {
+                self.by_side.insert(side, interval);
+            }
+        }
+        if self.global.is_none() && self.by_side.is_empty() {
+            self.last_msg.clear()
+        }
+    }
+
+    /// If the player must wait before chatting again return how long,
+    /// otherwise record that they sent a message now
+    fn check(&mut self, ucid: Ucid, side: Option<Side>, now: DateTime<Utc>) -> Option<Duration> {
+        let by_side = side.and_then(|s| self.by_side.get(&s).copied());
+        let interval = match (self.global, by_side) {
+            (None, None) => return None,
+            (Some(i), None) | (None, Some(i)) => i,
+            (Some(i0), Some(i1)) => max(i0, i1),
+        };
+        if let Some(last) = self.last_msg.get(&ucid) {
+            let wait = *last + interval - now;
+            if wait > Duration::zero() {
+                return Some(wait);
+            }
+        }
+        self.last_msg.insert(ucid, now);
+        None
+    }
+}
+
+/// If the player may not chat right now return the reason
+fn chat_blocked(ctx: &mut Context, id: PlayerId, now: DateTime<Utc>) -> Option<CompactString> {
+    let ucid = ctx.connected.get(&id)?.ucid;
+    if let Some((until, _)) = ctx.db.ephemeral.cfg.muted.get(&ucid) {
+        match until {
+            None => return Some("you are muted".into()),
+            Some(until) if until > &now => {
+                return Some(format_compact!("you are muted until {until}"))
+            }
+            Some(_) => {
+                let path = ctx.miz_state_path.clone();
+                Arc::make_mut(&mut ctx.db.ephemeral.cfg).muted.remove(&ucid);
+                let cfg = Arc::clone(&ctx.db.ephemeral.cfg);
+                ctx.do_bg_task(Task::SaveConfig(path, cfg))
+            }
+        }
+    }
+    if ctx.db.ephemeral.cfg.is_admin(&ucid) {
+        return None;
+    }
+    let side = ctx.db.player(&ucid).map(|p| p.side);
+    ctx.slow_mode.check(ucid, side, now).map(|wait| {
+        format_compact!(
+            "slow mode is on, you may chat again in {}s",
+            wait.num_seconds() + 1
+        )
+    })
+}
 
 fn register_player(ctx: &mut Context, lua: HooksLua, id: PlayerId, msg: String) -> Result<String> {
     let ifo = ctx.connected.get_or_lookup_player_info(lua, id)?;
@@ -106,7 +186,7 @@ fn admin_command(ctx: &mut Context, id: PlayerId, cmd: &str) {
         Some(ifo) => ifo,
         None => return,
     };
-    if !ctx.db.ephemeral.cfg.admins.contains_key(&ifo.ucid) {
+    if !ctx.db.ephemeral.cfg.is_admin(&ifo.ucid) {
         return;
     }
     match cmd.parse::<AdminCommand>() {
@@ -115,14 +195,25 @@ fn admin_command(ctx: &mut Context, id: PlayerId, cmd: &str) {
             format_compact!("parse error {:?}", e),
         ),
         Ok(AdminCommand::Help) => {
-            for cmd in AdminCommand::help() {
-                ctx.db.ephemeral.msgs().send(MsgTyp::Chat(Some(id)), *cmd);
+            for (kind, cmd) in AdminCommand::help() {
+                if ctx.db.ephemeral.cfg.admin_permits(&ifo.ucid, *kind) {
+                    ctx.db.ephemeral.msgs().send(MsgTyp::Chat(Some(id)), *cmd);
+                }
             }
         }
-        Ok(cmd) => {
-            info!("queueing admin command {:?} from {:?}", cmd, ifo);
-            ctx.admin_commands.push((id, cmd))
-        }
+        Ok(cmd) => match cmd.kind() {
+            Some(kind) if !ctx.db.ephemeral.cfg.admin_permits(&ifo.ucid, kind) => {
+                info!("denied admin command {:?} from {:?}", cmd, ifo);
+                ctx.db.ephemeral.msgs().send(
+                    MsgTyp::Chat(Some(id)),
+                    format_compact!("you are not permitted to run {:?}", kind),
+                )
+            }
+            Some(_) | None => {
+                info!("queueing admin command {:?} from {:?}", cmd, ifo);
+                ctx.admin_commands.push((Requester::Player(id), cmd))
+            }
+        },
     }
 }
 
@@ -161,6 +252,286 @@ fn balance_command(ctx: &mut Context, id: PlayerId) {
     }
 }
 
+fn top_command(ctx: &mut Context, id: PlayerId, s: &str) -> Result<()> {
+    let mut by = Ranking::Points;
+    let mut side = None;
+    let mut page = 1;
+    for arg in s.split_whitespace() {
+        if let Ok(n) = arg.parse::<usize>() {
+            page = max(n, 1)
+        } else if let Ok(s) = arg.parse::<Side>() {
+            side = Some(s)
+        } else {
+            by = arg.parse::<Ranking>()?
+        }
+    }
+    let msgs: SmallVec<[CompactString; 12]> = {
+        let board = ctx.db.leaderboard(by, side);
+        let pages = max(1, board.len().div_ceil(TOP_PAGE));
+        if page > pages {
+            bail!("there are only {pages} pages")
+        }
+        let whose = match side {
+            None => format_compact!("all sides"),
+            Some(side) => format_compact!("{side}"),
+        };
+        let mut msgs = smallvec![format_compact!(
+            "top {by} on {whose} this round, page {page} of {pages}"
+        )];
+        if board.is_empty() {
+            msgs.push(" nobody yet".into())
+        }
+        for (i, (_, p)) in board
+            .iter()
+            .enumerate()
+            .skip((page - 1) * TOP_PAGE)
+            .take(TOP_PAGE)
+        {
+            msgs.push(format_compact!(
+                " {}. {} ({}) {}",
+                i + 1,
+                p.tagged_name(),
+                p.side,
+                p.ranked_by(by)
+            ))
+        }
+        if page < pages {
+            let side = side.map(|s| format_compact!(" {s}")).unwrap_or_default();
+            msgs.push(format_compact!(" -top {by}{side} {} for more", page + 1))
+        }
+        msgs
+    };
+    for msg in msgs {
+        ctx.db.ephemeral.msgs().send(MsgTyp::Chat(Some(id)), msg)
+    }
+    Ok(())
+}
+
+fn stats_command(ctx: &mut Context, id: PlayerId, s: &str) -> Result<()> {
+    let ucid = match s.trim() {
+        "" => {
+            ctx.connected
+                .get(&id)
+                .ok_or_else(|| anyhow!("missing info for player {:?}", id))?
+                .ucid
+        }
+        key => admin::get_player_ucid(ctx, key)?,
+    };
+    let msgs: SmallVec<[CompactString; 8]> = {
+        let player = ctx
+            .db
+            .player(&ucid)
+            .ok_or_else(|| anyhow!("{} has not joined a side this round", s.trim()))?;
+        let mut msgs = smallvec![format_compact!(
+            "{} ({}) this round",
+            player.tagged_name(),
+            player.side
+        )];
+        for by in Ranking::ALL {
+            let rank = ctx.db.rank(&ucid, by).unwrap_or(0);
+            msgs.push(format_compact!(
+                " {by}: {}, #{rank} on {}",
+                player.ranked_by(by),
+                player.side
+            ))
+        }
+        msgs.push(format_compact!(" assists: {}", player.stats.assists));
+        msgs.push(format_compact!(" team kills: {}", player.stats.team_kills));
+        msgs
+    };
+    for msg in msgs {
+        ctx.db.ephemeral.msgs().send(MsgTyp::Chat(Some(id)), msg)
+    }
+    Ok(())
+}
+
+fn forgive_command(ctx: &mut Context, id: PlayerId, s: &str) -> Result<()> {
+    let victim = ctx
+        .connected
+        .get(&id)
+        .ok_or_else(|| anyhow!("missing info for player {:?}", id))?
+        .ucid;
+    let shooter = match s.trim() {
+        "" => None,
+        key => Some(admin::get_player_ucid(ctx, key)?),
+    };
+    let (ucid, ban) = ctx.db.forgive_team_kill(&victim, shooter.as_ref())?;
+    if let Some(until) = ban {
+        admin::lift_ban(ctx, &ucid, until)?
+    }
+    let name = ctx
+        .db
+        .player(&ucid)
+        .map(|p| p.name.clone())
+        .unwrap_or_default();
+    ctx.db.ephemeral.msgs().send(
+        MsgTyp::Chat(Some(id)),
+        format_compact!("you forgave {name}"),
+    );
+    Ok(())
+}
+
+fn squadron_info(ctx: &Context, ucid: &Ucid, tag: &str) -> Result<SmallVec<[CompactString; 8]>> {
+    let tag = match tag {
+        "" => ctx
+            .db
+            .player(ucid)
+            .and_then(|p| p.squadron.clone())
+            .ok_or_else(|| anyhow!("you are not in a squadron"))?,
+        tag => String::from(tag),
+    };
+    let sq = ctx.db.squadron(&tag)?;
+    let name = |ucid: &Ucid| {
+        ctx.db
+            .player(ucid)
+            .map(|p| p.name.clone())
+            .unwrap_or_default()
+    };
+    let mut officers = CompactString::default();
+    let mut members = CompactString::default();
+    for (ucid, p) in ctx.db.squadron_members(&tag) {
+        let names = if sq.officers.contains(&ucid) {
+            &mut officers
+        } else {
+            &mut members
+        };
+        if !names.is_empty() {
+            names.push_str(", ")
+        }
+        names.push_str(&p.name)
+    }
+    Ok(smallvec![
+        format_compact!("[{tag}] {} ({})", sq.name, sq.side),
+        format_compact!(" leader: {}", name(&sq.leader)),
+        format_compact!(" officers: {officers}"),
+        format_compact!(" members: {members}"),
+        format_compact!(" pool: {} points", sq.points),
+    ])
+}
+
+fn squadron_command(ctx: &mut Context, id: PlayerId, s: &str) -> Result<()> {
+    macro_rules! reply {
+        ($msg:expr) => {
+            ctx.db.ephemeral.msgs().send(MsgTyp::Chat(Some(id)), $msg)
+        };
+    }
+    let ucid = ctx
+        .connected
+        .get(&id)
+        .ok_or_else(|| anyhow!("missing info for player {:?}", id))?
+        .ucid;
+    let s = s.trim();
+    let (cmd, arg) = match s.split_once(' ') {
+        Some((cmd, arg)) => (cmd, arg.trim()),
+        None => (s, ""),
+    };
+    match cmd {
+        "create" => {
+            let (tag, name) = arg
+                .split_once(' ')
+                .ok_or_else(|| anyhow!("expected -squadron create <tag> <name>"))?;
+            ctx.db.squadron_create(&ucid, tag, name)?;
+            reply!(format_compact!("created [{tag}] {}", name.trim()))
+        }
+        "invite" => {
+            let target = admin::get_player_ucid(ctx, arg)?;
+            ctx.db.squadron_invite(&ucid, &target)?;
+            reply!(format_compact!("invited {arg}"))
+        }
+        "join" => ctx.db.squadron_join(&ucid, arg)?,
+        "leave" => {
+            let tag = ctx.db.squadron_leave(&ucid)?;
+            reply!(format_compact!("you have left [{tag}]"))
+        }
+        "kick" => {
+            let target = admin::get_player_ucid(ctx, arg)?;
+            ctx.db.squadron_kick(&ucid, &target)?;
+            reply!(format_compact!("removed {arg}"))
+        }
+        "promote" | "demote" => {
+            let target = admin::get_player_ucid(ctx, arg)?;
+            ctx.db.squadron_promote(&ucid, &target, cmd == "promote")?;
+            reply!(format_compact!("{cmd}d {arg}"))
+        }
+        "deposit" => {
+            let amount = arg.parse::<u32>().context("deposit expected a number")?;
+            ctx.db.squadron_deposit(&ucid, amount)?
+        }
+        "withdraw" => {
+            let (amount, target) = match arg.split_once(' ') {
+                None => (arg, ucid),
+                Some((amount, player)) => (amount, admin::get_player_ucid(ctx, player.trim())?),
+            };
+            let amount = amount
+                .parse::<u32>()
+                .context("withdraw expected a number")?;
+            ctx.db.squadron_withdraw(&ucid, &target, amount)?
+        }
+        "crates" => {
+            let crates = ctx.db.squadron_crates(&ucid)?;
+            if crates.is_empty() {
+                reply!("your squadron has no crates deployed")
+            }
+            for (owner, name, gid) in crates {
+                reply!(format_compact!(" {name} {gid} deployed by {owner}"))
+            }
+        }
+        "" | "info" => {
+            for msg in squadron_info(ctx, &ucid, arg)? {
+                reply!(msg)
+            }
+        }
+        _ => {
+            for msg in [
+                " -squadron [info] [tag]: show your squadron, or tag",
+                " -squadron create <tag> <name>: start a squadron",
+                " -squadron invite <player>: invite player (officers)",
+                " -squadron join <tag>: accept an invite",
+                " -squadron leave: leave your squadron",
+                " -squadron kick <player>: remove player (officers)",
+                " -squadron promote|demote <player>: make player an officer, or not (leader)",
+                " -squadron deposit <n>: pay n of your points into the pool",
+                " -squadron withdraw <n> [player]: pay n points out of the pool (officers)",
+                " -squadron crates: list crates deployed by your squadron",
+            ] {
+                reply!(msg)
+            }
+        }
+    }
+    Ok(())
+}
+
+fn ledger_command(ctx: &mut Context, id: PlayerId, s: &str) {
+    macro_rules! reply {
+        ($msg:expr) => {
+            ctx.db.ephemeral.msgs().send(MsgTyp::Chat(Some(id)), $msg)
+        };
+    }
+    let ucid = match ctx.connected.get(&id) {
+        Some(ifo) => ifo.ucid.clone(),
+        None => return,
+    };
+    let limit = match s.trim() {
+        "" => 10,
+        s => match s.parse::<usize>() {
+            Ok(n) => n.clamp(1, 50),
+            Err(e) => return reply!(format_compact!("ledger expected a number {e:?}")),
+        },
+    };
+    match ledger::query(&ctx.miz_state_path, &ucid, limit) {
+        Err(e) => {
+            error!("could not read the ledger of {ucid} {e:?}");
+            reply!("your ledger is not available right now")
+        }
+        Ok(entries) if entries.is_empty() => reply!("no points changes have been recorded"),
+        Ok(entries) => {
+            for e in entries {
+                reply!(format_compact!("{e}"))
+            }
+        }
+    }
+}
+
 fn transfer_command(ctx: &mut Context, id: PlayerId, s: &str) {
     macro_rules! reply {
         ($msg:tt) => {
@@ -228,6 +599,8 @@ fn delete_command(ctx: &mut Context, id: PlayerId, s: &str) {
                                 ctx.db.adjust_points(
                                     &player,
                                     points,
+                                    Reason::Refund,
+                                    Some(String::from(format_compact!("{id}"))),
                                     &format_compact!("reclaimed {id}"),
                                 );
                                 reply!("deleted {id}")
@@ -247,6 +620,8 @@ fn delete_command(ctx: &mut Context, id: PlayerId, s: &str) {
                                 ctx.db.adjust_points(
                                     &player,
                                     points,
+                                    Reason::Refund,
+                                    Some(String::from(format_compact!("{id}"))),
                                     &format_compact!("reclaimed {id}"),
                                 );
                                 reply!("deleted {id}")
@@ -375,7 +750,7 @@ pub(super) fn run_action_commands(
 fn help_command(ctx: &mut Context, id: PlayerId) {
     let admin = match ctx.connected.get(&id) {
         None => false,
-        Some(ifo) => ctx.db.ephemeral.cfg.admins.contains_key(&ifo.ucid),
+        Some(ifo) => ctx.db.ephemeral.cfg.is_admin(&ifo.ucid),
     };
     for cmd in [
         " blue: join the blue team",
@@ -384,6 +759,11 @@ fn help_command(ctx: &mut Context, id: PlayerId) {
         " -lives: display your current lives",
         " -time: how long until server restart",
         " -balance: show your points balance",
+        " -ledger [n]: show the last n changes to your points",
+        " -top [points|kills|captures|logistics] [side] [page]: show the leaderboard",
+        " -stats [player]: show what you, or player, have done this round",
+        " -forgive [player]: forgive the last team kill against you, or player's",
+        " -squadron help: manage your squadron",
         " -transfer <amount> <player>: transfer points to another player",
         " -delete <groupid>: delete a group you deployed for a partial refund",
         " -action <name> <args>: perform an action, -action help for a list of actions",
@@ -427,6 +807,36 @@ pub(super) fn process(
     } else if msg.starts_with("-balance") {
         balance_command(ctx, id);
         Ok("".into())
+    } else if let Some(s) = msg.strip_prefix("-top") {
+        if let Err(e) = top_command(ctx, id, s) {
+            ctx.db
+                .ephemeral
+                .msgs()
+                .send(MsgTyp::Chat(Some(id)), format_compact!("{e}"))
+        }
+        Ok("".into())
+    } else if let Some(s) = msg.strip_prefix("-stats") {
+        if let Err(e) = stats_command(ctx, id, s) {
+            ctx.db
+                .ephemeral
+                .msgs()
+                .send(MsgTyp::Chat(Some(id)), format_compact!("{e}"))
+        }
+        Ok("".into())
+    } else if let Some(s) = msg.strip_prefix("-forgive") {
+        forgive_command(ctx, id, s)?;
+        Ok("".into())
+    } else if let Some(s) = msg.strip_prefix("-squadron") {
+        if let Err(e) = squadron_command(ctx, id, s) {
+            ctx.db
+                .ephemeral
+                .msgs()
+                .send(MsgTyp::Chat(Some(id)), format_compact!("{e}"))
+        }
+        Ok("".into())
+    } else if let Some(s) = msg.strip_prefix("-ledger") {
+        ledger_command(ctx, id, s);
+        Ok("".into())
     } else if let Some(s) = msg.strip_prefix("-transfer ") {
         transfer_command(ctx, id, s);
         Ok("".into())
@@ -447,6 +857,9 @@ pub(super) fn process(
         );
         help_command(ctx, id);
         Ok("".into())
+    } else if let Some(notice) = chat_blocked(ctx, id, now) {
+        ctx.db.ephemeral.msgs().send(MsgTyp::Chat(Some(id)), notice);
+        Ok("".into())
     } else {
         Ok(msg)
     }
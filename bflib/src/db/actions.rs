@@ -12,9 +12,11 @@ use crate::{
     db::{cargo::Oldest, group::DeployKind},
     group, group_mut,
     jtac::{JtId, Jtacs},
+    ledger::Reason,
     objective,
     perf::PerfInner,
     spawnctx::{SpawnCtx, SpawnLoc},
+    stats::StatKind,
     unit,
 };
 use anyhow::{anyhow, bail, Context, Ok, Result};
@@ -286,7 +288,7 @@ impl Db {
             _ => cmd.action.cost,
         };
         if let Some(ucid) = ucid.as_ref() {
-            if !self.ephemeral.cfg.rules.actions.check(ucid) {
+            if !self.rule_permits(&self.ephemeral.cfg.rules.actions, ucid) {
                 bail!("you are not authorized for actions")
             }
             match self.persisted.players.get(ucid) {
@@ -322,6 +324,7 @@ impl Db {
             }
         }
         let name = cmd.name.clone();
+        let action = cmd.action.clone();
         match cmd.args {
             ActionArgs::Awacs(args) => self
                 .awacs(perf, spctx, idx, side, ucid.clone(), name, cmd.action, args)
@@ -390,8 +393,11 @@ impl Db {
             self.adjust_points(
                 ucid,
                 -(cost as i32),
+                Reason::Action,
+                Some(cmd.name.clone()),
                 &format!("perform action {}", cmd.name),
             );
+            self.ephemeral.stat(StatKind::Action { by: *ucid, action });
         }
         *self
             .ephemeral
@@ -23,8 +23,11 @@ use super::{
 use crate::{
     cfg::{CargoConfig, Crate, Deployable, LimitEnforceTyp, Troop, Vehicle},
     db::group::DeployKind,
-    group, maybe, objective,
+    group,
+    ledger::Reason,
+    maybe, objective,
     spawnctx::{SpawnCtx, SpawnLoc},
+    stats::StatKind,
     unit, unit_mut,
 };
 use anyhow::{anyhow, bail, Result};
@@ -180,10 +183,7 @@ impl Db {
             .objectives
             .into_iter()
             .find_map(|(oid, obj)| {
-                if obj.owner == side
-                    && obj.logi() > 0
-                    && na::distance_squared(&obj.pos.into(), &point.into()) <= obj.radius.powi(2)
-                {
+                if obj.owner == side && obj.logi() > 0 && obj.contains(point) {
                     return Some((oid, obj));
                 }
                 None
@@ -664,10 +664,7 @@ impl Db {
                         None => error!("missing group {:?}", cr.group),
                     }
                 }
-                if obj.owner == side && !is_origin && {
-                    let dist = na::distance_squared(&obj.pos.into(), &centroid.into());
-                    dist <= obj.radius.powi(2)
-                } {
+                if obj.owner == side && !is_origin && obj.contains(centroid) {
                     Some(*oid)
                 } else {
                     None
@@ -775,11 +772,30 @@ impl Db {
                 if obj.logi == 100 {
                     reasons.push("objective logistics are completely repaired".into());
                 } else {
+                    let name = obj.name.clone();
                     self.repair_one_logi_step(st.side, Utc::now(), oid)?;
                     self.delete_group(base_repairs.keys().next().unwrap())?;
-                    if let Some(amount) = self.ephemeral.cfg.points.map(|p| p.logistics_repair) {
-                        self.adjust_points(&st.ucid, amount as i32, "for logistics repair");
+                    let points = self
+                        .ephemeral
+                        .cfg
+                        .points
+                        .map(|p| p.logistics_repair)
+                        .unwrap_or(0);
+                    if let Some(stats) = self.round_stats_mut(&st.ucid) {
+                        stats.logistics += 1
                     }
+                    self.adjust_points(
+                        &st.ucid,
+                        points as i32,
+                        Reason::Repair,
+                        Some(name),
+                        "for logistics repair",
+                    );
+                    self.ephemeral.stat(StatKind::Repair {
+                        id: oid,
+                        ucid: st.ucid,
+                        points: points as usize,
+                    });
                     let obj = objective!(self, oid)?;
                     return Ok(Unpakistan::RepairedBase(obj.name.clone(), obj.logi()));
                 }
@@ -802,8 +818,18 @@ impl Db {
                 {
                     self.transfer_supplies(lua, from, to)?;
                     self.delete_group(&gid)?;
+                    if let Some(stats) = self.round_stats_mut(&st.ucid) {
+                        stats.logistics += 1
+                    }
                     if let Some(amount) = self.ephemeral.cfg.points.map(|p| p.logistics_transfer) {
-                        self.adjust_points(&st.ucid, amount as i32, "for supply transfer");
+                        let to = objective!(self, to)?.name.clone();
+                        self.adjust_points(
+                            &st.ucid,
+                            amount as i32,
+                            Reason::SupplyTransfer,
+                            Some(to),
+                            "for supply transfer",
+                        );
                     }
                     return Ok(Unpakistan::TransferedSupplies(
                         objective!(self, from)?.name.clone(),
@@ -841,7 +867,21 @@ impl Db {
                                 }
                                 let oid =
                                     self.add_farp(&spctx, idx, st.side, centroid, &spec, parts)?;
-                                self.adjust_points(&st.ucid, -(spec.cost as i32), "for farp spawn");
+                                let name = spec.path.last().cloned();
+                                self.adjust_points(
+                                    &st.ucid,
+                                    -(spec.cost as i32),
+                                    Reason::Deploy,
+                                    name,
+                                    "for farp spawn",
+                                );
+                                self.ephemeral.stat(StatKind::Deploy {
+                                    ucid: st.ucid,
+                                    deployable: spec.clone(),
+                                });
+                                if let Some(stats) = self.round_stats_mut(&st.ucid) {
+                                    stats.logistics += 1
+                                }
                                 let name = objective!(self, oid)?.name.clone();
                                 return Ok(Unpakistan::UnpackedFarp(name, oid));
                             }
@@ -870,8 +910,17 @@ impl Db {
                                 self.adjust_points(
                                     &st.ucid,
                                     -(spec.cost as i32),
+                                    Reason::Deploy,
+                                    Some(dep.clone()),
                                     &format_compact!("for {dep} unpack"),
                                 );
+                                self.ephemeral.stat(StatKind::Deploy {
+                                    ucid: st.ucid,
+                                    deployable: spec.clone(),
+                                });
+                                if let Some(stats) = self.round_stats_mut(&st.ucid) {
+                                    stats.logistics += 1
+                                }
                                 return Ok(Unpakistan::Unpacked(dep, gid));
                             }
                         },
@@ -1084,6 +1133,8 @@ impl Db {
         self.adjust_points(
             &ucid,
             -(troop_cfg.cost as i32),
+            Reason::Troop,
+            Some(troop_cfg.name.clone()),
             &format_compact!("for {name} troop"),
         );
         Ok(troop_cfg)
@@ -1161,6 +1212,13 @@ impl Db {
                 .push((ucid, troop_cfg));
             return Err(e);
         }
+        self.ephemeral.stat(StatKind::Troop {
+            ucid,
+            troop: troop_cfg.clone(),
+        });
+        if let Some(stats) = self.round_stats_mut(&ucid) {
+            stats.logistics += 1
+        }
         Ok(troop_cfg)
     }
 
@@ -1185,7 +1243,13 @@ impl Db {
         Trigger::singleton(lua)?
             .action()?
             .set_unit_internal_cargo(unit_name, cargo.weight())?;
-        self.adjust_points(&ucid, troop_cfg.cost as i32, "for troop return");
+        self.adjust_points(
+            &ucid,
+            troop_cfg.cost as i32,
+            Reason::TroopReturn,
+            Some(troop_cfg.name.clone()),
+            "for troop return",
+        );
         Ok(troop_cfg)
     }
 
@@ -16,9 +16,10 @@ for more details.
 
 use super::{
     cargo::Cargo,
+    frontline::FrontLine,
     group::{GroupId, SpawnedGroup, SpawnedUnit, UnitId},
     markup::ObjectiveMarkup,
-    objective::{Objective, ObjectiveId},
+    objective::{CaptureProgress, Objective, ObjectiveId},
     persisted::Persisted,
 };
 use crate::{
@@ -26,10 +27,12 @@ use crate::{
         ActionKind, AiPlaneCfg, AwacsCfg, BomberCfg, Cfg, Crate, Deployable, DeployableCfg,
         DeployableLogistics, DroneCfg, Troop, UnitTag, Vehicle, WarehouseConfig,
     },
+    ledger::{self, Reason},
     maybe,
     msgq::MsgQ,
     perf::{record_perf, PerfInner},
     spawnctx::{Despawn, SpawnCtx, Spawned},
+    stats::{Stat, StatKind},
 };
 use anyhow::{anyhow, bail, Context, Result};
 use chrono::prelude::*;
@@ -68,6 +71,8 @@ pub struct SlotInfo {
     pub ground_start: bool,
     pub miz_gid: miz::GroupId,
     pub side: Side,
+    /// the squadron the slot is reserved for, if any
+    pub squadron: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -121,6 +126,11 @@ pub struct Ephemeral {
     pub(super) deployable_idx: FxHashMap<Side, Arc<DeployableIndex>>,
     pub(super) group_marks: FxHashMap<GroupId, MarkId>,
     objective_markup: FxHashMap<ObjectiveId, ObjectiveMarkup>,
+    /// the neighbors of each objective, empty if captures aren't
+    /// restricted
+    pub(super) adjacent: FxHashMap<ObjectiveId, FxHashSet<ObjectiveId>>,
+    pub(super) capture_progress: FxHashMap<ObjectiveId, CaptureProgress>,
+    front_line: FrontLine,
     pub(super) object_id_by_uid: FxHashMap<UnitId, DcsOid<ClassUnit>>,
     pub(super) uid_by_object_id: FxHashMap<DcsOid<ClassUnit>, UnitId>,
     pub(super) object_id_by_slot: FxHashMap<SlotId, DcsOid<ClassUnit>>,
@@ -143,6 +153,9 @@ pub struct Ephemeral {
     spawnq: VecDeque<GroupId>,
     despawnq: VecDeque<(GroupId, Despawn)>,
     sync_warehouse: Vec<(ObjectiveId, Vehicle)>,
+    stats: Vec<Stat>,
+    ledger: Vec<ledger::Entry>,
+    pub(super) team_kill_bans: Vec<(Ucid, DateTime<Utc>)>,
     pub(super) msgs: MsgQ,
 }
 
@@ -156,6 +169,9 @@ impl Default for Ephemeral {
             deployable_idx: FxHashMap::default(),
             group_marks: FxHashMap::default(),
             objective_markup: FxHashMap::default(),
+            adjacent: FxHashMap::default(),
+            capture_progress: FxHashMap::default(),
+            front_line: FrontLine::default(),
             object_id_by_uid: FxHashMap::default(),
             uid_by_object_id: FxHashMap::default(),
             object_id_by_slot: FxHashMap::default(),
@@ -177,6 +193,9 @@ impl Default for Ephemeral {
             spawnq: VecDeque::default(),
             despawnq: VecDeque::default(),
             sync_warehouse: Vec::default(),
+            stats: Vec::default(),
+            ledger: Vec::default(),
+            team_kill_bans: Vec::default(),
             msgs: MsgQ::default(),
             logistics_stage: LogiStage::default(),
         }
@@ -188,25 +207,78 @@ impl Ephemeral {
         self.slot_info.get(slot)
     }
 
+    /// Is oid next to an objective held by side. Always true if oid has
+    /// no neighbors.
+    pub(super) fn adjacent_to(&self, persisted: &Persisted, oid: &ObjectiveId, side: Side) -> bool {
+        match self.adjacent.get(oid) {
+            None => true,
+            Some(adj) => adj.iter().any(|oid| {
+                persisted
+                    .objectives
+                    .get(oid)
+                    .map(|o| o.owner == side)
+                    .unwrap_or(false)
+            }),
+        }
+    }
+
+    pub(super) fn capturable_by(&self, persisted: &Persisted, obj: &Objective, side: Side) -> bool {
+        obj.captureable() && self.adjacent_to(persisted, &obj.id, side)
+    }
+
+    /// can a side other than the owner capture obj
+    pub(super) fn capturable(&self, persisted: &Persisted, obj: &Objective) -> bool {
+        [Side::Red, Side::Blue]
+            .into_iter()
+            .any(|side| side != obj.owner && self.capturable_by(persisted, obj, side))
+    }
+
+    pub fn update_front_line(&mut self, persisted: &Persisted) {
+        self.front_line
+            .update(self.cfg.front_line, &mut self.msgs, persisted)
+    }
+
+    /// the side capturing oid and its progress in percent, if any. The
+    /// progress is rounded down to 10% steps so the markup isn't
+    /// rewritten on every capture tick.
+    fn capture_status(&self, oid: &ObjectiveId) -> Option<(Side, u8)> {
+        self.capture_progress
+            .get(oid)
+            .map(|p| (p.side, (p.progress * 10.).clamp(0., 9.) as u8 * 10))
+    }
+
     pub fn create_objective_markup(&mut self, persisted: &Persisted, obj: &Objective) {
         if let Some(mk) = self.objective_markup.remove(&obj.id) {
             mk.remove(&mut self.msgs);
         }
+        let capturable = self.capturable(persisted, obj);
+        let capture = self.capture_status(&obj.id);
         self.objective_markup.insert(
             obj.id,
-            ObjectiveMarkup::new(&self.cfg, &mut self.msgs, obj, persisted),
+            ObjectiveMarkup::new(
+                &self.cfg,
+                &mut self.msgs,
+                obj,
+                persisted,
+                capturable,
+                capture,
+            ),
         );
     }
 
     pub fn update_objective_markup(&mut self, persisted: &Persisted, obj: &Objective) {
+        let capturable = self.capturable(persisted, obj);
+        let capture = self.capture_status(&obj.id);
         match self.objective_markup.entry(obj.id) {
-            Entry::Occupied(mut e) => e.get_mut().update(&mut self.msgs, obj),
+            Entry::Occupied(mut e) => e.get_mut().update(&mut self.msgs, obj, capturable, capture),
             Entry::Vacant(e) => {
                 e.insert(ObjectiveMarkup::new(
                     &self.cfg,
                     &mut self.msgs,
                     obj,
                     persisted,
+                    capturable,
+                    capture,
                 ));
             }
         }
@@ -226,6 +298,46 @@ impl Ephemeral {
         mem::take(&mut self.sync_warehouse)
     }
 
+    pub fn stat(&mut self, kind: StatKind) {
+        self.stats.push(Stat::new(kind))
+    }
+
+    pub fn take_stats(&mut self) -> Vec<Stat> {
+        mem::take(&mut self.stats)
+    }
+
+    /// record a change to ucid's points. balance is the balance after
+    /// the change.
+    pub(super) fn ledger(
+        &mut self,
+        ucid: Ucid,
+        amount: i32,
+        balance: i32,
+        reason: Reason,
+        counterpart: Option<String>,
+    ) {
+        if amount != 0 {
+            self.ledger.push(ledger::Entry {
+                time: Utc::now(),
+                ucid,
+                amount,
+                balance,
+                reason,
+                counterpart,
+            })
+        }
+    }
+
+    pub fn take_ledger(&mut self) -> Vec<ledger::Entry> {
+        mem::take(&mut self.ledger)
+    }
+
+    /// players who reached a ban on the team kill ladder, and when the
+    /// ban ends
+    pub fn take_team_kill_bans(&mut self) -> Vec<(Ucid, DateTime<Utc>)> {
+        mem::take(&mut self.team_kill_bans)
+    }
+
     pub fn push_despawn(&mut self, gid: GroupId, ds: Despawn) {
         let mut queued_spawn = false;
         self.spawnq.retain(|sp_gid| {
@@ -630,7 +742,18 @@ impl Ephemeral {
             .any(|si| &si.objective == oid && si.typ.as_str() == typ)
     }
 
-    pub(super) fn set_cfg(&mut self, miz: &Miz, mizidx: &MizIndex, mut cfg: Cfg) -> Result<()> {
+    /// Validate and index cfg and make it the live config. If anything
+    /// fails the previous config and indexes are left in place.
+    pub(super) fn set_cfg(&mut self, miz: &Miz, mizidx: &MizIndex, cfg: Cfg) -> Result<()> {
+        let old_idx = mem::take(&mut self.deployable_idx);
+        if let Err(e) = self.index_cfg(miz, mizidx, cfg) {
+            self.deployable_idx = old_idx;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn index_cfg(&mut self, miz: &Miz, mizidx: &MizIndex, mut cfg: Cfg) -> Result<()> {
         for (_, actions) in &mut cfg.actions {
             actions.sort_by(|name0, _, name1, _| name0.cmp(name1));
         }
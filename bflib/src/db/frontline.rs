@@ -0,0 +1,166 @@
+/*
+Copyright 2024 Eric Stokes.
+
+This file is part of bflib.
+
+bflib is free software: you can redistribute it and/or modify it under
+the terms of the GNU Affero Public License as published by the Free
+Software Foundation, either version 3 of the License, or (at your
+option) any later version.
+
+bflib is distributed in the hope that it will be useful, but WITHOUT
+ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero Public License
+for more details.
+*/
+
+use super::{
+    objective::{ObjectiveId, ObjectiveKind},
+    persisted::Persisted,
+};
+use crate::{cfg::FrontLineCfg, msgq::MsgQ};
+use dcso3::{
+    coalition::Side,
+    trigger::{LineSpec, LineType, MarkId, SideFilter},
+    Color, LuaVec3, Vector2, Vector3,
+};
+use fxhash::FxHashMap;
+
+/// How far territory extends past the outermost objectives. Edges on
+/// this boundary are not part of the front.
+const MARGIN: f64 = 100_000.;
+
+/// A convex polygon. Each vertex carries the objective whose bisector
+/// formed the edge from it to the next vertex, or None if the edge is
+/// on the outer boundary.
+type Cell = Vec<(Vector2, Option<ObjectiveId>)>;
+
+/// Clip cell to the half plane that is closer to a than to b
+fn clip(cell: &Cell, a: Vector2, b: Vector2, bid: ObjectiveId) -> Cell {
+    let mid = (a + b) / 2.;
+    let dir = b - a;
+    let dist = |p: &Vector2| (p - mid).dot(&dir);
+    let mut res = Vec::with_capacity(cell.len() + 1);
+    for (i, (p0, edge)) in cell.iter().enumerate() {
+        let p1 = &cell[(i + 1) % cell.len()].0;
+        let (d0, d1) = (dist(p0), dist(p1));
+        let cross = || p0 + (p1 - p0) * (d0 / (d0 - d1));
+        match (d0 <= 0., d1 <= 0.) {
+            (true, true) => res.push((*p0, *edge)),
+            (true, false) => {
+                res.push((*p0, *edge));
+                res.push((cross(), Some(bid)));
+            }
+            (false, true) => res.push((cross(), *edge)),
+            (false, false) => (),
+        }
+    }
+    res
+}
+
+/// Compute the segments of the boundary between red and blue
+/// territory. Every point on the map belongs to the side that owns the
+/// closest objective, so the boundary is made of the edges of the
+/// voronoi cells of red objectives that border blue ones.
+fn compute(persisted: &Persisted) -> Vec<(Vector2, Vector2)> {
+    let sites = persisted
+        .objectives
+        .into_iter()
+        .filter_map(|(oid, obj)| match obj.kind {
+            ObjectiveKind::Farp { .. } => None,
+            ObjectiveKind::Airbase | ObjectiveKind::Fob | ObjectiveKind::Logistics => {
+                Some((*oid, obj.pos, obj.owner))
+            }
+        })
+        .collect::<Vec<_>>();
+    let (mut min, mut max) = match sites.first() {
+        None => return vec![],
+        Some((_, pos, _)) => (*pos, *pos),
+    };
+    for (_, pos, _) in &sites {
+        min = min.inf(pos);
+        max = max.sup(pos);
+    }
+    let margin = Vector2::new(MARGIN, MARGIN);
+    let (min, max) = (min - margin, max + margin);
+    let bounds: Cell = vec![
+        (min, None),
+        (Vector2::new(max.x, min.y), None),
+        (max, None),
+        (Vector2::new(min.x, max.y), None),
+    ];
+    let owner: FxHashMap<ObjectiveId, Side> =
+        sites.iter().map(|(oid, _, side)| (*oid, *side)).collect();
+    let mut segments = vec![];
+    for (oid, pos, side) in &sites {
+        if *side != Side::Red {
+            continue;
+        }
+        let mut cell = bounds.clone();
+        for (bid, bpos, _) in &sites {
+            if bid != oid && bpos != pos {
+                cell = clip(&cell, *pos, *bpos, *bid);
+            }
+        }
+        for (i, (p0, edge)) in cell.iter().enumerate() {
+            if let Some(bid) = edge {
+                if owner.get(bid) == Some(&Side::Blue) {
+                    segments.push((*p0, cell[(i + 1) % cell.len()].0))
+                }
+            }
+        }
+    }
+    segments
+}
+
+/// The front line markup, redrawn whenever an objective changes hands
+#[derive(Debug, Default)]
+pub(super) struct FrontLine {
+    cfg: Option<FrontLineCfg>,
+    owners: FxHashMap<ObjectiveId, Side>,
+    marks: Vec<MarkId>,
+}
+
+impl FrontLine {
+    pub(super) fn update(
+        &mut self,
+        cfg: Option<FrontLineCfg>,
+        msgq: &mut MsgQ,
+        persisted: &Persisted,
+    ) {
+        let owners = persisted
+            .objectives
+            .into_iter()
+            .map(|(oid, obj)| (*oid, obj.owner))
+            .collect::<FxHashMap<_, _>>();
+        if cfg == self.cfg && owners == self.owners {
+            return;
+        }
+        self.cfg = cfg;
+        self.owners = owners;
+        for id in self.marks.drain(..) {
+            msgq.delete_mark(id)
+        }
+        if let Some(cfg) = cfg {
+            let [r, g, b, a] = cfg.color;
+            let color = Color::new(r, g, b, a);
+            let v = |p: Vector2| LuaVec3(Vector3::new(p.x, 0., p.y));
+            for (start, end) in compute(persisted) {
+                let id = MarkId::new();
+                msgq.line_to_all(
+                    SideFilter::All,
+                    id,
+                    LineSpec {
+                        start: v(start),
+                        end: v(end),
+                        color,
+                        line_type: LineType::Solid,
+                        read_only: true,
+                    },
+                    None,
+                );
+                self.marks.push(id);
+            }
+        }
+    }
+}
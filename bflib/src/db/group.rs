@@ -21,6 +21,7 @@ use super::{
 use crate::{
     cfg::{Action, ActionKind, Crate, Deployable, Troop, UnitTag, UnitTags, Vehicle},
     group, group_by_name, group_health, group_mut,
+    ledger::Reason,
     spawnctx::{Despawn, SpawnCtx, SpawnLoc},
     unit, unit_by_name, unit_mut, Connected,
 };
@@ -793,7 +794,8 @@ impl Db {
                                 let ucid = ucid.clone();
                                 let p = -(*p as i32);
                                 let msg = format_compact!("for the death of {gid} which was deployed by {owner} and moved by you");
-                                self.adjust_points(&ucid, p, &msg)
+                                let gname = Some(String::from(format_compact!("{gid}")));
+                                self.adjust_points(&ucid, p, Reason::GroupLoss, gname, &msg)
                             }
                             DeployKind::Troop { .. }
                             | DeployKind::Deployed { .. }
@@ -814,6 +816,8 @@ impl Db {
                                 self.adjust_points(
                                     &ucid,
                                     -(penalty as i32),
+                                    Reason::ActionPenalty,
+                                    Some(String::from(format_compact!("{gid}"))),
                                     &format_compact!("for the loss of action group {gid}"),
                                 )
                             }
@@ -0,0 +1,311 @@
+/*
+Copyright 2024 Eric Stokes.
+
+This file is part of bflib.
+
+bflib is free software: you can redistribute it and/or modify it under
+the terms of the GNU Affero Public License as published by the Free
+Software Foundation, either version 3 of the License, or (at your
+option) any later version.
+
+bflib is distributed in the hope that it will be useful, but WITHOUT
+ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero Public License
+for more details.
+*/
+
+//! `Persisted` keeps a number of indexes that are derived from the primary
+//! `groups`, `units`, and `objectives` maps. This checks that they all
+//! agree, and can rebuild the derived indexes when they don't.
+
+use super::{
+    group::{DeployKind, GroupId, UnitId},
+    objective::ObjectiveId,
+    persisted::Persisted,
+    Map, Set,
+};
+use crate::cfg::ActionKind;
+use compact_str::{format_compact, CompactString};
+use dcso3::{coalition::Side, net::Ucid, String};
+use std::fmt::Debug;
+
+/// The indexes as they should be given the primary maps
+#[derive(Default)]
+struct Derived {
+    groups_by_name: Map<String, GroupId>,
+    units_by_name: Map<String, UnitId>,
+    groups_by_side: Map<Side, Set<GroupId>>,
+    objectives_by_name: Map<String, ObjectiveId>,
+    objectives_by_group: Map<GroupId, ObjectiveId>,
+    farps: Set<ObjectiveId>,
+    deployed: Set<GroupId>,
+    troops: Set<GroupId>,
+    crates: Set<GroupId>,
+    jtacs: Set<GroupId>,
+    ewrs: Set<GroupId>,
+    actions: Set<GroupId>,
+    player_crates: Map<Ucid, Set<GroupId>>,
+}
+
+impl Derived {
+    fn new(p: &Persisted) -> Self {
+        let mut t = Self::default();
+        for (gid, group) in &p.groups {
+            t.groups_by_name.insert_cow(group.name.clone(), *gid);
+            t.groups_by_side
+                .get_or_default_cow(group.side)
+                .insert_cow(*gid);
+            match &group.origin {
+                DeployKind::Objective => (),
+                DeployKind::Action { spec, .. } => {
+                    t.actions.insert_cow(*gid);
+                    if let ActionKind::Drone(_) = &spec.kind {
+                        t.jtacs.insert_cow(*gid);
+                    }
+                }
+                DeployKind::Crate { player, .. } => {
+                    t.crates.insert_cow(*gid);
+                    t.player_crates
+                        .get_or_default_cow(*player)
+                        .insert_cow(*gid);
+                }
+                DeployKind::Deployed { spec, .. } => {
+                    t.deployed.insert_cow(*gid);
+                    if spec.jtac.is_some() {
+                        t.jtacs.insert_cow(*gid);
+                    }
+                    if spec.ewr.is_some() {
+                        t.ewrs.insert_cow(*gid);
+                    }
+                }
+                DeployKind::Troop { spec, .. } => {
+                    t.troops.insert_cow(*gid);
+                    if spec.jtac.is_some() {
+                        t.jtacs.insert_cow(*gid);
+                    }
+                }
+            }
+        }
+        for (uid, unit) in &p.units {
+            t.units_by_name.insert_cow(unit.name.clone(), *uid);
+        }
+        for (oid, obj) in &p.objectives {
+            t.objectives_by_name.insert_cow(obj.name.clone(), *oid);
+            if obj.kind.is_farp() {
+                t.farps.insert_cow(*oid);
+            }
+            for (_, groups) in &obj.groups {
+                for gid in groups {
+                    t.objectives_by_group.insert_cow(*gid, *oid);
+                }
+            }
+        }
+        t
+    }
+}
+
+fn check_map<K, V>(
+    problems: &mut Vec<CompactString>,
+    name: &str,
+    actual: &Map<K, V>,
+    expected: &Map<K, V>,
+) where
+    K: Ord + Clone + Debug,
+    V: Clone + Debug + PartialEq,
+{
+    for (k, v) in expected {
+        match actual.get(k) {
+            None => problems.push(format_compact!("{name} is missing {k:?}")),
+            Some(a) if a != v => problems.push(format_compact!(
+                "{name} maps {k:?} to {a:?}, expected {v:?}"
+            )),
+            Some(_) => (),
+        }
+    }
+    for (k, _) in actual {
+        if expected.get(k).is_none() {
+            problems.push(format_compact!("{name} has a dangling entry {k:?}"))
+        }
+    }
+}
+
+fn check_set<K>(problems: &mut Vec<CompactString>, name: &str, actual: &Set<K>, expected: &Set<K>)
+where
+    K: Ord + Clone + Debug,
+{
+    for k in expected {
+        if !actual.contains(k) {
+            problems.push(format_compact!("{name} is missing {k:?}"))
+        }
+    }
+    for k in actual {
+        if !expected.contains(k) {
+            problems.push(format_compact!("{name} has a dangling entry {k:?}"))
+        }
+    }
+}
+
+impl Persisted {
+    /// Check that every reference in the db points to something that
+    /// exists, and that every derived index agrees with the primary
+    /// maps. Returns a description of each problem found.
+    pub fn check_integrity(&self) -> Vec<CompactString> {
+        let mut problems = vec![];
+        for (gid, group) in &self.groups {
+            if &group.id != gid {
+                problems.push(format_compact!("group {gid} has id {}", group.id))
+            }
+            for uid in &group.units {
+                match self.units.get(uid) {
+                    None => problems.push(format_compact!("group {gid} has missing unit {uid}")),
+                    Some(unit) if unit.group != *gid => problems.push(format_compact!(
+                        "group {gid} has unit {uid} which belongs to group {}",
+                        unit.group
+                    )),
+                    Some(_) => (),
+                }
+            }
+            if let DeployKind::Crate { player, .. } = &group.origin {
+                if self.players.get(player).is_none() {
+                    problems.push(format_compact!("crate {gid} belongs to missing player {player}"))
+                }
+            }
+        }
+        for (uid, unit) in &self.units {
+            if &unit.id != uid {
+                problems.push(format_compact!("unit {uid} has id {}", unit.id))
+            }
+            match self.groups.get(&unit.group) {
+                None => problems.push(format_compact!(
+                    "unit {uid} belongs to missing group {}",
+                    unit.group
+                )),
+                Some(group) if !group.units.contains(uid) => problems.push(format_compact!(
+                    "unit {uid} is not in its group {}",
+                    unit.group
+                )),
+                Some(_) => (),
+            }
+        }
+        for (oid, obj) in &self.objectives {
+            for (_, groups) in &obj.groups {
+                for gid in groups {
+                    if self.groups.get(gid).is_none() {
+                        problems.push(format_compact!("objective {oid} has missing group {gid}"))
+                    }
+                }
+            }
+        }
+        for oid in &self.logistics_hubs {
+            if self.objectives.get(oid).is_none() {
+                problems.push(format_compact!("logistics hub {oid} is missing"))
+            }
+        }
+        let d = Derived::new(self);
+        let p = &mut problems;
+        check_map(p, "groups_by_name", &self.groups_by_name, &d.groups_by_name);
+        check_map(p, "units_by_name", &self.units_by_name, &d.units_by_name);
+        check_map(p, "objectives_by_name", &self.objectives_by_name, &d.objectives_by_name);
+        check_map(p, "objectives_by_group", &self.objectives_by_group, &d.objectives_by_group);
+        for side in Side::ALL {
+            let empty = Set::new();
+            let actual = self.groups_by_side.get(&side).unwrap_or(&empty);
+            let expected = d.groups_by_side.get(&side).unwrap_or(&empty);
+            check_set(p, &format!("groups_by_side[{side}]"), actual, expected);
+        }
+        check_set(p, "farps", &self.farps, &d.farps);
+        check_set(p, "deployed", &self.deployed, &d.deployed);
+        check_set(p, "troops", &self.troops, &d.troops);
+        check_set(p, "crates", &self.crates, &d.crates);
+        check_set(p, "jtacs", &self.jtacs, &d.jtacs);
+        check_set(p, "ewrs", &self.ewrs, &d.ewrs);
+        check_set(p, "actions", &self.actions, &d.actions);
+        for (ucid, player) in &self.players {
+            let empty = Set::new();
+            let expected = d.player_crates.get(ucid).unwrap_or(&empty);
+            check_set(p, &format!("player {ucid} crates"), &player.crates, expected);
+        }
+        problems
+    }
+
+    /// Drop references to things that don't exist, and rebuild all the
+    /// derived indexes from the primary maps.
+    pub fn rebuild_indexes(&mut self) {
+        let dangling_units = self
+            .units
+            .into_iter()
+            .filter(|(_, u)| self.groups.get(&u.group).is_none())
+            .map(|(uid, _)| *uid)
+            .collect::<Vec<_>>();
+        for uid in dangling_units {
+            self.units.remove_cow(&uid);
+        }
+        let units = self.units.clone();
+        for (_, group) in self.groups.iter_mut_cow() {
+            let missing = group
+                .units
+                .into_iter()
+                .filter(|uid| units.get(uid).is_none())
+                .copied()
+                .collect::<Vec<_>>();
+            for uid in missing {
+                group.units.remove_cow(&uid);
+            }
+        }
+        let orphaned_crates = self
+            .groups
+            .into_iter()
+            .filter(|(_, g)| match &g.origin {
+                DeployKind::Crate { player, .. } => self.players.get(player).is_none(),
+                _ => false,
+            })
+            .map(|(gid, _)| *gid)
+            .collect::<Vec<_>>();
+        for gid in orphaned_crates {
+            if let Some(group) = self.groups.remove_cow(&gid) {
+                for uid in &group.units {
+                    self.units.remove_cow(uid);
+                }
+            }
+        }
+        let groups = self.groups.clone();
+        for (_, obj) in self.objectives.iter_mut_cow() {
+            for (_, set) in obj.groups.iter_mut_cow() {
+                let missing = set
+                    .into_iter()
+                    .filter(|gid| groups.get(gid).is_none())
+                    .copied()
+                    .collect::<Vec<_>>();
+                for gid in missing {
+                    set.remove_cow(&gid);
+                }
+            }
+        }
+        let objectives = self.objectives.clone();
+        let missing_hubs = self
+            .logistics_hubs
+            .into_iter()
+            .filter(|oid| objectives.get(oid).is_none())
+            .copied()
+            .collect::<Vec<_>>();
+        for oid in missing_hubs {
+            self.logistics_hubs.remove_cow(&oid);
+        }
+        let d = Derived::new(self);
+        self.groups_by_name = d.groups_by_name;
+        self.units_by_name = d.units_by_name;
+        self.groups_by_side = d.groups_by_side;
+        self.objectives_by_name = d.objectives_by_name;
+        self.objectives_by_group = d.objectives_by_group;
+        self.farps = d.farps;
+        self.deployed = d.deployed;
+        self.troops = d.troops;
+        self.crates = d.crates;
+        self.jtacs = d.jtacs;
+        self.ewrs = d.ewrs;
+        self.actions = d.actions;
+        for (ucid, player) in self.players.iter_mut_cow() {
+            player.crates = d.player_crates.get(ucid).cloned().unwrap_or_else(Set::new);
+        }
+    }
+}
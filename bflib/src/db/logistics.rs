@@ -327,10 +327,11 @@ impl Db {
                     airbase
                         .auto_capture(false)
                         .context("setting airbase autocapture")?;
-                    let oid = self.persisted.objectives.into_iter().find(|(_, obj)| {
-                        let radius2 = obj.radius.powi(2);
-                        na::distance_squared(&pos.into(), &obj.pos.into()) <= radius2
-                    });
+                    let oid = self
+                        .persisted
+                        .objectives
+                        .into_iter()
+                        .find(|(_, obj)| obj.contains(pos));
                     let w = airbase
                         .get_warehouse()
                         .context("getting airbase warehouse")?;
@@ -15,15 +15,15 @@ for more details.
 */
 
 use super::{
-    objective::{Objective, ObjectiveKind},
+    objective::{Objective, ObjectiveKind, ObjectiveShape},
     persisted::Persisted,
 };
 use crate::{cfg::Cfg, msgq::MsgQ};
 use compact_str::format_compact;
 use dcso3::{
     coalition::Side,
-    trigger::{ArrowSpec, CircleSpec, LineType, MarkId, RectSpec, SideFilter, TextSpec},
-    Color, LuaVec3, Vector3,
+    trigger::{ArrowSpec, CircleSpec, LineType, MarkId, QuadSpec, RectSpec, SideFilter, TextSpec},
+    Color, LuaVec2, LuaVec3, String, Vector3,
 };
 use smallvec::SmallVec;
 
@@ -31,6 +31,8 @@ use smallvec::SmallVec;
 pub(super) struct ObjectiveMarkup {
     side: Side,
     threatened: bool,
+    capturable: bool,
+    capture: Option<(Side, u8)>,
     health: u8,
     logi: u8,
     supply: u8,
@@ -39,6 +41,7 @@ pub(super) struct ObjectiveMarkup {
     capturable_ring: MarkId,
     threatened_ring: MarkId,
     name: MarkId,
+    capture_label: MarkId,
     health_label: MarkId,
     healthbar: [MarkId; 5],
     logi_label: MarkId,
@@ -58,11 +61,23 @@ fn text_color(side: Side, a: f32) -> Color {
     }
 }
 
+fn capture_text(capture: Option<(Side, u8)>) -> (String, Color) {
+    match capture {
+        None => (String::from(""), Color::white(0.)),
+        Some((side, pct)) => (
+            format_compact!("{side} capturing {pct}%").into(),
+            text_color(side, 1.),
+        ),
+    }
+}
+
 impl ObjectiveMarkup {
     pub(super) fn remove(self, msgq: &mut MsgQ) {
         let ObjectiveMarkup {
             side: _,
             threatened: _,
+            capturable: _,
+            capture: _,
             health: _,
             logi: _,
             supply: _,
@@ -71,6 +86,7 @@ impl ObjectiveMarkup {
             capturable_ring,
             threatened_ring,
             name,
+            capture_label,
             health_label,
             healthbar,
             logi_label,
@@ -85,6 +101,7 @@ impl ObjectiveMarkup {
         msgq.delete_mark(threatened_ring);
         msgq.delete_mark(capturable_ring);
         msgq.delete_mark(name);
+        msgq.delete_mark(capture_label);
         msgq.delete_mark(health_label);
         for id in healthbar {
             msgq.delete_mark(id)
@@ -106,7 +123,13 @@ impl ObjectiveMarkup {
         }
     }
 
-    pub(super) fn update(&mut self, msgq: &mut MsgQ, obj: &Objective) {
+    pub(super) fn update(
+        &mut self,
+        msgq: &mut MsgQ,
+        obj: &Objective,
+        capturable: bool,
+        capture: Option<(Side, u8)>,
+    ) {
         if obj.owner != self.side {
             let text_color = |a| text_color(obj.owner, a);
             self.side = obj.owner;
@@ -145,12 +168,21 @@ impl ObjectiveMarkup {
             self.health = obj.health;
             update_bar!(healthbar, health);
         }
-        if self.logi != obj.logi {
-            self.logi = obj.logi;
+        if self.capturable != capturable {
+            self.capturable = capturable;
             msgq.set_markup_color(
                 self.capturable_ring,
-                Color::white(if obj.captureable() { 0.75 } else { 0. }),
+                Color::white(if capturable { 0.75 } else { 0. }),
             );
+        }
+        if self.capture != capture {
+            self.capture = capture;
+            let (text, color) = capture_text(capture);
+            msgq.set_markup_text(self.capture_label, text);
+            msgq.set_markup_color(self.capture_label, color);
+        }
+        if self.logi != obj.logi {
+            self.logi = obj.logi;
             update_bar!(logibar, logi);
         }
         if self.supply != obj.supply {
@@ -163,7 +195,14 @@ impl ObjectiveMarkup {
         }
     }
 
-    pub(super) fn new(cfg: &Cfg, msgq: &mut MsgQ, obj: &Objective, persisted: &Persisted) -> Self {
+    pub(super) fn new(
+        cfg: &Cfg,
+        msgq: &mut MsgQ,
+        obj: &Objective,
+        persisted: &Persisted,
+        capturable: bool,
+        capture: Option<(Side, u8)>,
+    ) -> Self {
         let text_color = |a| text_color(obj.owner, a);
         let all_spec = match obj.kind {
             ObjectiveKind::Airbase | ObjectiveKind::Fob | ObjectiveKind::Logistics => {
@@ -215,24 +254,53 @@ impl ObjectiveMarkup {
         let mut t = ObjectiveMarkup::default();
         t.side = obj.owner;
         t.threatened = obj.threatened;
+        t.capturable = capturable;
+        t.capture = capture;
         t.health = obj.health;
         t.logi = obj.logi;
         t.supply = obj.supply;
         t.fuel = obj.fuel;
         let mut pos3 = Vector3::new(obj.pos.x, 0., obj.pos.y);
-        msgq.circle_to_all(
-            all_spec,
-            t.owner_ring,
-            CircleSpec {
-                center: LuaVec3(pos3),
-                radius: obj.radius,
-                color: text_color(1.),
-                fill_color: Color::white(0.),
-                line_type: LineType::Dashed,
-                read_only: true,
-            },
-            None,
-        );
+        // draw the boundary of the objective scaled by factor
+        let outline = |msgq: &mut MsgQ,
+                       id: MarkId,
+                       factor: f64,
+                       color: Color,
+                       line_type: LineType| match &obj.shape {
+            ObjectiveShape::Circle => msgq.circle_to_all(
+                all_spec,
+                id,
+                CircleSpec {
+                    center: LuaVec3(pos3),
+                    radius: obj.radius * factor,
+                    color,
+                    fill_color: Color::white(0.),
+                    line_type,
+                    read_only: true,
+                },
+                None,
+            ),
+            ObjectiveShape::Quad(quad) => {
+                let quad = quad.scale(factor);
+                let v = |p: LuaVec2| LuaVec3(Vector3::new(p.x, 0., p.y));
+                msgq.quad_to_all(
+                    all_spec,
+                    id,
+                    QuadSpec {
+                        p0: v(quad.p0),
+                        p1: v(quad.p1),
+                        p2: v(quad.p2),
+                        p3: v(quad.p3),
+                        color,
+                        fill_color: Color::white(0.),
+                        line_type,
+                        read_only: true,
+                    },
+                    None,
+                )
+            }
+        };
+        outline(msgq, t.owner_ring, 1., text_color(1.), LineType::Dashed);
         msgq.circle_to_all(
             all_spec,
             t.threatened_ring,
@@ -246,18 +314,12 @@ impl ObjectiveMarkup {
             },
             None,
         );
-        msgq.circle_to_all(
-            all_spec,
+        outline(
+            msgq,
             t.capturable_ring,
-            CircleSpec {
-                center: LuaVec3(pos3),
-                radius: obj.radius as f64 * 0.9,
-                color: Color::white(if obj.captureable() { 0.75 } else { 0. }),
-                fill_color: Color::white(0.),
-                line_type: LineType::Solid,
-                read_only: true,
-            },
-            None,
+            0.9,
+            Color::white(if capturable { 0.75 } else { 0. }),
+            LineType::Solid,
         );
         msgq.text_to_all(
             all_spec,
@@ -271,6 +333,19 @@ impl ObjectiveMarkup {
                 text: format_compact!("{} {}", obj.name, obj.kind.name()).into(),
             },
         );
+        let (text, color) = capture_text(capture);
+        msgq.text_to_all(
+            all_spec,
+            t.capture_label,
+            TextSpec {
+                pos: LuaVec3(Vector3::new(pos3.x + 500., 1., pos3.z + 1500.)),
+                color,
+                fill_color: Color::black(0.),
+                font_size: 14,
+                read_only: true,
+                text,
+            },
+        );
         pos3.x += 5000.;
         pos3.z -= 5000.;
         bar_with_label(
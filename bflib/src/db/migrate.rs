@@ -0,0 +1,198 @@
+/*
+Copyright 2024 Eric Stokes.
+
+This file is part of bflib.
+
+bflib is free software: you can redistribute it and/or modify it under
+the terms of the GNU Affero Public License as published by the Free
+Software Foundation, either version 3 of the License, or (at your
+option) any later version.
+
+bflib is distributed in the hope that it will be useful, but WITHOUT
+ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero Public License
+for more details.
+*/
+
+//! Save files carry the schema version they were written with. Old
+//! saves are upgraded as json, one step at a time, before they are
+//! deserialized, so a change to the shape of `Persisted` doesn't break
+//! existing campaigns as long as a migration step is added for it.
+
+use anyhow::{anyhow, bail, Result};
+use fxhash::{FxHashMap, FxHashSet};
+use serde_json::{Map, Value};
+use std::collections::hash_map::Entry;
+
+/// The schema version written by this build. When making a change to
+/// `Persisted` (or anything in it) that serde can't absorb on its own,
+/// bump this and add a step to `MIGRATIONS`.
+pub const SCHEMA_VERSION: u64 = 1;
+
+type Migration = fn(&mut Map<String, Value>) -> Result<()>;
+
+/// `MIGRATIONS[n]` upgrades a version `n` save to version `n + 1`
+const MIGRATIONS: [Migration; SCHEMA_VERSION as usize] = [v0_to_v1];
+
+/// Saves written before the schema was versioned. Fill in the fields
+/// that were added with serde defaults so later steps can rely on them.
+fn v0_to_v1(save: &mut Map<String, Value>) -> Result<()> {
+    for set in ["actions", "logistics_hubs"] {
+        save.entry(set).or_insert_with(|| Value::Array(vec![]));
+    }
+    for n in ["nukes_used", "logistics_ticks_since_delivery"] {
+        save.entry(n).or_insert_with(|| Value::from(0));
+    }
+    Ok(())
+}
+
+pub fn version(save: &Value) -> Result<u64> {
+    match save.get("version") {
+        None => Ok(0),
+        Some(v) => v
+            .as_u64()
+            .ok_or_else(|| anyhow!("invalid save schema version {v}")),
+    }
+}
+
+/// upgrade save to `SCHEMA_VERSION`
+pub fn migrate(save: &mut Value) -> Result<()> {
+    let version = version(save)?;
+    if version > SCHEMA_VERSION {
+        bail!(
+            "the save file is schema version {version}, but this build only understands \
+             versions up to {SCHEMA_VERSION}. Upgrade bflib to load it."
+        )
+    }
+    let obj = save
+        .as_object_mut()
+        .ok_or_else(|| anyhow!("the save file is not a json object"))?;
+    for (n, step) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        step(obj).map_err(|e| anyhow!("migrating save from version {n} to {}, {e:?}", n + 1))?;
+    }
+    obj.insert("version".into(), Value::from(SCHEMA_VERSION));
+    Ok(())
+}
+
+fn key_string(k: &Value) -> String {
+    match k {
+        Value::String(s) => s.clone(),
+        k => k.to_string(),
+    }
+}
+
+/// Apply a journal delta to a save before it is migrated. Every delta
+/// in a journal was written by the same build that wrote the checkpoint
+/// it follows, so the delta and the save are always the same schema
+/// version.
+pub fn replay_delta(save: &mut Value, delta: Value) -> Result<()> {
+    let save = save
+        .as_object_mut()
+        .ok_or_else(|| anyhow!("the save file is not a json object"))?;
+    let delta = match delta {
+        Value::Object(delta) => delta,
+        _ => bail!("journal delta is not a json object"),
+    };
+    for (field, change) in delta {
+        let target = save.entry(field.clone()).or_insert(Value::Null);
+        match change {
+            Value::Object(mut change) if change.contains_key("upsert") => {
+                let upsert = change.remove("upsert").unwrap_or(Value::Null);
+                let remove = change.remove("remove").unwrap_or(Value::Null);
+                replay_map(target, upsert, remove)
+                    .map_err(|e| anyhow!("replaying {field}, {e:?}"))?
+            }
+            Value::Object(mut change) if change.contains_key("add") => {
+                let add = change.remove("add").unwrap_or(Value::Null);
+                let remove = change.remove("remove").unwrap_or(Value::Null);
+                replay_set(target, add, remove).map_err(|e| anyhow!("replaying {field}, {e:?}"))?
+            }
+            v => *target = v,
+        }
+    }
+    Ok(())
+}
+
+fn elts(v: Value) -> Result<Vec<Value>> {
+    match v {
+        Value::Array(a) => Ok(a),
+        Value::Null => Ok(vec![]),
+        v => bail!("expected an array, got {v}"),
+    }
+}
+
+fn replay_map(target: &mut Value, upsert: Value, remove: Value) -> Result<()> {
+    if target.is_null() {
+        *target = Value::Object(Map::new());
+    }
+    match target {
+        Value::Object(map) => {
+            for k in elts(remove)? {
+                map.remove(&key_string(&k));
+            }
+            for kv in elts(upsert)? {
+                match elts(kv)?.as_mut_slice() {
+                    [k, v] => {
+                        map.insert(key_string(k), v.take());
+                    }
+                    _ => bail!("invalid map delta entry"),
+                }
+            }
+        }
+        // maps with complex keys are written as a list of pairs. Value
+        // isn't hashable, so index the keys by their json text, which
+        // is canonical because object fields are sorted.
+        Value::Array(pairs) => {
+            let upsert = elts(upsert)?;
+            let mut stale = elts(remove)?
+                .iter()
+                .map(Value::to_string)
+                .collect::<FxHashSet<_>>();
+            stale.extend(
+                upsert
+                    .iter()
+                    .filter_map(|kv| kv.get(0))
+                    .map(Value::to_string),
+            );
+            pairs.retain(|kv| kv.get(0).map_or(true, |k| !stale.contains(&k.to_string())));
+            let mut added: FxHashMap<String, usize> = FxHashMap::default();
+            for kv in upsert {
+                match kv.get(0).map(Value::to_string) {
+                    None => pairs.push(kv),
+                    Some(k) => match added.entry(k) {
+                        Entry::Occupied(e) => pairs[*e.get()] = kv,
+                        Entry::Vacant(e) => {
+                            e.insert(pairs.len());
+                            pairs.push(kv)
+                        }
+                    },
+                }
+            }
+        }
+        v => bail!("expected a map, got {v}"),
+    }
+    Ok(())
+}
+
+fn replay_set(target: &mut Value, add: Value, remove: Value) -> Result<()> {
+    if target.is_null() {
+        *target = Value::Array(vec![]);
+    }
+    match target {
+        Value::Array(set) => {
+            let remove = elts(remove)?
+                .iter()
+                .map(Value::to_string)
+                .collect::<FxHashSet<_>>();
+            set.retain(|v| !remove.contains(&v.to_string()));
+            let mut present = set.iter().map(Value::to_string).collect::<FxHashSet<_>>();
+            for v in elts(add)? {
+                if present.insert(v.to_string()) {
+                    set.push(v)
+                }
+            }
+        }
+        v => bail!("expected a set, got {v}"),
+    }
+    Ok(())
+}
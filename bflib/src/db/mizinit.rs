@@ -15,13 +15,13 @@ for more details.
 */
 
 use super::{
-    ephemeral::SlotInfo, group::{DeployKind, GroupId}, objective::ObjGroup, Db, Map
+    ephemeral::SlotInfo, group::{DeployKind, GroupId}, objective::ObjGroup, squadron, Db, Map
 };
 use crate::{
     cfg::{Cfg, Vehicle}, db::{
         logistics::Warehouse,
-        objective::{Objective, ObjectiveId, ObjectiveKind},
-    }, group, landcache::LandCache, maybe, objective, objective_mut, perf::PerfInner, spawnctx::{SpawnCtx, SpawnLoc}
+        objective::{Objective, ObjectiveId, ObjectiveKind, ObjectiveShape},
+    }, group, landcache::LandCache, maybe, objective, objective_mut, perf::PerfInner, spawnctx::{SpawnCtx, SpawnLoc}, stats::StatKind
 };
 use anyhow::{anyhow, bail, Context, Result};
 use chrono::prelude::*;
@@ -29,9 +29,10 @@ use compact_str::CompactString;
 use dcso3::{
     coalition::Side,
     controller::PointType,
+    coord::Coord,
     env::miz::{Group, Miz, MizIndex, Skill, TriggerZone, TriggerZoneTyp},
     land::Land,
-    LuaVec2, MizLua, String, Vector2, Vector3,
+    LuaVec2, LuaVec3, MizLua, String, Vector2, Vector3,
 };
 use enumflags2::BitFlags;
 use fxhash::FxHashSet;
@@ -83,11 +84,18 @@ impl Db {
             bail!("invalid objective type for {name}, expected AB, FO, of LO")
         };
         let id = ObjectiveId::new();
-        let radius = match zone.typ()? {
-            TriggerZoneTyp::Quad(_) => bail!("zone volume type quad isn't supported yet"),
-            TriggerZoneTyp::Circle { radius } => radius,
+        let (pos, radius, shape) = match zone.typ()? {
+            TriggerZoneTyp::Circle { radius } => (zone.pos()?, radius, ObjectiveShape::Circle),
+            TriggerZoneTyp::Quad(quad) => {
+                let pos = quad.center();
+                let radius = quad
+                    .points()
+                    .into_iter()
+                    .map(|p| na::distance(&pos.into(), &p.into()))
+                    .fold(0., f64::max);
+                (pos, radius, ObjectiveShape::Quad(quad))
+            }
         };
-        let pos = zone.pos()?;
         let obj = Objective {
             id,
             spawned: false,
@@ -95,6 +103,7 @@ impl Db {
             threatened: false,
             pos,
             radius,
+            shape,
             name: name.clone(),
             kind,
             owner,
@@ -104,6 +113,7 @@ impl Db {
             supply: 0,
             fuel: 0,
             last_change_ts: Utc::now(),
+            captured_ts: Utc::now(),
             last_threatened_ts: Utc::now(),
             warehouse: Warehouse::default(),
             last_activate: DateTime::<Utc>::default(),
@@ -139,8 +149,7 @@ impl Db {
                 match iter.next() {
                     None => bail!("group {:?} isn't associated with an objective", name),
                     Some((id, obj)) => {
-                        if na::distance_squared(&pos.into(), &obj.pos.into()) <= obj.radius.powi(2)
-                        {
+                        if obj.contains(pos) {
                             break *id;
                         }
                     }
@@ -169,6 +178,7 @@ impl Db {
     }
 
     pub fn init_objective_slots(&mut self, side: Side, slot: Group) -> Result<()> {
+        let squadron = squadron::reserved_for(&slot.name()?);
         let mut ground_start = false;
         for point in slot.route()?.points()? {
             let point = point?;
@@ -206,7 +216,7 @@ impl Db {
                             return Ok(());
                         }
                         Some((id, obj)) => {
-                            if na::distance(&pos.into(), &obj.pos.into()) <= obj.radius {
+                            if obj.contains(pos) {
                                 break *id;
                             }
                         }
@@ -234,6 +244,7 @@ impl Db {
                     ground_start,
                     miz_gid: slot.id()?,
                     side,
+                    squadron: squadron.clone(),
                 },
             );
         }
@@ -260,6 +271,8 @@ impl Db {
                 t.init_objective(zone, name)?
             }
         }
+        let adjacent = t.objective_adjacency(&t.ephemeral.cfg, miz)?;
+        t.ephemeral.adjacent = adjacent;
         for side in Side::ALL {
             let coa = miz.coalition(side)?;
             for zone in miz.triggers()? {
@@ -295,6 +308,19 @@ impl Db {
             .into_iter()
             .map(|(id, _)| *id)
             .collect::<Vec<_>>();
+        t.ephemeral.stat(StatKind::NewRound);
+        let coord = Coord::singleton(lua)?;
+        for id in &ids {
+            let obj = objective!(t, *id)?;
+            let pos = coord.lo_to_ll(LuaVec3(Vector3::new(obj.pos.x, 0., obj.pos.y)))?;
+            let (owner, kind) = (obj.owner, obj.kind.clone());
+            t.ephemeral.stat(StatKind::Objective {
+                id: *id,
+                pos,
+                owner,
+                kind,
+            });
+        }
         for id in ids {
             t.update_objective_status(&id, now)?
         }
@@ -15,7 +15,7 @@ for more details.
 */
 
 extern crate nalgebra as na;
-use self::{group::DeployKind, persisted::Persisted};
+use self::{group::DeployKind, objective::ObjectiveKind, persisted::Persisted};
 use crate::{
     cfg::{
         Action, ActionKind, AwacsCfg, Cfg, Deployable, DeployableEwr, DeployableJtac, DroneCfg,
@@ -24,25 +24,31 @@ use crate::{
     db::ephemeral::Ephemeral,
     jtac::JtId,
 };
-use anyhow::{anyhow, Result};
+use anyhow::{bail, Result};
+use log::{error, info, warn};
 use dcso3::{
     centroid3d,
     coalition::Side,
     env::miz::{Miz, MizIndex},
-    Vector3,
+    String, Vector3,
 };
-use std::{fs::File, path::Path};
+use std::path::Path;
 
 pub mod actions;
 pub mod cargo;
 pub mod ephemeral;
+pub mod frontline;
 pub mod group;
+pub mod integrity;
 pub mod logistics;
 pub mod markup;
+pub mod migrate;
 pub mod mizinit;
 pub mod objective;
 pub mod persisted;
 pub mod player;
+pub mod squadron;
+pub mod victory;
 
 pub type Map<K, V> = immutable_chunkmap::map::Map<K, V, 256>;
 pub type Set<K> = immutable_chunkmap::set::Set<K, 256>;
@@ -175,19 +181,103 @@ pub struct Db {
 
 impl Db {
     pub fn load(miz: &Miz, idx: &MizIndex, path: &Path) -> Result<Self> {
-        let file = File::open(&path)
-            .map_err(|e| anyhow!("failed to open save file {:?}, {:?}", path, e))?;
-        let file = zstd::stream::Decoder::new(file)?;
-        let persisted: Persisted = serde_json::from_reader(file)
-            .map_err(|e| anyhow!("failed to decode save file {:?}, {:?}", path, e))?;
+        let mut persisted = Persisted::load(path)?;
+        let cfg = Cfg::load(path)?;
+        let problems = persisted.check_integrity();
+        for problem in &problems {
+            warn!("integrity check: {problem}")
+        }
+        if !problems.is_empty() && cfg.repair_indexes_on_load {
+            info!("rebuilding indexes to repair {} integrity problems", problems.len());
+            persisted.rebuild_indexes();
+            for problem in persisted.check_integrity() {
+                error!("integrity check after repair: {problem}")
+            }
+        }
         let mut db = Db {
             persisted,
             ephemeral: Ephemeral::default(),
         };
-        db.ephemeral.set_cfg(miz, idx, Cfg::load(path)?)?;
+        let adjacent = db.objective_adjacency(&cfg, miz)?;
+        db.ephemeral.set_cfg(miz, idx, cfg)?;
+        db.ephemeral.adjacent = adjacent;
         Ok(db)
     }
 
+    /// Check that cfg can replace the running config, and if it can
+    /// swap it in. Changes that would strand live objects, such as removing
+    /// a deployable that is currently deployed, are refused. On success
+    /// return the names of the sections that changed.
+    pub fn reload_cfg(&mut self, miz: &Miz, idx: &MizIndex, cfg: Cfg) -> Result<Vec<String>> {
+        let changed = self.ephemeral.cfg.changed_sections(&cfg)?;
+        if changed.is_empty() {
+            return Ok(changed);
+        }
+        let has_deployable = |side: &Side, name: &String| {
+            cfg.deployables
+                .get(side)
+                .map(|d| d.iter().any(|d| d.path.last() == Some(name)))
+                .unwrap_or(false)
+        };
+        for (_, group) in &self.persisted.groups {
+            match &group.origin {
+                DeployKind::Objective | DeployKind::Crate { .. } => (),
+                DeployKind::Deployed { spec, .. } => {
+                    if let Some(name) = spec.path.last() {
+                        if !has_deployable(&group.side, name) {
+                            bail!("can't remove deployable {name}, it has live instances")
+                        }
+                    }
+                }
+                DeployKind::Troop { spec, .. } => {
+                    let present = cfg
+                        .troops
+                        .get(&group.side)
+                        .map(|t| t.iter().any(|t| t.name == spec.name))
+                        .unwrap_or(false);
+                    if !present {
+                        bail!("can't remove troop {}, it has live instances", spec.name)
+                    }
+                }
+                DeployKind::Action { name, .. } => {
+                    let present = cfg
+                        .actions
+                        .get(&group.side)
+                        .map(|a| a.contains_key(name))
+                        .unwrap_or(false);
+                    if !present {
+                        bail!("can't remove action {name}, it has live instances")
+                    }
+                }
+            }
+        }
+        for (_, obj) in &self.persisted.objectives {
+            if let ObjectiveKind::Farp { spec, .. } = &obj.kind {
+                if let Some(name) = spec.path.last() {
+                    if !has_deployable(&obj.owner, name) {
+                        bail!("can't remove deployable {name}, farp {} uses it", obj.name)
+                    }
+                }
+            }
+        }
+        for (typ, lt) in &self.ephemeral.cfg.life_types {
+            match cfg.life_types.get(typ) {
+                None => bail!("can't remove the life type of {typ}, slots may be occupied"),
+                Some(lt) if !cfg.default_lives.contains_key(lt) => {
+                    bail!("life type {lt:?} of {typ} has no default lives")
+                }
+                Some(_) => (),
+            }
+            if !cfg.default_lives.contains_key(lt) {
+                bail!("can't remove the default lives of {lt:?}, players may hold them")
+            }
+        }
+        let adjacent = self.objective_adjacency(&cfg, miz)?;
+        self.ephemeral.set_cfg(miz, idx, cfg)?;
+        self.ephemeral.adjacent = adjacent;
+        Ok(changed)
+    }
+
     pub fn maybe_snapshot(&mut self) -> Option<Persisted> {
         if self.ephemeral.take_dirty() {
             Some(self.persisted.clone())
@@ -21,14 +21,16 @@ use super::{
     Db, Map, Set,
 };
 use crate::{
-    cfg::{Deployable, DeployableLogistics, UnitTag},
+    cfg::{Cfg, Deployable, DeployableLogistics, UnitTag},
     group, group_health, group_mut,
     landcache::LandCache,
+    ledger::Reason,
     maybe, objective, objective_mut,
     spawnctx::{Despawn, SpawnCtx, SpawnLoc},
+    stats::StatKind,
     unit, unit_mut,
 };
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use chrono::{prelude::*, Duration};
 use compact_str::format_compact;
 use dcso3::{
@@ -37,13 +39,13 @@ use dcso3::{
     coalition::Side,
     coord::Coord,
     cvt_err,
-    env::miz::{GroupKind, MizIndex},
+    env::miz::{GroupKind, Miz, MizIndex},
     group::Group,
     land::Land,
     net::Ucid,
     object::DcsObject,
     warehouse::LiquidType,
-    LuaVec2, LuaVec3, MizLua, String, Vector2, Vector3,
+    LuaVec2, LuaVec3, MizLua, Quad2, String, Vector2, Vector3,
 };
 use enumflags2::BitFlags;
 use fxhash::{FxHashMap, FxHashSet};
@@ -53,6 +55,15 @@ use serde_derive::{Deserialize, Serialize};
 use smallvec::{smallvec, SmallVec};
 use std::{cmp::max, str::FromStr, sync::Arc};
 
+/// The progress of a side toward capturing an objective
+#[derive(Debug, Clone, Copy)]
+pub(super) struct CaptureProgress {
+    pub(super) side: Side,
+    /// from 0 to 1, the objective is captured at 1
+    pub(super) progress: f32,
+    pub(super) last_ts: DateTime<Utc>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ObjectiveKind {
     Airbase,
@@ -221,12 +232,24 @@ impl ObjGroup {
 
 atomic_id!(ObjectiveId);
 
+/// The boundary of an objective. Circles are `pos` and `radius`, quads are
+/// drawn in the mission editor, in which case `pos` is their center and
+/// `radius` the distance to the farthest vertex.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub enum ObjectiveShape {
+    #[default]
+    Circle,
+    Quad(Quad2),
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Objective {
     pub id: ObjectiveId,
     pub name: String,
     pub(super) pos: Vector2,
     pub(super) radius: f64,
+    #[serde(default)]
+    pub(super) shape: ObjectiveShape,
     pub owner: Side,
     pub(super) kind: ObjectiveKind,
     pub(super) groups: Map<Side, Set<GroupId>>,
@@ -239,6 +262,9 @@ pub struct Objective {
     pub(super) threatened: bool,
     pub(super) last_threatened_ts: DateTime<Utc>,
     pub(super) last_change_ts: DateTime<Utc>,
+    /// when the current owner took the objective
+    #[serde(default = "Utc::now")]
+    pub(super) captured_ts: DateTime<Utc>,
     #[serde(default)]
     pub(super) warehouse: Warehouse,
     #[serde(skip)]
@@ -252,8 +278,24 @@ pub struct Objective {
 }
 
 impl Objective {
-    pub fn is_in_circle(&self, pos: Vector2) -> bool {
-        na::distance_squared(&self.pos.into(), &pos.into()) <= self.radius.powi(2)
+    pub fn contains(&self, pos: Vector2) -> bool {
+        match &self.shape {
+            ObjectiveShape::Circle => {
+                na::distance_squared(&self.pos.into(), &pos.into()) <= self.radius.powi(2)
+            }
+            ObjectiveShape::Quad(quad) => quad.contains(LuaVec2(pos)),
+        }
+    }
+
+    /// the distance from pos to the boundary of the objective, 0 if pos
+    /// is inside
+    pub fn distance(&self, pos: Vector2) -> f64 {
+        match &self.shape {
+            ObjectiveShape::Circle => {
+                (na::distance(&self.pos.into(), &pos.into()) - self.radius).max(0.)
+            }
+            ObjectiveShape::Quad(quad) => quad.distance(pos),
+        }
     }
 
     pub fn name(&self) -> &str {
@@ -341,6 +383,20 @@ impl Db {
         obj.map(|obj| (dist.sqrt(), azumith2d_to(obj.pos, pos), obj))
     }
 
+    /// returns the objective that matches the criteria whose boundary is
+    /// closest to the specified point, taking its shape into account
+    /// (distance to the boundary, 0 if the point is inside, objective)
+    pub fn objective_boundary_near_point<P: Fn(&Objective) -> bool>(
+        obj: &Map<ObjectiveId, Objective>,
+        pos: Vector2,
+        p: P,
+    ) -> Option<(f64, &Objective)> {
+        obj.into_iter()
+            .filter(|(_, obj)| p(obj))
+            .map(|(_, obj)| (obj.distance(pos), obj))
+            .min_by(|(d0, _), (d1, _)| d0.total_cmp(d1))
+    }
+
     fn compute_objective_status(&self, obj: &Objective) -> Result<(u8, u8)> {
         obj.groups
             .get(&obj.owner)
@@ -520,6 +576,7 @@ impl Db {
             },
             pos,
             radius: 2000.,
+            shape: ObjectiveShape::Circle,
             owner: side,
             health: 100,
             logi: 100,
@@ -531,6 +588,7 @@ impl Db {
             warehouse: Warehouse::default(),
             last_threatened_ts: now,
             last_change_ts: now,
+            captured_ts: now,
             last_activate: DateTime::<Utc>::default(),
             threat_pos3,
         };
@@ -581,6 +639,14 @@ impl Db {
             obj.health = health;
             obj.logi = logi;
             obj.last_change_ts = now;
+            let (supply, fuel) = (obj.supply, obj.fuel);
+            self.ephemeral.stat(StatKind::ObjectiveStatus {
+                id: *oid,
+                health,
+                logi,
+                supply,
+                fuel,
+            });
             (obj.kind.clone(), health, logi)
         };
         if let ObjectiveKind::Farp { .. } = &kind {
@@ -781,7 +847,6 @@ impl Db {
             }
             if !obj.spawned && spawn {
                 obj.spawned = true;
-                let radius2 = obj.radius.powi(2);
                 for gid in obj.groups.get(&obj.owner).unwrap_or(&Set::new()) {
                     let group = group!(self, gid)?;
                     let farp = obj.kind.is_farp();
@@ -789,7 +854,7 @@ impl Db {
                     if !farp && !services {
                         for uid in &group.units {
                             let unit = unit_mut!(self, uid)?;
-                            if na::distance_squared(&unit.pos.into(), &obj.pos.into()) > radius2 {
+                            if !obj.contains(unit.pos) {
                                 unit.pos = unit.spawn_pos;
                                 unit.position = unit.spawn_position;
                             }
@@ -948,25 +1013,157 @@ impl Db {
         Ok(())
     }
 
+    /// Build the objective adjacency graph from the links in the config
+    /// and the `adjacent` properties of the objective trigger zones in
+    /// the miz.
+    pub(super) fn objective_adjacency(
+        &self,
+        cfg: &Cfg,
+        miz: &Miz,
+    ) -> Result<FxHashMap<ObjectiveId, FxHashSet<ObjectiveId>>> {
+        let mut links: Vec<(String, String)> = vec![];
+        for (name, adj) in &cfg.adjacency {
+            for n in adj {
+                links.push((name.clone(), n.clone()))
+            }
+        }
+        for zone in miz.triggers()? {
+            let zone = zone?;
+            let name = zone.name()?;
+            if name.starts_with('O') && name.len() > 4 {
+                for prop in zone.properties()? {
+                    let prop = prop?;
+                    if prop.key.as_str() == "adjacent" {
+                        for n in prop.value.split(',') {
+                            let n = n.trim();
+                            if !n.is_empty() {
+                                links.push((String::from(&name[4..]), String::from(n)))
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        let mut adjacent: FxHashMap<ObjectiveId, FxHashSet<ObjectiveId>> = FxHashMap::default();
+        for (a, b) in links {
+            let lookup = |n: &String| match self.persisted.objectives_by_name.get(n) {
+                Some(oid) => Ok(*oid),
+                None => bail!("adjacency refers to unknown objective {n}"),
+            };
+            let (a, b) = (lookup(&a)?, lookup(&b)?);
+            if a != b {
+                adjacent.entry(a).or_default().insert(b);
+                adjacent.entry(b).or_default().insert(a);
+            }
+        }
+        Ok(adjacent)
+    }
+
     pub fn capturable_objectives(&self) -> SmallVec<[ObjectiveId; 1]> {
         let mut cap = smallvec![];
         for (oid, obj) in &self.persisted.objectives {
-            if obj.captureable() {
+            if self.ephemeral.capturable(&self.persisted, obj) {
                 cap.push(*oid)
             }
         }
         cap
     }
 
+    /// Advance side's capture of oid with troops present, warning the
+    /// owner when a capture starts. Return true if the capture is
+    /// complete. If timed capture isn't configured captures complete
+    /// immediately.
+    fn advance_capture(
+        &mut self,
+        oid: ObjectiveId,
+        side: Side,
+        troops: usize,
+        now: DateTime<Utc>,
+    ) -> Result<bool> {
+        let cfg = match self.ephemeral.cfg.capture {
+            None => return Ok(true),
+            Some(cfg) => cfg,
+        };
+        let time = max(cfg.min_time, cfg.time / max(troops as u32, 1)) as f32;
+        // set when side starts making progress, either at a fresh
+        // objective or after undoing the other side's progress
+        let mut started = false;
+        let p = self
+            .ephemeral
+            .capture_progress
+            .entry(oid)
+            .or_insert_with(|| {
+                started = true;
+                CaptureProgress {
+                    side,
+                    progress: 0.,
+                    last_ts: now,
+                }
+            });
+        let dt = (now - p.last_ts).num_milliseconds() as f32 / 1000.;
+        let step = if time == 0. { 1. } else { dt / time };
+        p.last_ts = now;
+        if p.side == side {
+            p.progress += step;
+        } else {
+            // the other side's progress has to be undone first
+            p.progress -= step;
+            if p.progress <= 0. {
+                p.side = side;
+                p.progress = 0.;
+                started = true;
+            }
+        }
+        let complete = p.progress >= 1.;
+        if complete {
+            self.ephemeral.capture_progress.remove(&oid);
+        }
+        if started {
+            let obj = objective!(self, oid)?;
+            let owner = obj.owner;
+            if owner != Side::Neutral && owner != side {
+                let msg = format_compact!("{} is being captured by the enemy!", obj.name);
+                self.ephemeral.msgs().panel_to_side(15, false, owner, msg);
+            }
+        }
+        Ok(complete)
+    }
+
+    /// Decay the capture progress at objectives where no troops are
+    /// present anymore
+    fn decay_capture_progress<T>(
+        &mut self,
+        present: &FxHashMap<ObjectiveId, T>,
+        now: DateTime<Utc>,
+    ) {
+        let decay_time = match self.ephemeral.cfg.capture {
+            None => return,
+            Some(cfg) => cfg.decay_time as f32,
+        };
+        self.ephemeral.capture_progress.retain(|oid, p| {
+            if present.contains_key(oid) {
+                return true;
+            }
+            let dt = (now - p.last_ts).num_milliseconds() as f32 / 1000.;
+            p.last_ts = now;
+            if decay_time == 0. {
+                false
+            } else {
+                p.progress -= dt / decay_time;
+                p.progress > 0.
+            }
+        })
+    }
+
     pub fn check_capture(
         &mut self,
         lua: MizLua,
         now: DateTime<Utc>,
     ) -> Result<SmallVec<[(Side, ObjectiveId); 1]>> {
-        let mut captured: FxHashMap<ObjectiveId, Vec<(Side, Ucid, GroupId)>> = FxHashMap::default();
+        let mut captured: FxHashMap<ObjectiveId, Vec<(Side, Ucid, GroupId, usize)>> =
+            FxHashMap::default();
         for (oid, obj) in &self.persisted.objectives {
             if obj.captureable() {
-                let r2 = obj.radius.powi(2);
                 for gid in &self.persisted.troops {
                     let group = group!(self, gid)?;
                     match &group.origin {
@@ -979,14 +1176,14 @@ impl Db {
                                 .units
                                 .into_iter()
                                 .filter_map(|uid| self.persisted.units.get(uid))
-                                .any(|u| {
-                                    na::distance_squared(&u.pos.into(), &obj.pos.into()) <= r2
-                                });
-                            if in_range {
+                                .filter(|u| !u.dead && obj.contains(u.pos))
+                                .count();
+                            if in_range > 0 {
                                 captured.entry(*oid).or_default().push((
                                     group.side,
                                     player.clone(),
                                     *gid,
+                                    in_range,
                                 ));
                             }
                         }
@@ -999,10 +1196,33 @@ impl Db {
                 }
             }
         }
+        self.decay_capture_progress(&captured, now);
         let mut actually_captured = smallvec![];
         for (oid, gids) in captured {
-            let (side, _, _) = gids.first().ok_or_else(|| anyhow!("no guid"))?;
-            if gids.iter().all(|(s, _, _)| side == s) {
+            let (side, _, _, _) = gids.first().ok_or_else(|| anyhow!("no guid"))?;
+            // troops of every side count toward contesting an objective,
+            // but a side can only start capturing one next to an
+            // objective it holds
+            let may_capture = objective!(self, oid)?.owner == *side
+                || self.ephemeral.adjacent_to(&self.persisted, &oid, *side)
+                || self
+                    .ephemeral
+                    .capture_progress
+                    .get(&oid)
+                    .map(|p| p.side == *side)
+                    .unwrap_or(false);
+            let complete = if may_capture && gids.iter().all(|(s, _, _, _)| side == s) {
+                let troops = gids.iter().map(|(_, _, _, n)| *n).sum();
+                self.advance_capture(oid, *side, troops, now)?
+            } else {
+                // contested, or held by a side that can't capture it,
+                // progress is paused until that changes
+                if let Some(p) = self.ephemeral.capture_progress.get_mut(&oid) {
+                    p.last_ts = now;
+                }
+                false
+            };
+            if complete {
                 let obj = objective_mut!(self, oid)?;
                 let name = obj.name.clone();
                 obj.spawned = false;
@@ -1010,6 +1230,7 @@ impl Db {
                 obj.last_threatened_ts = now;
                 obj.last_activate = now;
                 obj.owner = *side;
+                obj.captured_ts = now;
                 actually_captured.push((*side, oid));
                 for gid in obj.groups.get(&obj.owner).unwrap_or(&Set::new()) {
                     for uid in &group!(self, gid)?.units {
@@ -1046,19 +1267,40 @@ impl Db {
                 self.setup_supply_lines().context("setup supply lines")?;
                 self.deliver_supplies_from_logistics_hubs()
                     .context("delivering supplies")?;
+                let side = *side;
                 let mut ucids: SmallVec<[Ucid; 4]> = smallvec![];
-                for (_, ucid, gid) in gids {
+                for (_, ucid, gid, _) in gids {
                     self.delete_group(&gid)
                         .context("deleting capturing troops")?;
                     if !ucids.contains(&ucid) {
                         ucids.push(ucid);
                     }
                 }
-                if let Some(points) = self.ephemeral.cfg.points.as_ref() {
-                    let ppp = (points.capture as f32 / ucids.len() as f32).ceil() as i32;
-                    for ucid in ucids {
-                        self.adjust_points(&ucid, ppp, &format!("for capturing {name}"));
+                let ppp = self
+                    .ephemeral
+                    .cfg
+                    .points
+                    .as_ref()
+                    .map(|points| (points.capture as f32 / ucids.len() as f32).ceil() as i32);
+                for ucid in ucids {
+                    if let Some(stats) = self.round_stats_mut(&ucid) {
+                        stats.captures += 1
+                    }
+                    if let Some(ppp) = ppp {
+                        self.adjust_points(
+                            &ucid,
+                            ppp,
+                            Reason::Capture,
+                            Some(name.clone()),
+                            &format!("for capturing {name}"),
+                        );
                     }
+                    self.ephemeral.stat(StatKind::Capture {
+                        id: oid,
+                        ucid,
+                        side,
+                        points: ppp.unwrap_or(0) as usize,
+                    });
                 }
                 let obj = objective!(self, oid)?;
                 self.ephemeral.create_objective_markup(&self.persisted, obj);
@@ -1082,6 +1324,7 @@ impl Db {
         for (_, obj) in &self.persisted.objectives {
             self.ephemeral.update_objective_markup(&self.persisted, obj)
         }
+        self.ephemeral.update_front_line(&self.persisted);
         Ok(())
     }
 }
@@ -16,12 +16,23 @@ for more details.
 
 use super::{
     group::{GroupId, SpawnedGroup, SpawnedUnit, UnitId},
+    migrate,
     objective::{Objective, ObjectiveId},
     player::Player,
+    squadron::Squadron,
     Map, Set,
 };
+use anyhow::{anyhow, Context, Result};
 use dcso3::{coalition::Side, net::Ucid, String};
+use log::warn;
 use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+};
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Persisted {
@@ -42,6 +53,9 @@ pub struct Persisted {
     pub objectives_by_name: Map<String, ObjectiveId>,
     pub objectives_by_group: Map<GroupId, ObjectiveId>,
     pub players: Map<Ucid, Player>,
+    /// squadrons by tag
+    #[serde(default)]
+    pub squadrons: Map<String, Squadron>,
     #[serde(default)]
     pub logistics_hubs: Set<ObjectiveId>,
     #[serde(default)]
@@ -54,4 +68,192 @@ impl Persisted {
     pub fn players(&self) -> &Map<Ucid, Player> {
         &self.players
     }
+
+    /// the journal holds the chain of deltas written since the last
+    /// full checkpoint of the save file at path
+    pub fn journal_path(path: &Path) -> PathBuf {
+        let mut path = PathBuf::from(path);
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .map(|n| format!("{n}_JOURNAL"))
+            .unwrap_or_else(|| "JOURNAL".into());
+        path.set_file_name(name);
+        path
+    }
+
+    /// A journal is only ever appended to after its checkpoint is
+    /// written, so one that was last modified before the checkpoint
+    /// belongs to an older checkpoint, left behind by a crash.
+    fn journal_is_stale(path: &Path, journal: &File) -> bool {
+        let checkpoint = fs::metadata(path).and_then(|m| m.modified());
+        let journal = journal.metadata().and_then(|m| m.modified());
+        match (checkpoint, journal) {
+            (Ok(checkpoint), Ok(journal)) => journal < checkpoint,
+            (Err(_), _) | (_, Err(_)) => false,
+        }
+    }
+
+    /// load the checkpoint at path and replay its journal on top of it
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = File::open(&path)
+            .map_err(|e| anyhow!("failed to open save file {:?}, {:?}", path, e))?;
+        let file = zstd::stream::Decoder::new(file)?;
+        let mut save: Value = serde_json::from_reader(file)
+            .map_err(|e| anyhow!("failed to decode save file {:?}, {:?}", path, e))?;
+        let jpath = Self::journal_path(path);
+        match File::open(&jpath) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(anyhow!("failed to open journal {:?}, {:?}", jpath, e)),
+            Ok(journal) if Self::journal_is_stale(path, &journal) => {
+                warn!("ignoring journal {jpath:?}, it is older than the checkpoint")
+            }
+            Ok(journal) => {
+                // the journal is a series of independent zstd frames, one per
+                // delta. If the server died in the middle of an append the
+                // last frame will be truncated, in which case we stop at the
+                // last complete delta.
+                let journal = BufReader::new(zstd::stream::Decoder::new(journal)?);
+                for delta in serde_json::Deserializer::from_reader(journal).into_iter::<Value>() {
+                    match delta {
+                        Ok(delta) => migrate::replay_delta(&mut save, delta)?,
+                        Err(e) => {
+                            warn!("journal {:?} ends with an incomplete delta {:?}", jpath, e);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        migrate::migrate(&mut save).with_context(|| format!("loading {:?}", path))?;
+        serde_json::from_value(save)
+            .map_err(|e| anyhow!("failed to decode save file {:?}, {:?}", path, e))
+    }
+
+    /// the form of Persisted that is written to the save file
+    pub fn versioned(&self) -> Versioned {
+        Versioned {
+            version: migrate::SCHEMA_VERSION,
+            persisted: self,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Versioned<'a> {
+    version: u64,
+    #[serde(flatten)]
+    persisted: &'a Persisted,
+}
+
+/// The changes to a map between two snapshots. Entries present in both
+/// are compared by their serialized form, and only the ones that
+/// actually changed are written, so the size of a delta is
+/// proportional to the size of the change, not the size of the map.
+#[derive(Debug, Clone, Serialize)]
+pub struct MapDelta<K, V> {
+    upsert: Vec<(K, V)>,
+    remove: Vec<K>,
+}
+
+impl<K, V> MapDelta<K, V>
+where
+    K: Ord + Clone + fmt::Debug,
+    V: Clone + fmt::Debug + serde::Serialize,
+{
+    fn new(base: &Map<K, V>, cur: &Map<K, V>) -> Self {
+        let (mut buf0, mut buf1) = (vec![], vec![]);
+        let mut changed = |v0: &V, v1: &V| {
+            buf0.clear();
+            buf1.clear();
+            // if either fails to serialize it is written as changed
+            serde_json::to_writer(&mut buf0, v0).is_err()
+                || serde_json::to_writer(&mut buf1, v1).is_err()
+                || buf0 != buf1
+        };
+        let upsert = cur
+            .diff(base, |_, v, b| changed(v, b).then(|| v.clone()))
+            .into_iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        let remove = base
+            .diff(cur, |_, _, _| None)
+            .into_iter()
+            .map(|(k, _)| k.clone())
+            .collect();
+        Self { upsert, remove }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SetDelta<K> {
+    add: Vec<K>,
+    remove: Vec<K>,
+}
+
+impl<K> SetDelta<K>
+where
+    K: Ord + Clone + fmt::Debug,
+{
+    fn new(base: &Set<K>, cur: &Set<K>) -> Self {
+        Self {
+            add: cur.diff(base).into_iter().cloned().collect(),
+            remove: base.diff(cur).into_iter().cloned().collect(),
+        }
+    }
+}
+
+/// One journal record, the difference between two consecutive saves
+#[derive(Debug, Clone, Serialize)]
+pub struct PersistedDelta {
+    groups: MapDelta<GroupId, SpawnedGroup>,
+    units: MapDelta<UnitId, SpawnedUnit>,
+    groups_by_name: MapDelta<String, GroupId>,
+    units_by_name: MapDelta<String, UnitId>,
+    groups_by_side: MapDelta<Side, Set<GroupId>>,
+    deployed: SetDelta<GroupId>,
+    farps: SetDelta<ObjectiveId>,
+    crates: SetDelta<GroupId>,
+    troops: SetDelta<GroupId>,
+    jtacs: SetDelta<GroupId>,
+    ewrs: SetDelta<GroupId>,
+    actions: SetDelta<GroupId>,
+    objectives: MapDelta<ObjectiveId, Objective>,
+    objectives_by_name: MapDelta<String, ObjectiveId>,
+    objectives_by_group: MapDelta<GroupId, ObjectiveId>,
+    players: MapDelta<Ucid, Player>,
+    squadrons: MapDelta<String, Squadron>,
+    logistics_hubs: SetDelta<ObjectiveId>,
+    nukes_used: u32,
+    logistics_ticks_since_delivery: u32,
+}
+
+impl PersistedDelta {
+    pub fn new(base: &Persisted, cur: &Persisted) -> Self {
+        Self {
+            groups: MapDelta::new(&base.groups, &cur.groups),
+            units: MapDelta::new(&base.units, &cur.units),
+            groups_by_name: MapDelta::new(&base.groups_by_name, &cur.groups_by_name),
+            units_by_name: MapDelta::new(&base.units_by_name, &cur.units_by_name),
+            groups_by_side: MapDelta::new(&base.groups_by_side, &cur.groups_by_side),
+            deployed: SetDelta::new(&base.deployed, &cur.deployed),
+            farps: SetDelta::new(&base.farps, &cur.farps),
+            crates: SetDelta::new(&base.crates, &cur.crates),
+            troops: SetDelta::new(&base.troops, &cur.troops),
+            jtacs: SetDelta::new(&base.jtacs, &cur.jtacs),
+            ewrs: SetDelta::new(&base.ewrs, &cur.ewrs),
+            actions: SetDelta::new(&base.actions, &cur.actions),
+            objectives: MapDelta::new(&base.objectives, &cur.objectives),
+            objectives_by_name: MapDelta::new(&base.objectives_by_name, &cur.objectives_by_name),
+            objectives_by_group: MapDelta::new(
+                &base.objectives_by_group,
+                &cur.objectives_by_group,
+            ),
+            players: MapDelta::new(&base.players, &cur.players),
+            squadrons: MapDelta::new(&base.squadrons, &cur.squadrons),
+            logistics_hubs: SetDelta::new(&base.logistics_hubs, &cur.logistics_hubs),
+            nukes_used: cur.nukes_used,
+            logistics_ticks_since_delivery: cur.logistics_ticks_since_delivery,
+        }
+    }
 }
@@ -20,13 +20,15 @@ use super::{
     Db, Map, Set,
 };
 use crate::{
-    cfg::{LifeType, PointsCfg, UnitTag, Vehicle},
+    cfg::{LifeType, PointsCfg, TeamKillPenalty, UnitTag, Vehicle},
+    ledger::Reason,
     maybe, maybe_mut, objective_mut,
     shots::Dead,
+    stats::StatKind,
 };
 use anyhow::{anyhow, bail, Context, Result};
 use chrono::{prelude::*, Duration};
-use compact_str::format_compact;
+use compact_str::{format_compact, CompactString};
 use dcso3::{
     airbase::Airbase,
     coalition::Side,
@@ -38,7 +40,12 @@ use dcso3::{
 use log::{debug, error, info, warn};
 use serde_derive::{Deserialize, Serialize};
 use smallvec::{smallvec, SmallVec};
-use std::cmp::{max, min};
+use std::{
+    cmp::{max, min, Reverse},
+    fmt,
+    str::FromStr,
+    sync::Arc,
+};
 
 #[derive(Debug, Clone)]
 pub enum SlotAuth {
@@ -46,6 +53,10 @@ pub enum SlotAuth {
     ObjectiveNotOwned(Side),
     ObjectiveHasNoLogistics,
     NoLives(LifeType),
+    /// blocked from slots for team killing until
+    Blocked(DateTime<Utc>),
+    /// the slot is reserved for members of the squadron with this tag
+    Reserved(String),
     NotRegistered(Side),
     VehicleNotAvailable(Vehicle),
     Denied,
@@ -73,6 +84,91 @@ pub struct InstancedPlayer {
     pub moved: Option<DateTime<Utc>>,
 }
 
+/// What a player has done this round. Cleared along with everything
+/// else when the campaign is reset.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct RoundStats {
+    /// enemy units killed. If assists are configured only the last
+    /// hitter gets the kill, otherwise it counts for every shooter.
+    pub kills: u32,
+    /// enemy units killed by someone else after this player hit them
+    #[serde(default)]
+    pub assists: u32,
+    pub team_kills: u32,
+    pub captures: u32,
+    /// logistics repairs, supply transfers, crate unpacks, and troop
+    /// deployments
+    pub logistics: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Credit {
+    Kill,
+    Assist,
+}
+
+/// What a leaderboard is ranked by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ranking {
+    Points,
+    Kills,
+    Captures,
+    Logistics,
+}
+
+impl Ranking {
+    pub const ALL: [Ranking; 4] = [
+        Ranking::Points,
+        Ranking::Kills,
+        Ranking::Captures,
+        Ranking::Logistics,
+    ];
+}
+
+impl FromStr for Ranking {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "points" => Ok(Self::Points),
+            "kills" => Ok(Self::Kills),
+            "captures" => Ok(Self::Captures),
+            "logistics" => Ok(Self::Logistics),
+            x => bail!("unknown ranking {x}, expected points, kills, captures, or logistics"),
+        }
+    }
+}
+
+impl fmt::Display for Ranking {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Points => "points",
+            Self::Kills => "kills",
+            Self::Captures => "captures",
+            Self::Logistics => "logistics",
+        };
+        write!(f, "{s}")
+    }
+}
+
+/// A team kill that still counts toward the team kill penalties
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TeamKill {
+    pub time: DateTime<Utc>,
+    pub victim: Option<Ucid>,
+    /// the points deducted for the kill, returned if it is forgiven
+    pub points: u32,
+    /// the points deducted by the ladder steps the kill reached
+    #[serde(default)]
+    pub penalty_points: u32,
+    /// the slot block set by the ladder steps the kill reached
+    #[serde(default)]
+    pub slot_blocked_until: Option<DateTime<Utc>>,
+    /// the ban set by the ladder steps the kill reached
+    #[serde(default)]
+    pub banned_until: Option<DateTime<Utc>>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Player {
     pub name: String,
@@ -85,6 +181,16 @@ pub struct Player {
     pub airborne: Option<LifeType>,
     #[serde(default)]
     pub points: i32,
+    #[serde(default)]
+    pub stats: RoundStats,
+    /// team kills within the team kill window, oldest first
+    #[serde(default)]
+    pub recent_team_kills: Vec<TeamKill>,
+    #[serde(default)]
+    pub slot_blocked_until: Option<DateTime<Utc>>,
+    /// the tag of the player's squadron
+    #[serde(default)]
+    pub squadron: Option<String>,
     #[serde(skip)]
     pub current_slot: Option<(SlotId, Option<InstancedPlayer>)>,
     #[serde(skip)]
@@ -93,7 +199,43 @@ pub struct Player {
     pub jtac_or_spectators: bool,
 }
 
+impl Player {
+    pub fn ranked_by(&self, by: Ranking) -> i64 {
+        match by {
+            Ranking::Points => self.points as i64,
+            Ranking::Kills => self.stats.kills as i64,
+            Ranking::Captures => self.stats.captures as i64,
+            Ranking::Logistics => self.stats.logistics as i64,
+        }
+    }
+}
+
 impl Db {
+    /// Players ranked by `by`, best first, optionally only those on side.
+    /// Ties are broken by name so the order is stable between pages.
+    pub fn leaderboard(&self, by: Ranking, side: Option<Side>) -> Vec<(Ucid, &Player)> {
+        let mut res = self
+            .persisted
+            .players
+            .into_iter()
+            .filter(|(_, p)| side.map(|s| p.side == s).unwrap_or(true))
+            .map(|(ucid, p)| (*ucid, p))
+            .collect::<Vec<_>>();
+        res.sort_by(|(_, p0), (_, p1)| {
+            (Reverse(p0.ranked_by(by)), &p0.name).cmp(&(Reverse(p1.ranked_by(by)), &p1.name))
+        });
+        res
+    }
+
+    /// ucid's place on their side's leaderboard, starting from 1
+    pub fn rank(&self, ucid: &Ucid, by: Ranking) -> Option<usize> {
+        let side = self.persisted.players.get(ucid)?.side;
+        self.leaderboard(by, Some(side))
+            .iter()
+            .position(|(u, _)| u == ucid)
+            .map(|i| i + 1)
+    }
+
     pub fn player_deslot(&mut self, ucid: &Ucid) {
         if let Some(player) = self.persisted.players.get_mut_cow(ucid) {
             player.airborne = None;
@@ -110,10 +252,6 @@ impl Db {
         self.persisted.players.get(ucid)
     }
 
-    pub fn player_mut(&mut self, ucid: &Ucid) -> Option<&mut Player> {
-        self.persisted.players.get_mut_cow(ucid)
-    }
-
     pub fn transfer_points(&mut self, source: &Ucid, target: &Ucid, amount: u32) -> Result<()> {
         let sp = self
             .persisted
@@ -129,14 +267,27 @@ impl Db {
         }
         sp.points -= amount as i32;
         let sp_name = sp.name.clone();
+        let sp_tagged = sp.tagged_name();
+        let sp_balance = sp.points;
         match self.persisted.players.get_mut_cow(target) {
             Some(tp) => {
                 tp.points += amount as i32;
+                let amount = amount as i32;
+                let tp_name = tp.name.clone();
+                self.ephemeral.ledger(
+                    *source,
+                    -amount,
+                    sp_balance,
+                    Reason::Transfer,
+                    Some(tp_name),
+                );
+                self.ephemeral
+                    .ledger(*target, amount, tp.points, Reason::Transfer, Some(sp_name));
                 let msg = format_compact!(
                     "{}(+{}) you received points from {}",
                     tp.points,
                     amount,
-                    sp_name
+                    sp_tagged
                 );
                 self.ephemeral
                     .panel_to_player(&self.persisted, 10, target, msg);
@@ -209,21 +360,26 @@ impl Db {
         slot: SlotId,
         position: Vector2,
     ) -> Result<TakeoffRes> {
-        let sifo = self
+        let typ = self
             .ephemeral
             .slot_info
             .get(&slot)
+            .map(|sifo| sifo.typ.clone())
             .ok_or_else(|| anyhow!("could not find slot {:?}", slot))?;
-        let player = self
+        let ucid = *self
             .ephemeral
             .players_by_slot
             .get(&slot)
-            .and_then(|ucid| self.persisted.players.get_mut_cow(ucid))
             .ok_or_else(|| anyhow!("could not find player in slot {:?}", slot))?;
-        let life_type = match self.ephemeral.cfg.life_types.get(&sifo.typ) {
-            None => bail!("no life type for vehicle {:?}", sifo.typ),
+        let player = maybe_mut!(self.persisted.players, ucid, "player")?;
+        let life_type = match self.ephemeral.cfg.life_types.get(&typ) {
+            None => bail!("no life type for vehicle {:?}", typ),
             Some(typ) => *typ,
         };
+        self.ephemeral.stat(StatKind::Takeoff {
+            ucid,
+            aircraft: typ.clone(),
+        });
         let (_, player_lives) = player.lives.get_or_insert_cow(life_type, || {
             (time, self.ephemeral.cfg.default_lives[&life_type].0)
         });
@@ -235,7 +391,7 @@ impl Db {
             .objectives
             .into_iter()
             .fold(false, |res, (_, obj)| {
-                res || (obj.owner == player.side && obj.is_in_circle(position))
+                res || (obj.owner == player.side && obj.contains(position))
             });
         if is_on_owned_objective {
             // paranoia
@@ -257,18 +413,13 @@ impl Db {
             Some(sifo) => sifo,
             None => return None,
         };
-        let player = match self
-            .ephemeral
-            .players_by_slot
-            .get(&slot)
-            .and_then(|ucid| self.persisted.players.get_mut_cow(ucid))
-        {
-            Some(player) => player,
+        let ucid = match self.ephemeral.players_by_slot.get(&slot) {
+            Some(ucid) => *ucid,
             None => return None,
         };
         let life_type = self.ephemeral.cfg.life_types[&sifo.typ];
-        let (_, player_lives) = match player.lives.get_mut_cow(&life_type) {
-            Some(l) => l,
+        let player = match self.persisted.players.get_mut_cow(&ucid) {
+            Some(player) => player,
             None => return None,
         };
         let on_owned_objective = self
@@ -276,28 +427,39 @@ impl Db {
             .objectives
             .into_iter()
             .find_map(|(oid, obj)| {
-                if obj.owner == player.side && obj.is_in_circle(position) {
+                if obj.owner == player.side && obj.contains(position) {
                     Some(*oid)
                 } else {
                     None
                 }
             });
-        if let Some(oid) = on_owned_objective {
-            *player_lives += 1;
-            player.airborne = None;
-            if *player_lives >= self.ephemeral.cfg.default_lives[&life_type].0 {
-                player.lives.remove_cow(&life_type);
-            }
-            if let Some((_, Some(inst))) = &mut player.current_slot {
-                inst.position.p.x = position.x;
-                inst.position.p.z = position.y;
-                inst.landed_at_objective = Some(oid);
-            }
+        let returned = match on_owned_objective {
+            None => None,
+            Some(oid) => match player.lives.get_mut_cow(&life_type) {
+                None => None,
+                Some((_, player_lives)) => {
+                    *player_lives += 1;
+                    player.airborne = None;
+                    if *player_lives >= self.ephemeral.cfg.default_lives[&life_type].0 {
+                        player.lives.remove_cow(&life_type);
+                    }
+                    if let Some((_, Some(inst))) = &mut player.current_slot {
+                        inst.position.p.x = position.x;
+                        inst.position.p.z = position.y;
+                        inst.landed_at_objective = Some(oid);
+                    }
+                    Some(life_type)
+                }
+            },
+        };
+        self.ephemeral.stat(StatKind::Land {
+            ucid,
+            life_returned: returned.is_some(),
+        });
+        if returned.is_some() {
             self.ephemeral.dirty();
-            Some(life_type)
-        } else {
-            None
         }
+        returned
     }
 
     pub fn maybe_reset_lives(&mut self, ucid: &Ucid, now: DateTime<Utc>) -> Result<()> {
@@ -358,7 +520,8 @@ impl Db {
             SlotId::ArtilleryCommander(_, _)
             | SlotId::ForwardObserver(_, _)
             | SlotId::Observer(_, _) => {
-                if self.ephemeral.cfg.rules.ca.check(ucid) {
+                let squadron = player.squadron.as_ref();
+                if self.ephemeral.cfg.rules.ca.check(ucid, squadron) {
                     player.jtac_or_spectators = true;
                     SlotAuth::Yes
                 } else {
@@ -366,6 +529,11 @@ impl Db {
                 }
             }
             SlotId::Unit(_) | SlotId::MultiCrew(_, _) => {
+                if let Some(until) = player.slot_blocked_until {
+                    if time < until {
+                        return SlotAuth::Blocked(until);
+                    }
+                }
                 let sifo = match self.ephemeral.slot_info.get(&slot) {
                     Some(sifo) => sifo,
                     None => {
@@ -384,6 +552,14 @@ impl Db {
                 if objective.captureable() {
                     return SlotAuth::ObjectiveHasNoLogistics;
                 }
+                // a tag in a group name only reserves the slot once a
+                // squadron actually uses it
+                if let Some(tag) = &sifo.squadron {
+                    let exists = self.persisted.squadrons.get(tag).is_some();
+                    if exists && player.squadron.as_ref() != Some(tag) {
+                        return SlotAuth::Reserved(tag.clone());
+                    }
+                }
                 let life_type = self.ephemeral.cfg.life_types[&sifo.typ];
                 macro_rules! yes {
                     () => {
@@ -455,11 +631,27 @@ impl Db {
                             .points
                             .map(|p| p.new_player_join as i32)
                             .unwrap_or(0),
+                        stats: RoundStats::default(),
+                        recent_team_kills: vec![],
+                        slot_blocked_until: None,
+                        squadron: None,
                         current_slot: None,
                         changing_slots: false,
                         jtac_or_spectators: true,
                     },
                 );
+                let points = self
+                    .ephemeral
+                    .cfg
+                    .points
+                    .map(|p| p.new_player_join as usize)
+                    .unwrap_or(0);
+                self.ephemeral.stat(StatKind::PlayerRegister {
+                    name,
+                    ucid,
+                    side,
+                    points,
+                });
                 self.ephemeral.dirty();
                 Ok(())
             }
@@ -469,6 +661,8 @@ impl Db {
     pub fn force_sideswitch_player(&mut self, ucid: &Ucid, side: Side) -> Result<()> {
         let player = maybe_mut!(self.persisted.players, ucid, "no such player")?;
         player.side = side;
+        self.ephemeral.stat(StatKind::PlayerSideswitch { ucid: *ucid, side });
+        self.squadron_sideswitch(ucid);
         self.ephemeral.dirty();
         Ok(())
     }
@@ -491,6 +685,9 @@ impl Db {
                         None => (),
                     }
                     player.side = side;
+                    self.ephemeral
+                        .stat(StatKind::PlayerSideswitch { ucid: *ucid, side });
+                    self.squadron_sideswitch(ucid);
                     self.ephemeral.dirty();
                     Ok(())
                 }
@@ -630,10 +827,7 @@ impl Db {
             .persisted
             .objectives
             .into_iter()
-            .find(|(_, obj)| {
-                let radius2 = obj.radius.powi(2);
-                na::distance_squared(&point.into(), &obj.pos.into()) <= radius2
-            })
+            .find(|(_, obj)| obj.contains(point))
             .map(|(oid, _)| *oid);
         player.current_slot = Some((
             slot,
@@ -647,6 +841,12 @@ impl Db {
             }),
         ));
         player.changing_slots = false;
+        let aircraft = self.ephemeral.slot_info.get(&slot).map(|s| s.typ.clone());
+        self.ephemeral.stat(StatKind::Slot {
+            ucid,
+            slot,
+            aircraft,
+        });
         self.ephemeral.dirty();
         Ok(())
     }
@@ -730,7 +930,91 @@ impl Db {
         self.player_deslot(ucid);
     }
 
-    pub fn award_kill_points(&mut self, cfg: PointsCfg, dead: Dead) {
+    /// Divide total among the enemies of the victim in hit_by. With
+    /// assists configured the last hitter gets the kill and the bonus, and
+    /// the assist share is split among the earlier hitters by the number
+    /// of hits they landed in the assist window. Otherwise, or if nobody
+    /// actually hit the victim, the points are split evenly.
+    fn kill_shares(
+        &self,
+        cfg: &PointsCfg,
+        dead: &Dead,
+        hit_by: &[&Ucid],
+        total: u32,
+    ) -> SmallVec<[(Ucid, i32, Credit); 4]> {
+        let enemies = hit_by
+            .iter()
+            .filter(|ucid| {
+                self.persisted
+                    .players
+                    .get(**ucid)
+                    .map(|p| p.side != dead.victim_side)
+                    .unwrap_or(false)
+            })
+            .map(|ucid| **ucid)
+            .collect::<SmallVec<[Ucid; 4]>>();
+        if enemies.is_empty() {
+            return smallvec![];
+        }
+        let last = dead
+            .shots
+            .iter()
+            .filter(|s| s.hit && enemies.contains(&s.shooter_ucid))
+            .max_by_key(|s| s.time)
+            .map(|s| s.shooter_ucid);
+        match (last, cfg.assist_share) {
+            (None, _) | (Some(_), None) => {
+                let pps = (total as f32 / enemies.len() as f32).ceil() as i32;
+                enemies
+                    .iter()
+                    .map(|ucid| {
+                        let bonus = if Some(*ucid) == last {
+                            cfg.last_hit_bonus as i32
+                        } else {
+                            0
+                        };
+                        (*ucid, pps + bonus, Credit::Kill)
+                    })
+                    .collect()
+            }
+            (Some(killer), Some(share)) => {
+                let window = Duration::seconds(cfg.assist_window as i64);
+                let mut hits: SmallVec<[(Ucid, u32); 4]> = smallvec![];
+                for s in &dead.shots {
+                    if s.hit
+                        && s.shooter_ucid != killer
+                        && enemies.contains(&s.shooter_ucid)
+                        && dead.time - s.time <= window
+                    {
+                        match hits.iter_mut().find(|(ucid, _)| ucid == &s.shooter_ucid) {
+                            Some((_, n)) => *n += 1,
+                            None => hits.push((s.shooter_ucid, 1)),
+                        }
+                    }
+                }
+                let pool = if hits.is_empty() {
+                    0.
+                } else {
+                    total as f32 * share.clamp(0., 1.)
+                };
+                let total_hits = hits.iter().map(|(_, n)| *n).sum::<u32>();
+                let mut res: SmallVec<[(Ucid, i32, Credit); 4]> = smallvec![(
+                    killer,
+                    (total as f32 - pool).ceil() as i32 + cfg.last_hit_bonus as i32,
+                    Credit::Kill
+                )];
+                for (ucid, n) in hits {
+                    let p = (pool * n as f32 / total_hits as f32).ceil() as i32;
+                    res.push((ucid, p, Credit::Assist))
+                }
+                res
+            }
+        }
+    }
+
+    /// the players who hit the victim, or if nobody hit it the players
+    /// who shot at it in the 3 minutes before it died
+    fn hit_by(dead: &Dead) -> SmallVec<[&Ucid; 16]> {
         let mut hit_by: SmallVec<[&Ucid; 16]> = smallvec![];
         let non_self_shots = || {
             dead.shots.iter().filter(|shot| {
@@ -755,6 +1039,35 @@ impl Db {
                 }
             }
         }
+        hit_by
+    }
+
+    /// When points are not enabled team kills still count toward the
+    /// team kill ladder
+    pub fn record_team_kills(&mut self, dead: Dead) {
+        let victim = dead
+            .victim_ucid
+            .as_ref()
+            .and_then(|i| self.persisted.players.get(i))
+            .map(|p| p.tagged_name());
+        for ucid in Self::hit_by(&dead) {
+            match self.persisted.players.get_mut_cow(ucid) {
+                Some(player) if player.side == dead.victim_side => {
+                    player.stats.team_kills += 1;
+                }
+                Some(_) | None => continue,
+            }
+            if let Some(victim) = &victim {
+                let msg = format_compact!("you have team killed {victim}");
+                self.ephemeral
+                    .panel_to_player(&self.persisted, 10, ucid, msg)
+            }
+            self.team_kill_penalties(ucid, dead.victim_ucid, 0, dead.time)
+        }
+    }
+
+    pub fn award_kill_points(&mut self, cfg: PointsCfg, dead: Dead) {
+        let hit_by = Self::hit_by(&dead);
         if !hit_by.is_empty() {
             let total_points = if dead.victim_ucid.is_some() {
                 cfg.air_kill
@@ -777,28 +1090,81 @@ impl Db {
                     })
                     .unwrap_or(cfg.ground_kill)
             };
-            let pps = (total_points as f32 / hit_by.len() as f32).ceil() as i32;
+            let shares = self.kill_shares(&cfg, &dead, &hit_by, total_points);
             let victim_info = dead
                 .victim_ucid
                 .as_ref()
                 .and_then(|i| self.persisted.players.get(i))
-                .map(|p| (p.name.clone(), p.airborne));
+                .map(|p| (p.tagged_name(), p.airborne));
+            let counterpart = victim_info
+                .as_ref()
+                .map(|(n, _)| String::from(n.clone()))
+                .or_else(|| {
+                    (&dead.shots)
+                        .into_iter()
+                        .find(|s| s.target_typ.trim() != "")
+                        .map(|s| s.target_typ.clone())
+                });
+            let mut team_kill = false;
+            let mut team_killers: SmallVec<[(Ucid, u32); 1]> = smallvec![];
+            let mut points: SmallVec<[(Ucid, usize); 2]> = smallvec![];
+            let mut assists: SmallVec<[Ucid; 2]> = smallvec![];
             for ucid in hit_by {
                 if let Some(player) = self.persisted.players.get_mut_cow(ucid) {
                     let msg = if player.side != dead.victim_side {
+                        // hits outside the assist window earn nothing
+                        let (pps, credit) = match shares.iter().find(|(u, _, _)| u == ucid) {
+                            Some((_, pps, credit)) => (*pps, *credit),
+                            None => continue,
+                        };
+                        points.push((*ucid, pps as usize));
+                        let reason = match credit {
+                            Credit::Kill => {
+                                player.stats.kills += 1;
+                                Reason::Kill
+                            }
+                            Credit::Assist => {
+                                player.stats.assists += 1;
+                                assists.push(*ucid);
+                                Reason::Assist
+                            }
+                        };
                         player.points += pps;
                         let tp = player.points;
-                        match &victim_info {
-                            None => format_compact!("{tp}(+{pps}) points"),
-                            Some((victim, _)) => {
+                        self.ephemeral
+                            .ledger(*ucid, pps, tp, reason, counterpart.clone());
+                        match (&victim_info, credit) {
+                            (None, Credit::Kill) => format_compact!("{tp}(+{pps}) points"),
+                            (None, Credit::Assist) => {
+                                format_compact!("{tp}(+{pps}) points for an assist")
+                            }
+                            (Some((victim, _)), Credit::Kill) => {
                                 format_compact!("{tp}(+{pps}) points, killed {}", victim)
                             }
+                            (Some((victim, _)), Credit::Assist) => {
+                                format_compact!("{tp}(+{pps}) points, assist on {}", victim)
+                            }
                         }
                     } else {
+                        team_kill = true;
+                        points.push((*ucid, total_points as usize));
+                        player.stats.team_kills += 1;
+                        let deducted = match &victim_info {
+                            Some((_, None)) => 0,
+                            None | Some((_, Some(_))) => total_points,
+                        };
+                        team_killers.push((*ucid, deducted));
                         match &victim_info {
                             None => {
                                 player.points -= total_points as i32;
                                 let tp = player.points;
+                                self.ephemeral.ledger(
+                                    *ucid,
+                                    -(total_points as i32),
+                                    tp,
+                                    Reason::TeamKill,
+                                    counterpart.clone(),
+                                );
                                 format_compact!(
                                     "{tp}(-{total_points}) points, you have killed a friendly unit"
                                 )
@@ -815,6 +1181,13 @@ impl Db {
                                 }
                                 player.points -= total_points as i32;
                                 let tp = player.points;
+                                self.ephemeral.ledger(
+                                    *ucid,
+                                    -(total_points as i32),
+                                    tp,
+                                    Reason::TeamKill,
+                                    counterpart.clone(),
+                                );
                                 self.ephemeral.dirty();
                                 let lost = if lost {
                                     format_compact!("\nYou have lost a {life_type} life")
@@ -833,13 +1206,215 @@ impl Db {
                         .panel_to_player(&self.persisted, 10, &ucid, msg)
                 }
             }
+            for (ucid, deducted) in team_killers {
+                self.team_kill_penalties(&ucid, dead.victim_ucid, deducted, dead.time)
+            }
+            self.ephemeral.stat(StatKind::Kill {
+                shots: dead,
+                team_kill,
+                points,
+                assists,
+            });
+        }
+    }
+
+    /// Record a team kill by ucid, and apply the steps of the team kill
+    /// ladder it reached. What was applied is kept with the kill so it
+    /// can be undone if the kill is forgiven.
+    fn team_kill_penalties(
+        &mut self,
+        ucid: &Ucid,
+        victim: Option<Ucid>,
+        points: u32,
+        now: DateTime<Utc>,
+    ) {
+        let cfg = Arc::clone(&self.ephemeral.cfg);
+        let tk = match &cfg.team_kill {
+            Some(tk) => tk,
+            None => return,
+        };
+        let life_type = self
+            .persisted
+            .players
+            .get(ucid)
+            .and_then(|p| p.current_slot.as_ref())
+            .and_then(|(slot, _)| self.ephemeral.slot_info.get(slot))
+            .and_then(|sifo| cfg.life_types.get(&sifo.typ).copied());
+        let player = match self.persisted.players.get_mut_cow(ucid) {
+            Some(player) => player,
+            None => return,
+        };
+        let window = Duration::seconds(tk.window as i64);
+        player.recent_team_kills.retain(|k| now - k.time < window);
+        let mut kill = TeamKill {
+            time: now,
+            victim,
+            points,
+            penalty_points: 0,
+            slot_blocked_until: None,
+            banned_until: None,
+        };
+        let count = player.recent_team_kills.len() as u32 + 1;
+        let mut msgs: SmallVec<[CompactString; 4]> = smallvec![];
+        let mut block = false;
+        for step in tk.steps.iter().filter(|s| count == s.team_kills) {
+            match step.penalty {
+                TeamKillPenalty::Points(n) => {
+                    kill.penalty_points += n;
+                    player.points -= n as i32;
+                    let tp = player.points;
+                    self.ephemeral
+                        .ledger(*ucid, -(n as i32), tp, Reason::TeamKillPenalty, None);
+                    msgs.push(format_compact!("{tp}(-{n}) points for repeated team kills"))
+                }
+                TeamKillPenalty::Life => {
+                    if let Some(lt) = life_type {
+                        let (_, n) = player
+                            .lives
+                            .get_or_insert_cow(lt, || (now, cfg.default_lives[&lt].0));
+                        if *n > 0 {
+                            *n -= 1;
+                            msgs.push(format_compact!(
+                                "you have lost a {lt} life for repeated team kills"
+                            ))
+                        }
+                    }
+                }
+                TeamKillPenalty::SlotBlock(secs) => {
+                    let until = now + Duration::seconds(secs as i64);
+                    let until = max(until, player.slot_blocked_until.unwrap_or(until));
+                    player.slot_blocked_until = Some(until);
+                    kill.slot_blocked_until = Some(until);
+                    block = true;
+                    msgs.push(format_compact!(
+                        "you can't take a slot until {} for repeated team kills",
+                        until.format("%H:%M:%S UTC")
+                    ))
+                }
+                TeamKillPenalty::Ban(secs) => {
+                    let until = now + Duration::seconds(secs as i64);
+                    let until = max(until, kill.banned_until.unwrap_or(until));
+                    kill.banned_until = Some(until);
+                }
+            }
+        }
+        player.recent_team_kills.push(kill.clone());
+        if let Some(until) = kill.banned_until {
+            self.ephemeral.team_kill_bans.push((*ucid, until))
+        }
+        info!("{ucid} has {count} team kills in the window, {msgs:?}");
+        if block {
+            self.ephemeral.force_player_to_spectators(ucid)
+        }
+        for msg in msgs {
+            self.ephemeral
+                .panel_to_player(&self.persisted, 10, ucid, msg)
+        }
+        self.ephemeral.dirty();
+    }
+
+    /// Forgive the most recent team kill of victim, only by shooter if
+    /// given. The points it cost are returned and the slot block it set
+    /// is lifted. Returns the forgiven player, and the end of the ban the
+    /// kill set if that ban must be lifted.
+    pub fn forgive_team_kill(
+        &mut self,
+        victim: &Ucid,
+        shooter: Option<&Ucid>,
+    ) -> Result<(Ucid, Option<DateTime<Utc>>)> {
+        let window = match &self.ephemeral.cfg.team_kill {
+            Some(tk) => Duration::seconds(tk.window as i64),
+            None => bail!("team kills are not tracked on this server"),
+        };
+        let now = Utc::now();
+        let (ucid, time) = self
+            .persisted
+            .players
+            .into_iter()
+            .filter(|(ucid, _)| shooter.map(|s| s == *ucid).unwrap_or(true))
+            .flat_map(|(ucid, p)| {
+                p.recent_team_kills
+                    .iter()
+                    .filter(|k| k.victim.as_ref() == Some(victim) && now - k.time < window)
+                    .map(move |k| (*ucid, k.time))
+            })
+            .max_by_key(|(_, time)| *time)
+            .ok_or_else(|| anyhow!("there is no recent team kill to forgive"))?;
+        let victim_name = self.persisted.players.get(victim).map(|p| p.name.clone());
+        let player = maybe_mut!(self.persisted.players, &ucid, "player")?;
+        let i = player
+            .recent_team_kills
+            .iter()
+            .position(|k| k.time == time && k.victim.as_ref() == Some(victim))
+            .unwrap();
+        let tk = player.recent_team_kills.remove(i);
+        // if the block in force is the one this kill set it falls back to
+        // the longest block set by the remaining kills
+        let unblocked =
+            tk.slot_blocked_until.is_some() && player.slot_blocked_until == tk.slot_blocked_until;
+        if unblocked {
+            player.slot_blocked_until = player
+                .recent_team_kills
+                .iter()
+                .filter_map(|k| k.slot_blocked_until)
+                .filter(|until| *until > now)
+                .max();
         }
+        // a ban set by another kill that is still in force stays
+        let unban = tk.banned_until.filter(|_| {
+            !player
+                .recent_team_kills
+                .iter()
+                .any(|k| k.banned_until.map(|until| until > now).unwrap_or(false))
+        });
+        if let Some(until) = unban {
+            self.ephemeral
+                .team_kill_bans
+                .retain(|(u, ts)| u != &ucid || ts != &until);
+        }
+        if unblocked {
+            let msg = match &victim_name {
+                Some(name) => {
+                    format_compact!("{name} forgave your team kill, your slot block was lifted")
+                }
+                None => format_compact!("your team kill was forgiven, your slot block was lifted"),
+            };
+            self.ephemeral
+                .panel_to_player(&self.persisted, 10, &ucid, msg);
+        }
+        let refund = (tk.points + tk.penalty_points) as i32;
+        if refund > 0 {
+            let why = match &victim_name {
+                Some(name) => format_compact!("returned, {name} forgave your team kill"),
+                None => format_compact!("returned, your team kill was forgiven"),
+            };
+            self.adjust_points(&ucid, refund, Reason::Forgiven, victim_name, &why);
+        }
+        self.ephemeral.dirty();
+        Ok((ucid, unban))
     }
 
-    pub fn adjust_points(&mut self, ucid: &Ucid, amount: i32, why: &str) {
+    pub(super) fn round_stats_mut(&mut self, ucid: &Ucid) -> Option<&mut RoundStats> {
+        self.ephemeral.dirty();
+        self.persisted
+            .players
+            .get_mut_cow(ucid)
+            .map(|p| &mut p.stats)
+    }
+
+    pub fn adjust_points(
+        &mut self,
+        ucid: &Ucid,
+        amount: i32,
+        reason: Reason,
+        counterpart: Option<String>,
+        why: &str,
+    ) {
         if let Some(player) = self.persisted.players.get_mut_cow(ucid) {
             player.points += amount;
             let pp = player.points;
+            self.ephemeral
+                .ledger(*ucid, amount, pp, reason, counterpart);
             if amount != 0 {
                 let m = format_compact!("{}({}) points {}", pp, amount, why);
                 self.ephemeral.panel_to_player(&self.persisted, 10, ucid, m);
@@ -847,4 +1422,14 @@ impl Db {
             }
         }
     }
+
+    pub fn set_points(&mut self, ucid: &Ucid, amount: i32) -> Result<()> {
+        let player = maybe_mut!(self.persisted.players, ucid, "player")?;
+        let change = amount - player.points;
+        player.points = amount;
+        self.ephemeral
+            .ledger(*ucid, change, amount, Reason::Admin, None);
+        self.ephemeral.dirty();
+        Ok(())
+    }
 }
@@ -0,0 +1,395 @@
+/*
+Copyright 2024 Eric Stokes.
+
+This file is part of bflib.
+
+bflib is free software: you can redistribute it and/or modify it under
+the terms of the GNU Affero Public License as published by the Free
+Software Foundation, either version 3 of the License, or (at your
+option) any later version.
+
+bflib is distributed in the hope that it will be useful, but WITHOUT
+ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero Public License
+for more details.
+*/
+
+//! Squadrons are groups of players on the same side who fly together.
+//! A player belongs to at most one squadron, recorded in
+//! `Player::squadron`. The squadron itself holds its leader, officers,
+//! pending invites, and a shared pool of points that members can pay
+//! into and officers can pay out of.
+//!
+//! Slots can be reserved for a squadron by putting its tag in brackets
+//! in the slot's group name in the mission, e.g. `Senaki F-14 [VF1]`.
+//! Once a squadron with that tag exists only its members may take the
+//! slot.
+
+use super::{
+    group::{DeployKind, GroupId},
+    player::Player,
+    Db, Set,
+};
+use crate::{cfg::Rule, ledger::Reason, maybe, maybe_mut};
+use anyhow::{anyhow, bail, Result};
+use compact_str::{format_compact, CompactString};
+use dcso3::{coalition::Side, net::Ucid, String};
+use log::error;
+use serde_derive::{Deserialize, Serialize};
+use smallvec::SmallVec;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Squadron {
+    pub name: String,
+    pub side: Side,
+    pub leader: Ucid,
+    /// members who may invite, kick, and pay out of the pool. The leader
+    /// is always an officer but is not listed here.
+    pub officers: Set<Ucid>,
+    /// players who have been invited but haven't joined yet
+    pub invites: Set<Ucid>,
+    /// the shared points pool
+    pub points: i32,
+}
+
+impl Squadron {
+    pub fn is_officer(&self, ucid: &Ucid) -> bool {
+        &self.leader == ucid || self.officers.contains(ucid)
+    }
+}
+
+impl Player {
+    /// the player's name with their squadron tag, e.g. [VF1] Bob
+    pub fn tagged_name(&self) -> CompactString {
+        match &self.squadron {
+            None => CompactString::from(self.name.as_str()),
+            Some(tag) => format_compact!("[{tag}] {}", self.name),
+        }
+    }
+}
+
+fn valid_tag(tag: &str) -> Result<()> {
+    if tag.is_empty() || tag.len() > 6 {
+        bail!("squadron tags must be 1 to 6 characters long")
+    }
+    if !tag.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        bail!("squadron tags may only contain letters, numbers, and -")
+    }
+    Ok(())
+}
+
+/// The squadron a slot group is reserved for, the first valid tag in
+/// brackets in its name
+pub(super) fn reserved_for(group_name: &str) -> Option<String> {
+    let (_, rest) = group_name.split_once('[')?;
+    let (tag, _) = rest.split_once(']')?;
+    valid_tag(tag).ok()?;
+    Some(String::from(tag))
+}
+
+impl Db {
+    /// check rule for ucid, taking their squadron into account
+    pub fn rule_permits(&self, rule: &Rule, ucid: &Ucid) -> bool {
+        let squadron = self
+            .persisted
+            .players
+            .get(ucid)
+            .and_then(|p| p.squadron.as_ref());
+        rule.check(ucid, squadron)
+    }
+
+    pub fn squadron(&self, tag: &str) -> Result<&Squadron> {
+        maybe!(self.persisted.squadrons, String::from(tag), "squadron")
+    }
+
+    /// the squadron ucid belongs to, and its tag
+    fn squadron_of(&self, ucid: &Ucid) -> Result<(String, &Squadron)> {
+        let player = maybe!(self.persisted.players, ucid, "player")?;
+        let tag = player
+            .squadron
+            .clone()
+            .ok_or_else(|| anyhow!("you are not in a squadron"))?;
+        let sq = self.squadron(&tag)?;
+        Ok((tag, sq))
+    }
+
+    /// ucid must be an officer of their squadron
+    fn officer_of(&self, ucid: &Ucid) -> Result<(String, &Squadron)> {
+        let (tag, sq) = self.squadron_of(ucid)?;
+        if !sq.is_officer(ucid) {
+            bail!("only officers of [{tag}] can do that")
+        }
+        Ok((tag, sq))
+    }
+
+    pub fn squadron_members(&self, tag: &str) -> SmallVec<[(Ucid, &Player); 16]> {
+        self.persisted
+            .players
+            .into_iter()
+            .filter(|(_, p)| p.squadron.as_ref().map(|s| s.as_str()) == Some(tag))
+            .map(|(ucid, p)| (*ucid, p))
+            .collect()
+    }
+
+    fn squadron_msg(&mut self, tag: &str, msg: CompactString) {
+        let members = self
+            .squadron_members(tag)
+            .into_iter()
+            .map(|(ucid, _)| ucid)
+            .collect::<SmallVec<[Ucid; 16]>>();
+        for ucid in members {
+            self.ephemeral
+                .panel_to_player(&self.persisted, 10, &ucid, msg.clone())
+        }
+    }
+
+    pub fn squadron_create(&mut self, ucid: &Ucid, tag: &str, name: &str) -> Result<()> {
+        valid_tag(tag)?;
+        if name.trim().is_empty() {
+            bail!("the squadron needs a name")
+        }
+        let tag = String::from(tag);
+        if self.persisted.squadrons.get(&tag).is_some() {
+            bail!("the tag [{tag}] is taken")
+        }
+        let player = maybe_mut!(self.persisted.players, ucid, "player")?;
+        if let Some(cur) = &player.squadron {
+            bail!("you are already in [{cur}], leave it first")
+        }
+        player.squadron = Some(tag.clone());
+        let side = player.side;
+        self.persisted.squadrons.insert_cow(
+            tag,
+            Squadron {
+                name: String::from(name.trim()),
+                side,
+                leader: *ucid,
+                officers: Set::new(),
+                invites: Set::new(),
+                points: 0,
+            },
+        );
+        self.ephemeral.dirty();
+        Ok(())
+    }
+
+    pub fn squadron_invite(&mut self, ucid: &Ucid, target: &Ucid) -> Result<()> {
+        let (tag, sq) = self.officer_of(ucid)?;
+        let (side, name) = (sq.side, sq.name.clone());
+        let player = maybe!(self.persisted.players, target, "player")?;
+        if player.side != side {
+            bail!("{} is not on {side}", player.name)
+        }
+        if player.squadron.is_some() {
+            bail!("{} is already in a squadron", player.name)
+        }
+        maybe_mut!(self.persisted.squadrons, tag, "squadron")?
+            .invites
+            .insert_cow(*target);
+        let msg = format_compact!(
+            "you have been invited to [{tag}] {name}, type -squadron join {tag} to join"
+        );
+        self.ephemeral
+            .panel_to_player(&self.persisted, 15, target, msg);
+        self.ephemeral.dirty();
+        Ok(())
+    }
+
+    pub fn squadron_join(&mut self, ucid: &Ucid, tag: &str) -> Result<()> {
+        let sq = self.squadron(tag)?;
+        if !sq.invites.contains(ucid) {
+            bail!("you have not been invited to [{tag}]")
+        }
+        let side = sq.side;
+        let player = maybe_mut!(self.persisted.players, ucid, "player")?;
+        if player.side != side {
+            bail!("[{tag}] flies for {side}")
+        }
+        if let Some(cur) = &player.squadron {
+            bail!("you are already in [{cur}], leave it first")
+        }
+        let tag = String::from(tag);
+        player.squadron = Some(tag.clone());
+        let msg = format_compact!("{} has joined [{tag}]", player.name);
+        maybe_mut!(self.persisted.squadrons, tag, "squadron")?
+            .invites
+            .remove_cow(ucid);
+        self.squadron_msg(&tag, msg);
+        self.ephemeral.dirty();
+        Ok(())
+    }
+
+    /// Remove ucid from their squadron. If they led it leadership passes
+    /// to an officer, or failing that another member. The last member
+    /// to leave disbands the squadron and takes what is left in the pool.
+    pub fn squadron_leave(&mut self, ucid: &Ucid) -> Result<String> {
+        let (tag, _) = self.squadron_of(ucid)?;
+        let player = maybe_mut!(self.persisted.players, ucid, "player")?;
+        player.squadron = None;
+        let name = player.name.clone();
+        let remaining = self
+            .squadron_members(&tag)
+            .into_iter()
+            .map(|(ucid, _)| ucid)
+            .collect::<SmallVec<[Ucid; 16]>>();
+        let sq = maybe_mut!(self.persisted.squadrons, tag, "squadron")?;
+        sq.officers.remove_cow(ucid);
+        if remaining.is_empty() {
+            let points = sq.points;
+            self.persisted.squadrons.remove_cow(&tag);
+            let why = format_compact!("from the pool of [{tag}], which has disbanded");
+            self.adjust_points(ucid, points, Reason::Squadron, Some(tag.clone()), &why);
+        } else if &sq.leader == ucid {
+            let leader = sq
+                .officers
+                .into_iter()
+                .next()
+                .copied()
+                .unwrap_or(remaining[0]);
+            sq.officers.remove_cow(&leader);
+            sq.leader = leader;
+            let leader = self
+                .persisted
+                .players
+                .get(&leader)
+                .map(|p| p.name.clone())
+                .unwrap_or_default();
+            self.squadron_msg(&tag, format_compact!("{leader} now leads [{tag}]"));
+        }
+        self.squadron_msg(&tag, format_compact!("{name} has left [{tag}]"));
+        self.ephemeral.dirty();
+        Ok(tag)
+    }
+
+    pub fn squadron_kick(&mut self, ucid: &Ucid, target: &Ucid) -> Result<()> {
+        let (tag, sq) = self.officer_of(ucid)?;
+        let (ttag, _) = self.squadron_of(target)?;
+        if tag != ttag {
+            bail!("they are not in [{tag}]")
+        }
+        if sq.is_officer(target) && &sq.leader != ucid {
+            bail!("only the leader can remove an officer")
+        }
+        self.squadron_leave(target)?;
+        Ok(())
+    }
+
+    /// A squadron flies for one side, so a player who changes sides
+    /// leaves it
+    pub(super) fn squadron_sideswitch(&mut self, ucid: &Ucid) {
+        let in_squadron = self
+            .persisted
+            .players
+            .get(ucid)
+            .map(|p| p.squadron.is_some())
+            .unwrap_or(false);
+        if in_squadron {
+            if let Err(e) = self.squadron_leave(ucid) {
+                error!("could not remove {ucid} from their squadron {e:?}")
+            }
+        }
+    }
+
+    /// make target an officer, or not. Only the leader may do this.
+    pub fn squadron_promote(&mut self, ucid: &Ucid, target: &Ucid, officer: bool) -> Result<()> {
+        let (tag, sq) = self.squadron_of(ucid)?;
+        if &sq.leader != ucid {
+            bail!("only the leader of [{tag}] can do that")
+        }
+        let (ttag, _) = self.squadron_of(target)?;
+        if tag != ttag {
+            bail!("they are not in [{tag}]")
+        }
+        if ucid == target {
+            bail!("you lead [{tag}]")
+        }
+        let sq = maybe_mut!(self.persisted.squadrons, tag, "squadron")?;
+        if officer {
+            sq.officers.insert_cow(*target);
+        } else {
+            sq.officers.remove_cow(target);
+        }
+        self.ephemeral.dirty();
+        Ok(())
+    }
+
+    /// move amount of ucid's points into their squadron's pool
+    pub fn squadron_deposit(&mut self, ucid: &Ucid, amount: u32) -> Result<()> {
+        let (tag, _) = self.squadron_of(ucid)?;
+        let player = maybe!(self.persisted.players, ucid, "player")?;
+        if player.points < amount as i32 {
+            bail!(
+                "insufficient balance, you have {}, you requested {amount}",
+                player.points
+            )
+        }
+        let name = player.name.clone();
+        maybe_mut!(self.persisted.squadrons, tag, "squadron")?.points += amount as i32;
+        let why = format_compact!("paid into the pool of [{tag}]");
+        self.adjust_points(
+            ucid,
+            -(amount as i32),
+            Reason::Squadron,
+            Some(tag.clone()),
+            &why,
+        );
+        self.squadron_msg(
+            &tag,
+            format_compact!("{name} paid {amount} points into the pool"),
+        );
+        Ok(())
+    }
+
+    /// pay amount out of the pool of ucid's squadron to target, ucid
+    /// must be an officer
+    pub fn squadron_withdraw(&mut self, ucid: &Ucid, target: &Ucid, amount: u32) -> Result<()> {
+        let (tag, sq) = self.officer_of(ucid)?;
+        if sq.points < amount as i32 {
+            bail!("the pool only has {} points", sq.points)
+        }
+        let (ttag, _) = self.squadron_of(target)?;
+        if tag != ttag {
+            bail!("they are not in [{tag}]")
+        }
+        maybe_mut!(self.persisted.squadrons, tag, "squadron")?.points -= amount as i32;
+        let name = self
+            .persisted
+            .players
+            .get(target)
+            .map(|p| p.name.clone())
+            .unwrap_or_default();
+        let why = format_compact!("from the pool of [{tag}]");
+        self.adjust_points(
+            target,
+            amount as i32,
+            Reason::Squadron,
+            Some(tag.clone()),
+            &why,
+        );
+        self.squadron_msg(
+            &tag,
+            format_compact!("{amount} points paid from the pool to {name}"),
+        );
+        Ok(())
+    }
+
+    /// the crates deployed by the members of ucid's squadron, as (owner,
+    /// crate, group)
+    pub fn squadron_crates(
+        &self,
+        ucid: &Ucid,
+    ) -> Result<SmallVec<[(String, String, GroupId); 16]>> {
+        let (tag, _) = self.squadron_of(ucid)?;
+        let mut res = SmallVec::new();
+        for (_, player) in self.squadron_members(&tag) {
+            for gid in &player.crates {
+                if let Some(group) = self.persisted.groups.get(gid) {
+                    if let DeployKind::Crate { spec, .. } = &group.origin {
+                        res.push((player.name.clone(), spec.name.clone(), *gid))
+                    }
+                }
+            }
+        }
+        Ok(res)
+    }
+}
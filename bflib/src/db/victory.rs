@@ -0,0 +1,103 @@
+/*
+Copyright 2024 Eric Stokes.
+
+This file is part of bflib.
+
+bflib is free software: you can redistribute it and/or modify it under
+the terms of the GNU Affero Public License as published by the Free
+Software Foundation, either version 3 of the License, or (at your
+option) any later version.
+
+bflib is distributed in the hope that it will be useful, but WITHOUT
+ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero Public License
+for more details.
+*/
+
+use super::Db;
+use crate::cfg::VictoryCondition;
+use chrono::{prelude::*, Duration};
+use compact_str::{format_compact, CompactString};
+use dcso3::coalition::Side;
+use std::cmp::max;
+
+impl Db {
+    fn victory_condition_met(
+        &self,
+        cond: &VictoryCondition,
+        side: Side,
+        now: DateTime<Utc>,
+    ) -> Option<CompactString> {
+        let objectives = || self.persisted.objectives.into_iter().map(|(_, o)| o);
+        match cond {
+            VictoryCondition::AllAirbases => {
+                let mut airbases = objectives().filter(|o| o.is_airbase()).peekable();
+                if airbases.peek().is_some() && airbases.all(|o| o.owner == side) {
+                    Some(format_compact!("{side} holds every airbase"))
+                } else {
+                    None
+                }
+            }
+            VictoryCondition::Objectives(pct) => {
+                let (total, held) = objectives()
+                    .filter(|o| !o.is_farp())
+                    .fold((0, 0), |(total, held), o| {
+                        (total + 1, held + (o.owner == side) as usize)
+                    });
+                if total > 0 && held * 100 >= total * *pct as usize {
+                    Some(format_compact!("{side} holds {held} of {total} objectives"))
+                } else {
+                    None
+                }
+            }
+            VictoryCondition::Capitals { objectives, hours } => {
+                let mut since = DateTime::<Utc>::MIN_UTC;
+                for name in objectives {
+                    let oid = self.persisted.objectives_by_name.get(name)?;
+                    let obj = self.persisted.objectives.get(oid)?;
+                    if obj.owner != side {
+                        return None;
+                    }
+                    since = max(since, obj.captured_ts);
+                }
+                if !objectives.is_empty() && now - since >= Duration::hours(*hours as i64) {
+                    Some(format_compact!(
+                        "{side} has held {} for {hours} hours",
+                        objectives.join(", ")
+                    ))
+                } else {
+                    None
+                }
+            }
+            VictoryCondition::NoEnemyLogistics => {
+                let enemy = side.opposite();
+                let hubs = || {
+                    self.persisted
+                        .logistics_hubs
+                        .into_iter()
+                        .filter_map(|oid| self.persisted.objectives.get(oid))
+                };
+                let enemy_hubs = hubs().filter(|o| o.owner == enemy).count();
+                if hubs().next().is_some() && enemy_hubs == 0 {
+                    Some(format_compact!("{enemy} has no logistics hubs left"))
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// The side that has met one of the configured victory conditions,
+    /// and how, or None if the round goes on
+    pub fn check_victory(&self, now: DateTime<Utc>) -> Option<(Side, CompactString)> {
+        let cfg = self.ephemeral.cfg.victory.as_ref()?;
+        for cond in &cfg.conditions {
+            for side in [Side::Red, Side::Blue] {
+                if let Some(why) = self.victory_condition_met(cond, side, now) {
+                    return Some((side, why));
+                }
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,150 @@
+/*
+Copyright 2024 Eric Stokes.
+
+This file is part of bflib.
+
+bflib is free software: you can redistribute it and/or modify it under
+the terms of the GNU Affero Public License as published by the Free
+Software Foundation, either version 3 of the License, or (at your
+option) any later version.
+
+bflib is distributed in the hope that it will be useful, but WITHOUT
+ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero Public License
+for more details.
+*/
+
+//! Every change to a player's points balance, one json object per line
+//! in `<save>_LEDGER`, so disputes over lost points can be settled.
+
+use crate::backup::with_suffix;
+use anyhow::Result;
+use chrono::prelude::*;
+use compact_str::{format_compact, CompactString};
+use dcso3::{net::Ucid, String};
+use log::warn;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fmt,
+    fs::File,
+    io::{self, BufRead, BufReader, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Reason {
+    Kill,
+    Assist,
+    TeamKill,
+    TeamKillPenalty,
+    Forgiven,
+    Capture,
+    Repair,
+    SupplyTransfer,
+    Deploy,
+    Troop,
+    TroopReturn,
+    Action,
+    ActionPenalty,
+    GroupLoss,
+    Refund,
+    Transfer,
+    Squadron,
+    Admin,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub time: DateTime<Utc>,
+    pub ucid: Ucid,
+    pub amount: i32,
+    /// the balance after the change
+    pub balance: i32,
+    pub reason: Reason,
+    /// the other player, or the objective, group, action, etc involved
+    pub counterpart: Option<String>,
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {:+} = {} {:?}",
+            self.time.format("%Y-%m-%d %H:%M:%S"),
+            self.amount,
+            self.balance,
+            self.reason
+        )?;
+        if let Some(c) = &self.counterpart {
+            write!(f, " {c}")?
+        }
+        Ok(())
+    }
+}
+
+fn path(save: &Path) -> Result<PathBuf> {
+    with_suffix(save, "_LEDGER")
+}
+
+pub(super) fn append(save: &Path, entries: &[Entry]) -> Result<()> {
+    let mut buf = vec![];
+    for e in entries {
+        serde_json::to_writer(&mut buf, e)?;
+        buf.push(b'\n');
+    }
+    let mut file = File::options()
+        .create(true)
+        .append(true)
+        .open(path(save)?)?;
+    file.write_all(&buf)?;
+    Ok(())
+}
+
+/// Queries only search this many bytes at the end of the ledger, since
+/// they run on the game thread and the ledger grows without bound
+const QUERY_TAIL: u64 = 1024 * 1024;
+
+/// the last `limit` entries for ucid, oldest first. Only the most recent
+/// entries, the last `QUERY_TAIL` bytes of the ledger, are searched.
+pub(super) fn query(save: &Path, ucid: &Ucid, limit: usize) -> Result<Vec<Entry>> {
+    let mut file = match File::open(path(save)?) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let len = file.metadata()?.len();
+    let partial = len > QUERY_TAIL;
+    if partial {
+        file.seek(SeekFrom::Start(len - QUERY_TAIL))?;
+    }
+    // most lines are about other players, skip them without decoding
+    let key: CompactString = format_compact!("{ucid}");
+    let mut res = VecDeque::new();
+    let mut lines = BufReader::new(file).split(b'\n');
+    if partial {
+        // we probably started in the middle of a line
+        lines.next();
+    }
+    for line in lines {
+        let line = line?;
+        if !line.windows(key.len()).any(|w| w == key.as_bytes()) {
+            continue;
+        }
+        // a crash in the middle of an append can leave a torn line
+        let entry: Entry = match serde_json::from_slice(&line) {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!("skipping bad ledger line {e:?}");
+                continue;
+            }
+        };
+        if &entry.ucid == ucid {
+            if res.len() == limit {
+                res.pop_front();
+            }
+            res.push_back(entry);
+        }
+    }
+    Ok(res.into())
+}
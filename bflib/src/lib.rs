@@ -15,26 +15,32 @@ for more details.
 */
 
 mod admin;
+mod adminsock;
+mod audit;
+mod backup;
 mod bg;
-mod cfg;
+pub mod cfg;
 mod chatcmd;
 mod db;
 mod ewr;
 mod jtac;
 mod landcache;
+mod ledger;
 mod menu;
 mod msgq;
 mod perf;
+mod round;
+mod schedule;
 mod shots;
 mod spawnctx;
 pub mod stats;
 
 extern crate nalgebra as na;
 use crate::{cfg::Cfg, db::player::SlotAuth, perf::record_perf};
-use admin::{run_admin_commands, AdminCommand};
+use admin::{run_admin_commands, AdminCommand, Requester};
 use anyhow::{anyhow, bail, Context as AnyhowContext, Result};
 use cfg::LifeType;
-use chatcmd::run_action_commands;
+use chatcmd::{run_action_commands, SlowMode};
 use chrono::{prelude::*, Duration};
 use compact_str::{format_compact, CompactString};
 use db::{objective::ObjectiveId, player::TakeoffRes, Db};
@@ -65,11 +71,13 @@ use log::{debug, error, info, warn};
 use mlua::prelude::*;
 use msgq::MsgTyp;
 use perf::{Perf, PerfInner};
+use schedule::Scheduler;
 use shots::ShotDb;
 use smallvec::{smallvec, SmallVec};
 use spawnctx::SpawnCtx;
-use std::{path::PathBuf, sync::Arc};
-use tokio::sync::mpsc::UnboundedSender;
+use stats::StatKind;
+use std::{path::PathBuf, sync::Arc, time::SystemTime};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
 
 #[derive(Debug)]
 struct PlayerInfo {
@@ -212,7 +220,10 @@ struct Context {
     load_state: LoadState,
     idx: env::miz::MizIndex,
     db: Db,
-    admin_commands: Vec<(PlayerId, AdminCommand)>,
+    admin_commands: Vec<(Requester, AdminCommand)>,
+    remote_admin: Option<UnboundedReceiver<adminsock::Request>>,
+    scheduler: Scheduler,
+    slow_mode: SlowMode,
     action_commands: Vec<(PlayerId, String)>,
     to_background: Option<UnboundedSender<bg::Task>>,
     recently_landed: FxHashMap<DcsOid<ClassUnit>, DateTime<Utc>>,
@@ -222,6 +233,10 @@ struct Context {
     menu_init_queue: IndexSet<SlotId, FxBuildHasher>,
     last_frame: Option<DateTime<Utc>>,
     last_slow_timed_events: DateTime<Utc>,
+    /// the side that has won the round, the next round starts at the
+    /// next restart
+    round_over: Option<Side>,
+    cfg_mtime: Option<SystemTime>,
     last_unit_position: usize,
     last_player_position: usize,
     subscribed_jtac_menus: FxHashMap<SlotId, FxHashSet<ObjectiveId>>,
@@ -266,6 +281,45 @@ impl Context {
         }
     }
 
+    /// move commands received on the admin socket into the queue
+    fn queue_remote_admin_commands(&mut self) {
+        if let Some(rx) = self.remote_admin.as_mut() {
+            while let Ok(r) = rx.try_recv() {
+                self.admin_commands
+                    .push((Requester::Remote(r.remote), r.cmd))
+            }
+        }
+    }
+
+    /// (re)start the admin socket with the current config, or stop it
+    /// if it is no longer configured
+    fn start_admin_socket(&mut self) {
+        self.queue_remote_admin_commands();
+        let listen = match &self.db.ephemeral.cfg.admin_socket {
+            None => {
+                self.remote_admin = None;
+                None
+            }
+            Some(_) => {
+                let (tx, rx) = mpsc::unbounded_channel();
+                self.remote_admin = Some(rx);
+                Some((Arc::clone(&self.db.ephemeral.cfg), tx))
+            }
+        };
+        self.do_bg_task(bg::Task::AdminSocket(listen))
+    }
+
+    fn flush_stats(&mut self) {
+        for st in self.db.ephemeral.take_stats() {
+            self.do_bg_task(bg::Task::Stat(st))
+        }
+        let ledger = self.db.ephemeral.take_ledger();
+        if !ledger.is_empty() {
+            let path = self.miz_state_path.clone();
+            self.do_bg_task(bg::Task::Ledger(path, ledger))
+        }
+    }
+
     fn init_async_bg(&mut self, lua: &Lua) -> Result<()> {
         if self.to_background.is_none() {
             let write_dir = PathBuf::from(Lfs::singleton(lua)?.writedir()?.as_str());
@@ -385,6 +439,19 @@ fn try_occupy_slot(id: PlayerId, ifo: &PlayerInfo, side: Side, slot: SlotId) ->
             );
             Ok(false)
         }
+        SlotAuth::Blocked(until) => {
+            let msg = format_compact!(
+                "you can't take a slot until {} for repeated team kills",
+                until.format("%H:%M:%S UTC")
+            );
+            ctx.db.ephemeral.msgs().send(MsgTyp::Chat(Some(id)), msg);
+            Ok(false)
+        }
+        SlotAuth::Reserved(tag) => {
+            let msg = format_compact!("this slot is reserved for members of [{tag}]");
+            ctx.db.ephemeral.msgs().send(MsgTyp::Chat(Some(id)), msg);
+            Ok(false)
+        }
         SlotAuth::VehicleNotAvailable(vehicle) => {
             let msg = format_compact!("Objective does not have any {} in stock", vehicle.0);
             ctx.db.ephemeral.msgs().send(MsgTyp::Chat(Some(id)), msg);
@@ -456,7 +523,7 @@ fn unit_killed(
     now: DateTime<Utc>,
 ) -> Result<()> {
     ctx.recently_landed.remove(&id);
-    if ctx.db.ephemeral.cfg.points.is_some() {
+    if ctx.db.ephemeral.cfg.tracks_shots() {
         ctx.shots_out.dead(id.clone(), now);
     }
     if let Err(e) = ctx.jtac.unit_dead(lua, &mut ctx.db, &id) {
@@ -500,7 +567,7 @@ fn on_event(lua: MizLua, ev: Event) -> Result<()> {
         Event::PlayerLeaveUnit(e) => {
             if let Some(unit) = e.initiator.and_then(|u| u.as_unit().ok()) {
                 let oid = unit.object_id()?;
-                if ctx.db.ephemeral.cfg.points.is_some() {
+                if ctx.db.ephemeral.cfg.tracks_shots() {
                     if let Some(ucid) = ctx.db.player_in_unit(false, &oid) {
                         if let Some(player) = ctx.db.player(&ucid) {
                             if let Some((_, Some(inst))) = player.current_slot.as_ref() {
@@ -519,7 +586,7 @@ fn on_event(lua: MizLua, ev: Event) -> Result<()> {
         Event::Hit(e) | Event::Kill(e) => {
             if let Some(target) = e.target.as_ref().and_then(|t| t.as_unit().ok()) {
                 let dead = target.get_life()? < 1;
-                if ctx.db.ephemeral.cfg.points.is_some() {
+                if ctx.db.ephemeral.cfg.tracks_shots() {
                     if let Some(shooter) = e.initiator.and_then(|u| u.as_unit().ok()) {
                         if let Err(e) = ctx.shots_out.hit(
                             &ctx.db,
@@ -547,7 +614,7 @@ fn on_event(lua: MizLua, ev: Event) -> Result<()> {
             }
         }
         Event::Shot(e) => {
-            if ctx.db.ephemeral.cfg.points.is_some() {
+            if ctx.db.ephemeral.cfg.tracks_shots() {
                 if let Err(e) = ctx.shots_out.shot(&ctx.db, start_ts, e) {
                     error!("error processing shot event {:?}", e)
                 }
@@ -628,6 +695,8 @@ fn on_event(lua: MizLua, ev: Event) -> Result<()> {
             }
         }
         Event::MissionEnd => unsafe {
+            ctx.db.ephemeral.stat(StatKind::SessionEnd);
+            ctx.flush_stats();
             Context::reset();
             Perf::reset();
             Context::get_mut().init_async_bg(lua.inner())?;
@@ -742,6 +811,32 @@ fn advise_captured(ctx: &mut Context, lua: MizLua, ts: DateTime<Utc>) -> Result<
     Ok(())
 }
 
+fn check_victory(ctx: &mut Context, ts: DateTime<Utc>) -> Result<()> {
+    if ctx.round_over.is_some() {
+        return Ok(());
+    }
+    let (winner, why) = match ctx.db.check_victory(ts) {
+        None => return Ok(()),
+        Some(v) => v,
+    };
+    info!("{winner} has won the round, {why}");
+    ctx.round_over = Some(winner);
+    let msg = format_compact!(
+        "{why}, {winner} has won the round! A new round will start at the next restart"
+    );
+    ctx.db.ephemeral.msgs().panel_to_all(60, false, msg.clone());
+    ctx.db.ephemeral.msgs().send(MsgTyp::Chat(None), msg);
+    ctx.db.ephemeral.stat(StatKind::RoundEnd {
+        winner: Some(winner),
+    });
+    ctx.do_bg_task(bg::Task::ArchiveRound(
+        ctx.miz_state_path.clone(),
+        ctx.db.persisted.clone(),
+    ));
+    let rollover = round::Rollover::new(&ctx.db.ephemeral.cfg, winner);
+    round::schedule(&ctx.miz_state_path, &rollover).context("scheduling the next round")
+}
+
 fn generate_ewr_reports(ctx: &mut Context, now: DateTime<Utc>) -> Result<()> {
     use std::fmt::Write;
     let mut msgs: SmallVec<[(UnitId, CompactString); 64]> = smallvec![];
@@ -770,6 +865,33 @@ fn generate_ewr_reports(ctx: &mut Context, now: DateTime<Utc>) -> Result<()> {
     Ok(())
 }
 
+fn maybe_reload_cfg(ctx: &mut Context, lua: MizLua, path: &PathBuf) -> Result<()> {
+    let mtime = ctx.db.ephemeral.cfg.mtime(path)?;
+    if ctx.cfg_mtime == Some(mtime) {
+        return Ok(());
+    }
+    ctx.cfg_mtime = Some(mtime);
+    let cfg = Cfg::load(path)?;
+    let miz = Miz::singleton(lua)?;
+    let changed = ctx.db.reload_cfg(&miz, &ctx.idx, cfg)?;
+    if !changed.is_empty() {
+        info!("reloaded the config, changed sections {changed:?}")
+    }
+    if changed
+        .iter()
+        .any(|s| s.as_str() == "admin_socket" || s.as_str() == "admin_roles")
+    {
+        ctx.start_admin_socket()
+    }
+    if changed.iter().any(|s| s.as_str() == "schedules") {
+        if ctx.scheduler.sync(&ctx.db.ephemeral.cfg, Utc::now()) {
+            let task = ctx.scheduler.save_task();
+            ctx.do_bg_task(task)
+        }
+    }
+    Ok(())
+}
+
 fn check_auto_shutdown(ctx: &mut Context, lua: MizLua, now: DateTime<Utc>) {
     if let Some(asd) = ctx.shutdown.as_mut() {
         if asd.when - now <= Duration::minutes(30) && !asd.thirty_minute_warning {
@@ -873,6 +995,9 @@ fn run_slow_timed_events(
     if ts - ctx.last_slow_timed_events >= freq {
         ctx.last_slow_timed_events = ts;
         check_auto_shutdown(ctx, lua, ts);
+        if let Err(e) = maybe_reload_cfg(ctx, lua, path) {
+            error!("not reloading the config {e:?}")
+        }
         for (oid, vh) in ctx.db.ephemeral.warehouses_to_sync() {
             if let Err(e) = ctx.db.sync_vehicle_at_obj(lua, oid, vh.clone()) {
                 error!(
@@ -882,10 +1007,24 @@ fn run_slow_timed_events(
             }
         }
         return_lives(lua, ctx, ts);
-        if let Some(points) = ctx.db.ephemeral.cfg.points {
+        if ctx.db.ephemeral.cfg.tracks_shots() {
             for dead in ctx.shots_out.bring_out_your_dead(ts) {
                 info!("kill {:?}", dead);
-                ctx.db.award_kill_points(points, dead)
+                match ctx.db.ephemeral.cfg.points {
+                    Some(points) => ctx.db.award_kill_points(points, dead),
+                    None => ctx.db.record_team_kills(dead),
+                }
+            }
+        }
+        for (ucid, until) in ctx.db.ephemeral.take_team_kill_bans() {
+            let name = ctx
+                .db
+                .player(&ucid)
+                .map(|p| p.name.clone())
+                .unwrap_or_default();
+            info!("banning {name}({ucid}) until {until} for repeated team kills");
+            if let Err(e) = admin::ban(ctx, lua, ucid, Some(until), name) {
+                error!("could not ban team killer {ucid} {e:?}")
             }
         }
         let start_ts = Utc::now();
@@ -928,6 +1067,9 @@ fn run_slow_timed_events(
             }
         }
         record_perf(&mut perf.unit_culling, ts);
+        if let Err(e) = check_victory(ctx, ts) {
+            error!("could not check victory conditions {e:?}")
+        }
         let ts = Utc::now();
         if let Err(e) = ctx.db.update_objectives_markup() {
             error!("could not remark objectives {e}")
@@ -938,7 +1080,8 @@ fn run_slow_timed_events(
         record_perf(&mut perf.update_jtac_contacts, ts);
         let now = Utc::now();
         if let Some(snap) = ctx.db.maybe_snapshot() {
-            ctx.do_bg_task(bg::Task::SaveState(path.clone(), snap));
+            let interval = ctx.db.ephemeral.cfg.checkpoint_interval;
+            ctx.do_bg_task(bg::Task::SaveState(path.clone(), snap, interval));
         }
         record_perf(&mut perf.snapshot, now);
         record_perf(&mut perf.slow_timed, start_ts);
@@ -1031,12 +1174,14 @@ fn run_timed_events(lua: MizLua, path: &PathBuf) -> Result<()> {
     if let Err(e) = ctx.db.logistics_step(lua, perf, ts) {
         error!("error running logistics events {e:?}")
     }
+    schedule::queue_due(ctx, Utc::now());
     if let Err(e) = run_admin_commands(ctx, lua) {
         error!("failed to run admin commands {e:?}")
     }
     if let Err(e) = run_action_commands(ctx, perf, lua) {
         error!("failed to run action commands {e:?}")
     }
+    ctx.flush_stats();
     ctx.load_state.step();
     record_perf(&mut perf.timed_events, ts);
     ctx.log_perf(now);
@@ -1081,6 +1226,24 @@ fn delayed_init_miz(lua: MizLua) -> Result<()> {
         ctx.miz_state_path.clone()
     };
     debug!("path to saved state is {:?}", path);
+    match round::rollover(&path) {
+        Ok(None) => (),
+        Err(e) => {
+            error!("could not start the next round {e:?}");
+            // the save may already be gone
+            ctx.do_bg_task(bg::Task::ResetJournal)
+        }
+        Ok(Some(r)) => {
+            info!("{} won the last round, starting a new round", r.winner);
+            ctx.do_bg_task(bg::Task::ResetJournal)
+        }
+    }
+    ctx.round_over = None;
+    match backup::restore_staged(&path) {
+        Err(e) => error!("could not restore the staged backup {e:?}"),
+        Ok(false) => (),
+        Ok(true) => ctx.do_bg_task(bg::Task::ResetJournal),
+    }
     info!("initializing db");
     if !path.exists() {
         debug!("saved state doesn't exist, starting from default");
@@ -1090,12 +1253,25 @@ fn delayed_init_miz(lua: MizLua) -> Result<()> {
         debug!("saved state exists, loading it");
         ctx.db = Db::load(&miz, &ctx.idx, &path).context("loading the saved state")?;
     }
+    ctx.cfg_mtime = ctx.db.ephemeral.cfg.mtime(&path).ok();
     ctx.shutdown = ctx
         .db
         .ephemeral
         .cfg
         .shutdown
         .map(|hrs| AutoShutdown::new(Utc::now() + Duration::hours(hrs as i64)));
+    ctx.do_bg_task(bg::Task::OpenStats(path.clone()));
+    ctx.start_admin_socket();
+    match Scheduler::load(&path, &ctx.db.ephemeral.cfg, Utc::now()) {
+        Err(e) => error!("could not load the schedule state {e:?}"),
+        Ok(scheduler) => {
+            ctx.scheduler = scheduler;
+            let task = ctx.scheduler.save_task();
+            ctx.do_bg_task(task)
+        }
+    }
+    let stop_time = ctx.shutdown.as_ref().map(|asd| asd.when);
+    ctx.db.ephemeral.stat(StatKind::SessionStart { stop_time });
     info!("spawning units");
     ctx.respawn_groups(lua, &miz)
         .context("setting up the mission after load")?;
@@ -1189,7 +1365,8 @@ fn init_miz(lua: MizLua) -> Result<()> {
     Ok(())
 }
 
-#[mlua::lua_module]
+#[cfg_attr(feature = "module", mlua::lua_module)]
+#[cfg_attr(not(feature = "module"), allow(dead_code))]
 fn bflib(lua: &Lua) -> LuaResult<LuaTable> {
     unsafe { Context::get_mut() }
         .init_async_bg(lua.inner())
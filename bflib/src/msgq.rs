@@ -18,7 +18,9 @@ use dcso3::{
     coalition::Side,
     env::miz::{GroupId, UnitId},
     net::{Net, PlayerId},
-    trigger::{Action, ArrowSpec, CircleSpec, MarkId, RectSpec, SideFilter, TextSpec},
+    trigger::{
+        Action, ArrowSpec, CircleSpec, LineSpec, MarkId, QuadSpec, RectSpec, SideFilter, TextSpec,
+    },
     Color, LuaVec3, String, Vector2, Vector3,
 };
 use log::error;
@@ -62,6 +64,12 @@ pub enum Msg {
         typ: MsgTyp,
         text: String,
     },
+    Line {
+        id: MarkId,
+        to: SideFilter,
+        spec: LineSpec,
+        message: Option<String>,
+    },
     Circle {
         id: MarkId,
         to: SideFilter,
@@ -74,6 +82,12 @@ pub enum Msg {
         spec: RectSpec,
         message: Option<String>,
     },
+    Quad {
+        id: MarkId,
+        to: SideFilter,
+        spec: QuadSpec,
+        message: Option<String>,
+    },
     Text {
         id: MarkId,
         to: SideFilter,
@@ -93,6 +107,10 @@ pub enum Msg {
         id: MarkId,
         color: Color,
     },
+    SetMarkupText {
+        id: MarkId,
+        text: String,
+    },
 }
 
 #[derive(Debug, Clone)]
@@ -133,8 +151,10 @@ impl MsgQ {
                 Cmd::DeleteMark(_) => true,
                 Cmd::Send(msg) => match msg {
                     Msg::Message { .. } => true,
-                    Msg::Circle { id, .. }
+                    Msg::Line { id, .. }
+                    | Msg::Circle { id, .. }
                     | Msg::Rect { id, .. }
+                    | Msg::Quad { id, .. }
                     | Msg::Text { id, .. }
                     | Msg::Arrow { id, .. } => {
                         if *id == did {
@@ -144,9 +164,9 @@ impl MsgQ {
                             true
                         }
                     }
-                    Msg::SetMarkupColor { id, .. } | Msg::SetMarkupFillColor { id, .. } => {
-                        *id != did
-                    }
+                    Msg::SetMarkupColor { id, .. }
+                    | Msg::SetMarkupFillColor { id, .. }
+                    | Msg::SetMarkupText { id, .. } => *id != did,
                 },
             })
         };
@@ -289,6 +309,21 @@ impl MsgQ {
         )
     }
 
+    pub fn line_to_all(
+        &mut self,
+        to: SideFilter,
+        id: MarkId,
+        spec: LineSpec,
+        message: Option<String>,
+    ) {
+        self.0[2].push_back(Cmd::Send(Msg::Line {
+            id,
+            to,
+            spec,
+            message,
+        }))
+    }
+
     pub fn circle_to_all(
         &mut self,
         to: SideFilter,
@@ -319,6 +354,21 @@ impl MsgQ {
         }))
     }
 
+    pub fn quad_to_all(
+        &mut self,
+        to: SideFilter,
+        id: MarkId,
+        spec: QuadSpec,
+        message: Option<String>,
+    ) {
+        self.0[2].push_back(Cmd::Send(Msg::Quad {
+            id,
+            to,
+            spec,
+            message,
+        }))
+    }
+
     pub fn text_to_all(&mut self, to: SideFilter, id: MarkId, spec: TextSpec) {
         self.0[1].push_back(Cmd::Send(Msg::Text { id, to, spec }))
     }
@@ -346,6 +396,13 @@ impl MsgQ {
         self.0[2].push_back(Cmd::Send(Msg::SetMarkupFillColor { id, color }))
     }
 
+    pub fn set_markup_text<S: Into<String>>(&mut self, id: MarkId, text: S) {
+        self.0[2].push_back(Cmd::Send(Msg::SetMarkupText {
+            id,
+            text: text.into(),
+        }))
+    }
+
     pub fn process(&mut self, max_rate: usize, net: &Net, act: &Action) {
         for _ in 0..max_rate {
             let cmd = match self.0[0].pop_front() {
@@ -396,6 +453,12 @@ impl MsgQ {
                         }
                     },
                 },
+                Cmd::Send(Msg::Line {
+                    id,
+                    to,
+                    spec,
+                    message,
+                }) => act.line_to_all(to, id, spec, message),
                 Cmd::Send(Msg::Circle {
                     id,
                     to,
@@ -408,6 +471,12 @@ impl MsgQ {
                     spec,
                     message,
                 }) => act.rect_to_all(to, id, spec, message),
+                Cmd::Send(Msg::Quad {
+                    id,
+                    to,
+                    spec,
+                    message,
+                }) => act.quad_to_all(to, id, spec, message),
                 Cmd::Send(Msg::Text { id, to, spec }) => act.text_to_all(to, id, spec),
                 Cmd::Send(Msg::Arrow {
                     id,
@@ -419,6 +488,7 @@ impl MsgQ {
                 Cmd::Send(Msg::SetMarkupFillColor { id, color }) => {
                     act.set_markup_fill_color(id, color)
                 }
+                Cmd::Send(Msg::SetMarkupText { id, text }) => act.set_markup_text(id, text),
             };
             if let Err(e) = res {
                 error!("could not send message {:?}", e)
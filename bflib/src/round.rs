@@ -0,0 +1,129 @@
+/*
+Copyright 2024 Eric Stokes.
+
+This file is part of bflib.
+
+bflib is free software: you can redistribute it and/or modify it under
+the terms of the GNU Affero Public License as published by the Free
+Software Foundation, either version 3 of the License, or (at your
+option) any later version.
+
+bflib is distributed in the hope that it will be useful, but WITHOUT
+ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero Public License
+for more details.
+*/
+
+//! When a side wins the round the final state is archived next to the
+//! save file as `<save>_ROUND<timestamp>`, and a rollover is scheduled.
+//! The next time the mission starts the save is removed, so a new round
+//! begins, on the next scenario if the config rotates through them.
+
+use crate::{backup::with_suffix, cfg::Cfg, db::persisted::Persisted};
+use anyhow::{Context, Result};
+use chrono::prelude::*;
+use dcso3::{coalition::Side, String};
+use log::info;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rollover {
+    pub winner: Side,
+    /// the overlay the next round will use
+    pub scenario: Option<String>,
+    /// the overlays the scenario replaces
+    pub scenarios: Vec<String>,
+}
+
+impl Rollover {
+    /// The rollover for a round won by winner. The next scenario is the
+    /// one after the current one in the rotation, or the first if none
+    /// of them is in use.
+    pub fn new(cfg: &Cfg, winner: Side) -> Self {
+        let scenarios = cfg
+            .victory
+            .as_ref()
+            .map(|v| v.scenarios.clone())
+            .unwrap_or_default();
+        let current = cfg
+            .overlays
+            .iter()
+            .find_map(|o| scenarios.iter().position(|s| s == o));
+        let scenario = match current {
+            _ if scenarios.is_empty() => None,
+            None => Some(scenarios[0].clone()),
+            Some(i) => Some(scenarios[(i + 1) % scenarios.len()].clone()),
+        };
+        Self {
+            winner,
+            scenario,
+            scenarios,
+        }
+    }
+}
+
+fn rollover_path(save: &Path) -> Result<PathBuf> {
+    with_suffix(save, "_ROLLOVER")
+}
+
+/// archive the final state of a round
+pub(super) fn archive(save: &Path, persisted: &Persisted) -> Result<PathBuf> {
+    let path = with_suffix(save, &format!("_ROUND{}", Utc::now().timestamp()))?;
+    let file = fs::File::create(&path)?;
+    let mut file = zstd::stream::Encoder::new(file, 9)?.auto_finish();
+    serde_json::to_writer(&mut file, &persisted.versioned())?;
+    Ok(path)
+}
+
+/// start a new round the next time the mission starts
+pub(super) fn schedule(save: &Path, rollover: &Rollover) -> Result<()> {
+    fs::write(rollover_path(save)?, serde_json::to_vec(rollover)?)?;
+    Ok(())
+}
+
+/// the rollover that will happen when the mission next starts
+pub(super) fn scheduled(save: &Path) -> Result<Option<Rollover>> {
+    match fs::read(rollover_path(save)?) {
+        Ok(buf) => Ok(Some(serde_json::from_slice(&buf)?)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn remove_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Called at mission start before the save is loaded. If a rollover is
+/// scheduled then the next scenario is put in place in the config, and
+/// the save and its journal are removed. The background journal still
+/// holds the old round, so the caller must reset it.
+///
+/// The marker is removed before anything is deleted, so a step that
+/// fails is never retried against the new round's save. At worst the
+/// old round carries on.
+pub(super) fn rollover(save: &Path) -> Result<Option<Rollover>> {
+    let rollover = match scheduled(save)? {
+        None => return Ok(None),
+        Some(r) => r,
+    };
+    // setting the scenario is idempotent, so if it fails the marker is
+    // left in place and it is tried again at the next start
+    if let Some(scenario) = &rollover.scenario {
+        Cfg::set_scenario(save, &rollover.scenarios, scenario)
+            .context("setting the next scenario")?;
+        info!("the next round will use the scenario {scenario}");
+    }
+    fs::remove_file(rollover_path(save)?).context("removing the rollover marker")?;
+    remove_if_exists(save).context("removing the save")?;
+    remove_if_exists(&Persisted::journal_path(save)).context("removing the journal")?;
+    Ok(Some(rollover))
+}
@@ -0,0 +1,187 @@
+/*
+Copyright 2024 Eric Stokes.
+
+This file is part of bflib.
+
+bflib is free software: you can redistribute it and/or modify it under
+the terms of the GNU Affero Public License as published by the Free
+Software Foundation, either version 3 of the License, or (at your
+option) any later version.
+
+bflib is distributed in the hope that it will be useful, but WITHOUT
+ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero Public License
+for more details.
+*/
+
+//! Run the admin commands in `Cfg::schedules` at their scheduled times.
+//! The last time each schedule ran is kept in `<save>_SCHEDULE`, next to
+//! the save file, so restarts don't reset the clock, and a run that was
+//! missed while the server was down happens once when it comes back.
+//! Like the audit log it is not removed when the campaign is reset.
+
+use crate::{
+    admin::{AdminCommand, Requester},
+    backup::with_suffix,
+    bg::Task,
+    cfg::{Cfg, ScheduleWhen},
+    Context,
+};
+use anyhow::Result;
+use chrono::{prelude::*, Duration};
+use dcso3::String;
+use fxhash::FxHashMap;
+use log::{error, info};
+use smallvec::SmallVec;
+use std::{
+    cmp::max,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+pub(super) type LastRun = FxHashMap<String, DateTime<Utc>>;
+
+fn path(save: &Path) -> Result<PathBuf> {
+    with_suffix(save, "_SCHEDULE")
+}
+
+fn load(save: &Path) -> Result<LastRun> {
+    match fs::read(path(save)?) {
+        Ok(buf) => Ok(serde_json::from_slice(&buf)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(LastRun::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub(super) fn save(save: &Path, last_run: &LastRun) -> Result<()> {
+    let path = path(save)?;
+    let mut tmp = path.clone();
+    tmp.set_extension("tmp");
+    fs::write(&tmp, serde_json::to_vec(last_run)?)?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
+
+/// The first time after last that when is due, or None if it will
+/// never be due again
+pub(super) fn next_run(when: &ScheduleWhen, last: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    match when {
+        ScheduleWhen::Every(secs) => Some(last + Duration::seconds(max(*secs, 1) as i64)),
+        ScheduleWhen::Daily(t) => {
+            let at = Utc.from_utc_datetime(&last.date_naive().and_time(*t));
+            Some(if at > last {
+                at
+            } else {
+                at + Duration::days(1)
+            })
+        }
+        ScheduleWhen::Weekly(day, t) => {
+            let days =
+                day.num_days_from_monday() as i64 - last.weekday().num_days_from_monday() as i64;
+            let date = last.date_naive() + Duration::days(days.rem_euclid(7));
+            let at = Utc.from_utc_datetime(&date.and_time(*t));
+            Some(if at > last {
+                at
+            } else {
+                at + Duration::days(7)
+            })
+        }
+        ScheduleWhen::Once(ts) => (*ts > last).then_some(*ts),
+    }
+}
+
+#[derive(Debug, Default)]
+pub(super) struct Scheduler {
+    save: PathBuf,
+    last_run: LastRun,
+}
+
+impl Scheduler {
+    /// Load the last run times, and start the clock for any schedules
+    /// that haven't run before.
+    pub(super) fn load(save: &Path, cfg: &Cfg, now: DateTime<Utc>) -> Result<Self> {
+        let mut t = Self {
+            save: PathBuf::from(save),
+            last_run: load(save)?,
+        };
+        t.sync(cfg, now);
+        Ok(t)
+    }
+
+    /// Start the clock for schedules that are new in cfg, and forget the
+    /// ones that were removed. Returns true if anything changed.
+    pub(super) fn sync(&mut self, cfg: &Cfg, now: DateTime<Utc>) -> bool {
+        let len = self.last_run.len();
+        self.last_run
+            .retain(|name, _| cfg.schedules.contains_key(name));
+        let mut changed = self.last_run.len() != len;
+        for name in cfg.schedules.keys() {
+            if !self.last_run.contains_key(name) {
+                self.last_run.insert(name.clone(), now);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    fn next_run(&self, name: &String, when: &ScheduleWhen) -> Option<DateTime<Utc>> {
+        self.last_run
+            .get(name)
+            .and_then(|last| next_run(when, *last))
+    }
+
+    /// The next run of every schedule, soonest first. Schedules that will
+    /// never run again are last.
+    pub(super) fn upcoming<'a>(
+        &self,
+        cfg: &'a Cfg,
+    ) -> Vec<(Option<DateTime<Utc>>, &'a String, &'a String)> {
+        let mut res = cfg
+            .schedules
+            .iter()
+            .map(|(name, s)| (self.next_run(name, &s.when), name, &s.command))
+            .collect::<Vec<_>>();
+        res.sort_by_key(|(next, name, _)| (next.is_none(), *next, *name));
+        res
+    }
+
+    pub(super) fn save_task(&self) -> Task {
+        Task::SaveSchedule(self.save.clone(), self.last_run.clone())
+    }
+}
+
+/// Queue the commands that are due to run
+pub(super) fn queue_due(ctx: &mut Context, now: DateTime<Utc>) {
+    let cfg = Arc::clone(&ctx.db.ephemeral.cfg);
+    let due = cfg
+        .schedules
+        .iter()
+        .filter(|(name, s)| {
+            ctx.scheduler
+                .next_run(name, &s.when)
+                .map(|ts| ts <= now)
+                .unwrap_or(false)
+        })
+        .collect::<SmallVec<[_; 4]>>();
+    if due.is_empty() {
+        return;
+    }
+    for (name, s) in due {
+        ctx.scheduler.last_run.insert(name.clone(), now);
+        match s.command.parse::<AdminCommand>() {
+            Err(e) => error!("schedule {name}: invalid command {}, {e:?}", s.command),
+            Ok(AdminCommand::Help) => error!("schedule {name}: help can't be scheduled"),
+            Ok(cmd) => {
+                info!("schedule {name}: queueing {cmd:?}");
+                if let Some(msg) = &s.announce {
+                    ctx.db.ephemeral.msgs().panel_to_all(30, false, msg.clone())
+                }
+                ctx.admin_commands
+                    .push((Requester::Schedule(name.clone()), cmd))
+            }
+        }
+    }
+    let task = ctx.scheduler.save_task();
+    ctx.do_bg_task(task)
+}
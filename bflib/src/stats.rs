@@ -1,7 +1,7 @@
-use crate::{
+pub use crate::{
     cfg::{Action, Deployable, Troop, Vehicle},
     db::objective::{ObjectiveId, ObjectiveKind},
-    shots::Dead,
+    shots::{Dead, Shot},
 };
 use chrono::prelude::*;
 use dcso3::{
@@ -21,7 +21,7 @@ pub enum StatKind {
         winner: Option<Side>,
     },
     SessionStart {
-        stop_time: DateTime<Utc>,
+        stop_time: Option<DateTime<Utc>>,
     },
     SessionEnd,
     Objective {
@@ -87,5 +87,27 @@ pub enum StatKind {
         shots: Dead,
         team_kill: bool,
         points: SmallVec<[(Ucid, usize); 2]>,
+        /// the players in points who assisted rather than getting the
+        /// kill
+        #[serde(default)]
+        assists: SmallVec<[Ucid; 2]>,
     },
 }
+
+/// A timestamped stat record, one of these is written per line to the
+/// stats log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Stat {
+    pub time: DateTime<Utc>,
+    #[serde(flatten)]
+    pub kind: StatKind,
+}
+
+impl Stat {
+    pub fn new(kind: StatKind) -> Self {
+        Self {
+            time: Utc::now(),
+            kind,
+        }
+    }
+}
@@ -0,0 +1,49 @@
+use crate::{mission_edit::LoadedMiz, CfgCheckCmd, CfgDumpCmd, CfgSchemaCmd};
+use anyhow::{Context, Result};
+use bflib::cfg::{check::check as check_cfg, Cfg};
+use compact_str::format_compact;
+use mlua::Lua;
+use std::{fs, path::PathBuf};
+
+/// check the config against the mission, print the report, and return
+/// true if there were no errors
+pub fn check(cmd: &CfgCheckCmd) -> Result<bool> {
+    let lua = Box::leak(Box::new(Lua::new()));
+    let miz = LoadedMiz::new(lua, &cmd.miz).context("loading the mission")?;
+    let cfg = Cfg::load_file(&cmd.cfg)?;
+    let report = check_cfg(&cfg, &miz.mission).context("checking the config")?;
+    for w in &report.warnings {
+        println!("warning: {w}")
+    }
+    for e in &report.errors {
+        println!("error: {e}")
+    }
+    println!(
+        "{} error(s), {} warning(s)",
+        report.errors.len(),
+        report.warnings.len()
+    );
+    Ok(report.is_ok())
+}
+
+/// write the effective config, with all the overlays merged in
+pub fn dump(cmd: &CfgDumpCmd) -> Result<()> {
+    let mut cfg = Cfg::load_file(&cmd.cfg)?;
+    cfg.overlays.clear();
+    let s = serde_json::to_string_pretty(&cfg).context("serializing the config")?;
+    write(&cmd.output, s)
+}
+
+/// write the json schema of the config file
+pub fn schema(cmd: &CfgSchemaCmd) -> Result<()> {
+    let s = serde_json::to_string_pretty(&Cfg::schema()).context("serializing the schema")?;
+    write(&cmd.output, s)
+}
+
+fn write(output: &Option<PathBuf>, s: String) -> Result<()> {
+    match output {
+        None => println!("{s}"),
+        Some(path) => fs::write(path, s).with_context(|| format_compact!("writing {path:?}"))?,
+    }
+    Ok(())
+}
@@ -3,6 +3,7 @@ use clap::{Args, Parser, Subcommand};
 use serde_derive::Serialize;
 use std::path::PathBuf;
 
+mod cfg;
 mod mission_edit;
 
 #[derive(Args, Clone, Debug, Serialize)]
@@ -28,9 +29,49 @@ struct MizCmd {
     red_production_template: String
 }
 
+#[derive(Args, Clone, Debug, Serialize)]
+struct CfgCheckCmd {
+    /// the mission file the config will be used with
+    #[clap(long)]
+    miz: PathBuf,
+    /// the config file to check
+    #[clap(long)]
+    cfg: PathBuf,
+}
+
+#[derive(Args, Clone, Debug, Serialize)]
+struct CfgDumpCmd {
+    /// the config file to dump
+    #[clap(long)]
+    cfg: PathBuf,
+    /// where to write the merged config, stdout if not specified
+    #[clap(long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Args, Clone, Debug, Serialize)]
+struct CfgSchemaCmd {
+    /// where to write the schema, stdout if not specified
+    #[clap(long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Clone, Debug, Serialize)]
+enum CfgCmd {
+    /// cross reference a config with a mission file, exits non zero
+    /// if there are errors
+    Check(CfgCheckCmd),
+    /// print the effective config with all its overlays merged
+    Dump(CfgDumpCmd),
+    /// print the json schema of the config file, for editor support
+    Schema(CfgSchemaCmd),
+}
+
 #[derive(Subcommand, Clone, Debug, Serialize)]
 enum Tools {
     Miz(MizCmd),
+    #[clap(subcommand)]
+    Cfg(CfgCmd),
 }
 
 #[derive(Parser)]
@@ -45,6 +86,13 @@ fn main() -> Result<()> {
 
     match bftools_args.tool {
         Tools::Miz(cfg) => mission_edit::run(&cfg)?,
+        Tools::Cfg(CfgCmd::Check(cmd)) => {
+            if !cfg::check(&cmd)? {
+                std::process::exit(1)
+            }
+        }
+        Tools::Cfg(CfgCmd::Dump(cmd)) => cfg::dump(&cmd)?,
+        Tools::Cfg(CfgCmd::Schema(cmd)) => cfg::schema(&cmd)?,
     };
     Ok(())
 }
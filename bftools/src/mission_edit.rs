@@ -277,9 +277,9 @@ fn serialize_to_lua(key: &str, value: Value<'static>) -> Result<std::string::Str
     }
 }
 
-struct LoadedMiz {
+pub(crate) struct LoadedMiz {
     miz: UnpackedMiz,
-    mission: Miz<'static>,
+    pub(crate) mission: Miz<'static>,
     #[allow(dead_code)]
     options: Table<'static>,
     #[allow(dead_code)]
@@ -287,7 +287,7 @@ struct LoadedMiz {
 }
 
 impl LoadedMiz {
-    fn new(lua: &'static Lua, path: &Path) -> Result<Self> {
+    pub(crate) fn new(lua: &'static Lua, path: &Path) -> Result<Self> {
         let miz = UnpackedMiz::new(path).with_context(|| format_compact!("unpacking {path:?}"))?;
         let mut mission = lua.create_table()?;
         let mut options = lua.create_table()?;
@@ -45,6 +45,8 @@ pub mod lfs;
 pub mod mission_commands;
 pub mod net;
 pub mod object;
+#[cfg(feature = "schemars")]
+pub mod schema;
 pub mod spot;
 pub mod static_object;
 pub mod timer;
@@ -155,7 +157,7 @@ macro_rules! atomic_id {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct Quad2 {
     pub p0: LuaVec2,
     pub p1: LuaVec2,
@@ -214,6 +216,47 @@ impl Quad2 {
         intersections % 2 == 1
     }
 
+    pub fn points(&self) -> [Vector2; 4] {
+        [self.p0.0, self.p1.0, self.p2.0, self.p3.0]
+    }
+
+    pub fn center(&self) -> Vector2 {
+        centroid2d(self.points())
+    }
+
+    /// the distance from p to the nearest edge, or 0 if p is inside
+    pub fn distance(&self, p: Vector2) -> f64 {
+        if self.contains(LuaVec2(p)) {
+            return 0.;
+        }
+        let [p0, p1, p2, p3] = self.points();
+        [(p0, p1), (p1, p2), (p2, p3), (p3, p0)]
+            .into_iter()
+            .map(|(v0, v1)| {
+                let edge = v1 - v0;
+                let len2 = edge.norm_squared();
+                let t = if len2 == 0. {
+                    0.
+                } else {
+                    ((p - v0).dot(&edge) / len2).clamp(0., 1.)
+                };
+                na::distance(&p.into(), &(v0 + edge * t).into())
+            })
+            .fold(f64::MAX, f64::min)
+    }
+
+    /// scale the quad by factor about its center
+    pub fn scale(&self, factor: f64) -> Self {
+        let c = self.center();
+        let s = |v: LuaVec2| LuaVec2(c + (v.0 - c) * factor);
+        Self {
+            p0: s(self.p0),
+            p1: s(self.p1),
+            p2: s(self.p2),
+            p3: s(self.p3),
+        }
+    }
+
     pub fn longest_edge(&self) -> (Vector2, Vector2, f64) {
         [
             (self.p0.0, self.p1.0),
@@ -245,6 +288,10 @@ pub struct Color {
 }
 
 impl Color {
+    pub fn new(r: f32, g: f32, b: f32, a: f32) -> Color {
+        Color { r, g, b, a }
+    }
+
     pub fn black(a: f32) -> Color {
         Color {
             r: 0.,
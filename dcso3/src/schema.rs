@@ -0,0 +1,109 @@
+/*
+Copyright 2024 Eric Stokes.
+
+This file is part of dcso3.
+
+dcso3 is free software: you can redistribute it and/or modify it under
+the terms of the MIT License.
+
+dcso3 is distributed in the hope that it will be useful, but WITHOUT
+ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+FITNESS FOR A PARTICULAR PURPOSE.
+*/
+
+//! JSON schemas for the types that show up in user edited config files
+
+use crate::{coalition::Side, controller::AltType, net::Ucid, String};
+use schemars::{
+    gen::SchemaGenerator,
+    schema::{InstanceType, Metadata, Schema, SchemaObject, StringValidation, SubschemaValidation},
+    JsonSchema,
+};
+use serde_json::Value;
+
+fn string_enum(description: &str, cases: &[&str]) -> SchemaObject {
+    SchemaObject {
+        metadata: Some(Box::new(Metadata {
+            description: Some(description.into()),
+            ..Default::default()
+        })),
+        instance_type: Some(InstanceType::String.into()),
+        enum_values: Some(cases.iter().map(|c| Value::from(*c)).collect()),
+        ..Default::default()
+    }
+}
+
+impl JsonSchema for String {
+    fn is_referenceable() -> bool {
+        false
+    }
+
+    fn schema_name() -> std::string::String {
+        "String".into()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        gen.subschema_for::<std::string::String>()
+    }
+}
+
+impl JsonSchema for Ucid {
+    fn schema_name() -> std::string::String {
+        "Ucid".into()
+    }
+
+    fn json_schema(_gen: &mut SchemaGenerator) -> Schema {
+        Schema::Object(SchemaObject {
+            metadata: Some(Box::new(Metadata {
+                description: Some("A DCS player's unique id, 32 hex digits".into()),
+                ..Default::default()
+            })),
+            instance_type: Some(InstanceType::String.into()),
+            string: Some(Box::new(StringValidation {
+                max_length: Some(32),
+                min_length: Some(32),
+                pattern: Some("^[0-9a-fA-F]{32}$".into()),
+            })),
+            ..Default::default()
+        })
+    }
+}
+
+impl JsonSchema for Side {
+    fn schema_name() -> std::string::String {
+        "Side".into()
+    }
+
+    fn json_schema(_gen: &mut SchemaGenerator) -> Schema {
+        Schema::Object(string_enum("A coalition", &["Neutral", "Red", "Blue"]))
+    }
+}
+
+impl JsonSchema for AltType {
+    fn schema_name() -> std::string::String {
+        "AltType".into()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        let custom = {
+            let mut o = SchemaObject::default();
+            let obj = o.object();
+            obj.required.insert("Custom".into());
+            obj.properties
+                .insert("Custom".into(), gen.subschema_for::<String>());
+            obj.additional_properties = Some(Box::new(false.into()));
+            Schema::Object(o)
+        };
+        let known = Schema::Object(string_enum(
+            "How an altitude is measured",
+            &["BARO", "RADIO"],
+        ));
+        Schema::Object(SchemaObject {
+            subschemas: Some(Box::new(SubschemaValidation {
+                one_of: Some(vec![known, custom]),
+                ..Default::default()
+            })),
+            ..Default::default()
+        })
+    }
+}